@@ -0,0 +1,686 @@
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::io::{Error, Result};
+use std::mem::take;
+use std::ops::AddAssign;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use tokio::fs::metadata;
+use tokio::select;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::time::interval;
+use tracing::info;
+
+use crate::db::column_family::column_family_dropped;
+use crate::db::merge_operator::collapse_merges;
+use crate::db::options::{
+    CompactRangeOptions, CompactionDecision, CompactionStyle, DbOptions, FifoCompactionOptions,
+};
+use crate::db::snapshot::split_stripes;
+use crate::db::state::DbState;
+use crate::key::internal_key::{lookup_key, InternalKey, ValueType, MAX_SEQ_NUM};
+use crate::key::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
+use crate::levels::levels::Levels;
+use crate::manifest::entry::ManifestLogEntry;
+use crate::manifest::manifest::{append_entries, ManifestRequest};
+use crate::sst::table::table::SstTable;
+use crate::sst::table::writer::SstTableWriter;
+use crate::utils::fs::sync_dir;
+use crate::utils::time::{tick, unix_time};
+use crate::version::version::FileMetaData;
+
+use super::merge::{EntryStream, MergeIterator};
+use super::picker::{
+    pick_bottommost, pick_fifo, pick_leveled, pick_range, pick_universal, pick_universal_all,
+    Compaction,
+};
+
+pub enum CompactionRequest {
+    // Compacts until no level needs it anymore
+    Schedule,
+    // Compacts the files of a column family overlapping [start, end] down to
+    // the last level holding any of them
+    CompactRange {
+        column_family: u32,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        options: CompactRangeOptions,
+        completion: oneshot::Sender<Result<CompactionStats>>,
+    },
+}
+
+// What compactions read and wrote. Records are only counted for the files
+// that were rewritten.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub input_files: usize,
+    pub input_bytes: u64,
+    pub input_records: u64,
+    pub output_files: usize,
+    pub output_bytes: u64,
+    pub output_records: u64,
+    pub elapsed: Duration,
+}
+
+impl AddAssign for CompactionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.input_files += other.input_files;
+        self.input_bytes += other.input_bytes;
+        self.input_records += other.input_records;
+        self.output_files += other.output_files;
+        self.output_bytes += other.output_bytes;
+        self.output_records += other.output_records;
+        self.elapsed += other.elapsed;
+    }
+}
+
+type Output = (Arc<FileMetaData>, Arc<SstTable>);
+
+// Runs compactions one at a time in the background, flushes only ever add
+// files to level 0 meanwhile. Each column family is compacted with its own
+// options.
+pub struct Compactor {
+    path: PathBuf,
+    state: Arc<RwLock<DbState>>,
+    manifest_sender: Sender<ManifestRequest>,
+    receiver: Receiver<CompactionRequest>,
+    // Largest key compacted out of each level of each column family the last
+    // time
+    compact_pointers: BTreeMap<u32, Vec<Vec<u8>>>,
+}
+
+impl Compactor {
+    pub fn new(
+        path: PathBuf,
+        state: Arc<RwLock<DbState>>,
+        manifest_sender: Sender<ManifestRequest>,
+        receiver: Receiver<CompactionRequest>,
+    ) -> Self {
+        Self {
+            path,
+            state,
+            manifest_sender,
+            receiver,
+            compact_pointers: BTreeMap::new(),
+        }
+    }
+
+    // Levels and options of a column family, None once it is dropped
+    fn column_family(&self, column_family: u32) -> Option<(Arc<Levels>, DbOptions)> {
+        let state = self.state.read().unwrap();
+        state
+            .column_families
+            .get(&column_family)
+            .map(|family| (family.levels.clone(), family.options.clone()))
+    }
+
+    // Shortest time to live of the column families using FIFO compaction
+    fn ttl(&self) -> Option<Duration> {
+        let state = self.state.read().unwrap();
+        state
+            .column_families
+            .values()
+            .filter_map(|family| match family.options.compaction_style {
+                CompactionStyle::Fifo(FifoCompactionOptions { ttl, .. }) => ttl,
+                _ => None,
+            })
+            .min()
+    }
+
+    async fn compact_while_needed(&mut self) -> Result<()> {
+        let column_families: Vec<u32> = {
+            let state = self.state.read().unwrap();
+            state.column_families.keys().copied().collect()
+        };
+        for column_family in column_families {
+            while let Some((levels, options)) = self.column_family(column_family) {
+                let compact_pointers = self
+                    .compact_pointers
+                    .get(&column_family)
+                    .map_or(&[][..], |pointers| pointers.as_slice());
+                let compaction = match &options.compaction_style {
+                    CompactionStyle::Leveled => pick_leveled(&levels, &options, compact_pointers),
+                    CompactionStyle::Universal(universal) => {
+                        pick_universal(&levels, &options, universal)
+                    }
+                    CompactionStyle::Fifo(fifo) => pick_fifo(&levels, fifo, unix_time()?),
+                };
+                match compaction {
+                    Some(compaction) => {
+                        self.compact(column_family, &options, levels, compaction, 1)
+                            .await?
+                    }
+                    None => break,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    async fn compact_range(
+        &mut self,
+        column_family: u32,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        options: &CompactRangeOptions,
+    ) -> Result<CompactionStats> {
+        let (start, end) = (start.as_deref(), end.as_deref());
+        let mut stats = CompactionStats::default();
+        let (levels, family_options) = self
+            .column_family(column_family)
+            .ok_or_else(column_family_dropped)?;
+        match &family_options.compaction_style {
+            CompactionStyle::Leveled => {
+                let last = (0..levels.num_levels())
+                    .rev()
+                    .find(|&level| {
+                        levels
+                            .files(level)
+                            .iter()
+                            .any(|f| f.overlaps_range(start, end))
+                    })
+                    .map(|last| last.clamp(1, levels.num_levels() - 1));
+                let first_new_file = self.state.read().unwrap().next_file_number;
+                for level in 0..last.unwrap_or(0) {
+                    // Each compaction changes the levels the next one picks from
+                    let (levels, _) = self
+                        .column_family(column_family)
+                        .ok_or_else(column_family_dropped)?;
+                    if let Some(compaction) =
+                        pick_range(&levels, &family_options, level, start, end)
+                    {
+                        stats += self
+                            .compact(
+                                column_family,
+                                &family_options,
+                                levels,
+                                compaction,
+                                options.max_subcompactions,
+                            )
+                            .await?;
+                    }
+                }
+                // The files already in the last level still hold deletes and
+                // shadowed versions
+                if let Some(last) = last {
+                    let (levels, _) = self
+                        .column_family(column_family)
+                        .ok_or_else(column_family_dropped)?;
+                    if let Some(compaction) =
+                        pick_bottommost(&levels, &family_options, last, start, end, first_new_file)
+                    {
+                        stats += self
+                            .compact(
+                                column_family,
+                                &family_options,
+                                levels,
+                                compaction,
+                                options.max_subcompactions,
+                            )
+                            .await?;
+                    }
+                }
+            }
+            CompactionStyle::Universal(_) => {
+                if let Some(compaction) = pick_universal_all(&levels, &family_options) {
+                    stats += self
+                        .compact(
+                            column_family,
+                            &family_options,
+                            levels,
+                            compaction,
+                            options.max_subcompactions,
+                        )
+                        .await?;
+                }
+            }
+            // Nothing is ever rewritten
+            CompactionStyle::Fifo(_) => {}
+        }
+        info!("Compacted range: {:?}", stats);
+        Ok(stats)
+    }
+
+    async fn compact(
+        &mut self,
+        column_family: u32,
+        options: &DbOptions,
+        levels: Arc<Levels>,
+        compaction: Compaction,
+        subcompactions: usize,
+    ) -> Result<CompactionStats> {
+        let started = Instant::now();
+        let mut stats = CompactionStats {
+            input_files: compaction.files().count(),
+            input_bytes: compaction.files().map(|(_, f)| f.meta.file_size).sum(),
+            ..Default::default()
+        };
+        let compaction = Arc::new(compaction);
+        let outputs = if compaction.deletion {
+            Vec::new()
+        } else if compaction.is_trivial_move() {
+            let (_, file) = compaction.files().next().unwrap();
+            vec![(file.meta.clone(), file.table.clone())]
+        } else {
+            self.merge(options, levels, &compaction, subcompactions, &mut stats)
+                .await?
+        };
+        stats.output_files = outputs.len();
+        stats.output_bytes = outputs.iter().map(|(meta, _)| meta.file_size).sum();
+
+        let mut entries: Vec<ManifestLogEntry> = compaction
+            .files()
+            .map(|(level, file)| ManifestLogEntry::DeletedFile {
+                level: level as u32,
+                file_number: file.meta.file_number,
+            })
+            .collect();
+        for (meta, _) in &outputs {
+            entries.push(meta.new_file_entry(column_family, compaction.output_level as u32));
+        }
+        entries.push(ManifestLogEntry::NextFileNumber {
+            next_file_number: self.state.read().unwrap().next_file_number,
+        });
+        append_entries(&self.manifest_sender, entries).await?;
+
+        let dropped = {
+            let mut state = self.state.write().unwrap();
+            match state.column_families.get_mut(&column_family) {
+                Some(family) => {
+                    let levels = Arc::make_mut(&mut family.levels);
+                    for (level, file) in compaction.files() {
+                        levels.remove(level, file.meta.file_number);
+                    }
+                    for (meta, table) in &outputs {
+                        levels.add(compaction.output_level, meta.clone(), table.clone());
+                    }
+                    false
+                }
+                None => true,
+            }
+        };
+        if dropped {
+            for (_, table) in &outputs {
+                table.mark_obsolete();
+            }
+        }
+        if !compaction.is_trivial_move() {
+            for (_, file) in compaction.files() {
+                file.table.mark_obsolete();
+            }
+        }
+
+        if let Some(largest) = compaction
+            .inputs
+            .first()
+            .and_then(|(_, files)| files.iter().map(|f| &f.meta.largest).max())
+        {
+            let compact_pointers = self.compact_pointers.entry(column_family).or_default();
+            if compact_pointers.len() <= compaction.level {
+                compact_pointers.resize(compaction.level + 1, Vec::new());
+            }
+            compact_pointers[compaction.level] = largest.clone();
+        }
+
+        info!(
+            "Compacted {} files of level {} into {} files of level {}",
+            compaction.files().count(),
+            compaction.level,
+            outputs.len(),
+            compaction.output_level
+        );
+        stats.elapsed = started.elapsed();
+        Ok(stats)
+    }
+
+    // Merges the sub ranges of the compaction on their own tasks
+    async fn merge(
+        &self,
+        options: &DbOptions,
+        levels: Arc<Levels>,
+        compaction: &Arc<Compaction>,
+        subcompactions: usize,
+        stats: &mut CompactionStats,
+    ) -> Result<Vec<Output>> {
+        // Snapshots taken from now on see every version of the inputs
+        let snapshots = self.state.read().unwrap().snapshots.seq_nums();
+        let handles: Vec<_> = compaction
+            .sub_ranges(subcompactions)
+            .into_iter()
+            .map(|(start, end)| {
+                let subcompaction = SubCompaction {
+                    path: self.path.clone(),
+                    options: options.clone(),
+                    state: self.state.clone(),
+                    levels: levels.clone(),
+                    compaction: compaction.clone(),
+                    snapshots: snapshots.clone(),
+                    start,
+                    end,
+                };
+                tokio::spawn(subcompaction.run())
+            })
+            .collect();
+
+        let mut outputs = Vec::new();
+        for handle in handles {
+            let (sub_outputs, input_records, output_records) =
+                handle.await.map_err(Error::other)??;
+            outputs.extend(sub_outputs);
+            stats.input_records += input_records;
+            stats.output_records += output_records;
+        }
+        sync_dir(&self.path).await?;
+        Ok(outputs)
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut ttl = None;
+        let mut ttl_interval = None;
+        loop {
+            // Files expire even when nothing is written
+            let next_ttl = self.ttl();
+            if next_ttl != ttl {
+                ttl = next_ttl;
+                ttl_interval = ttl.map(|ttl| interval(ttl.max(Duration::from_secs(1))));
+            }
+            select! {
+                request = self.receiver.recv() => match request {
+                    Some(CompactionRequest::Schedule) => self.compact_while_needed().await?,
+                    Some(CompactionRequest::CompactRange {
+                        column_family,
+                        start,
+                        end,
+                        options,
+                        completion,
+                    }) => {
+                        let result = self.compact_range(column_family, start, end, &options).await;
+                        let _ = completion.send(result);
+                        // A schedule may have been dropped while the channel
+                        // was full
+                        self.compact_while_needed().await?
+                    }
+                    None => break,
+                },
+                _ = tick(&mut ttl_interval) => self.compact_while_needed().await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Part of a compaction, the user keys in [start, end)
+struct SubCompaction {
+    path: PathBuf,
+    options: DbOptions,
+    state: Arc<RwLock<DbState>>,
+    levels: Arc<Levels>,
+    compaction: Arc<Compaction>,
+    // Ascending
+    snapshots: Vec<u64>,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+}
+
+impl SubCompaction {
+    // Writes the latest version of every key of the inputs, leaving out the
+    // versions deleted by range tombstones and the deletes that no longer
+    // shadow anything. Also returns the number of records read and written.
+    async fn run(self) -> Result<(Vec<Output>, u64, u64)> {
+        let from = self
+            .start
+            .as_ref()
+            .map(|start| lookup_key(start, MAX_SEQ_NUM));
+        let range_tombstones = FragmentedRangeTombstones::new(
+            &self
+                .compaction
+                .files()
+                .flat_map(|(_, file)| {
+                    file.table
+                        .range_tombstones()
+                        .clip(self.start.as_deref(), self.end.as_deref())
+                })
+                .collect::<Vec<_>>(),
+        );
+        // Range tombstones that may still delete versions below the output,
+        // or versions kept for older snapshots
+        let kept_tombstones =
+            FragmentedRangeTombstones::new(range_tombstones.tombstones().iter().filter(|t| {
+                self.snapshots.first().is_some_and(|s| *s < t.seq_num)
+                    || !self
+                        .compaction
+                        .is_bottommost_range(&self.levels, &t.start, &t.end)
+            }));
+        let mut sources: Vec<EntryStream> = Vec::new();
+        for (_, file) in self.compaction.files() {
+            match &from {
+                Some(from) => sources.push(Box::pin(file.table.iter_from(from).await)),
+                None => sources.push(Box::pin(file.table.iter().await)),
+            }
+        }
+        let mut merge = MergeIterator::new(sources).await?;
+
+        let mut outputs = Vec::new();
+        let mut pending = Vec::new();
+        let mut pending_size = 0;
+        // Each output holds the range tombstones from its first key up to
+        // the first key of the next one
+        let mut output_start = self.start.clone();
+        let mut input_records = 0;
+        let mut output_records = 0;
+        // Versions of the current user key, newest first
+        let mut versions: Vec<(InternalKey, Vec<u8>)> = Vec::new();
+        loop {
+            let entry = match merge.next().await? {
+                Some((key, value)) => {
+                    let key = InternalKey::decode(&key)?;
+                    let past_end = self.end.as_ref().is_some_and(|end| key.user_key >= *end);
+                    (!past_end).then_some((key, value))
+                }
+                None => None,
+            };
+            let key_done = match &entry {
+                Some((key, _)) => versions
+                    .first()
+                    .is_some_and(|(first, _)| first.user_key != key.user_key),
+                None => !versions.is_empty(),
+            };
+            if key_done {
+                let user_key = versions[0].0.user_key.clone();
+                let kept = self.compact_versions(take(&mut versions), &range_tombstones)?;
+                // Outputs are only split between user keys, so that files of
+                // a level never overlap
+                if pending_size >= self.compaction.target_file_size {
+                    let tombstones = kept_tombstones.clip(output_start.as_deref(), Some(&user_key));
+                    outputs.push(self.write_output(take(&mut pending), tombstones).await?);
+                    pending_size = 0;
+                    output_start = Some(user_key);
+                }
+                for (key, value) in kept {
+                    pending_size += (key.user_key.len() + value.len()) as u64;
+                    output_records += 1;
+                    pending.push((key, value));
+                }
+            }
+            match entry {
+                Some(entry) => {
+                    input_records += 1;
+                    versions.push(entry);
+                }
+                None => break,
+            }
+        }
+        let tombstones = kept_tombstones.clip(output_start.as_deref(), self.end.as_deref());
+        if !pending.is_empty() || !tombstones.is_empty() {
+            outputs.push(self.write_output(pending, tombstones).await?);
+        }
+        Ok((outputs, input_records, output_records))
+    }
+
+    // Versions of a user key to write out of all those found, newest first.
+    // Each snapshot keeps the newest version it sees.
+    fn compact_versions(
+        &self,
+        versions: Vec<(InternalKey, Vec<u8>)>,
+        range_tombstones: &FragmentedRangeTombstones,
+    ) -> Result<Vec<(InternalKey, Vec<u8>)>> {
+        let user_key = versions[0].0.user_key.clone();
+        let bottommost = OnceCell::new();
+        let is_bottommost =
+            || *bottommost.get_or_init(|| self.compaction.is_bottommost(&self.levels, &user_key));
+
+        let stripes = split_stripes(versions, &self.snapshots);
+        let count = stripes.len();
+        let mut kept = Vec::new();
+        for (i, stripe) in stripes.into_iter().enumerate() {
+            // Nothing older is left below
+            let oldest = i + 1 == count && is_bottommost();
+            kept.extend(self.compact_stripe(stripe, range_tombstones, oldest)?);
+        }
+        Ok(kept)
+    }
+
+    // Versions of a stripe to write, readers see at most its newest version
+    // and the merge operands above it
+    fn compact_stripe(
+        &self,
+        mut versions: Vec<(InternalKey, Vec<u8>)>,
+        range_tombstones: &FragmentedRangeTombstones,
+        oldest: bool,
+    ) -> Result<Vec<(InternalKey, Vec<u8>)>> {
+        let user_key = versions[0].0.user_key.clone();
+        let newest_seq = versions[0].0.seq_num;
+        // Snapshots right below and at or above the stripe
+        let below = self.snapshots.iter().rev().find(|s| **s < newest_seq);
+        let above = self.snapshots.iter().find(|s| **s >= newest_seq);
+
+        // Versions older than a range tombstone of the stripe covering the
+        // key are deleted
+        if let Some(covering) = range_tombstones
+            .covering(&user_key, above.copied().unwrap_or(MAX_SEQ_NUM))
+            .filter(|covering| below.is_none_or(|below| below < covering))
+        {
+            let count = versions
+                .iter()
+                .position(|(key, _)| key.seq_num < covering)
+                .unwrap_or(versions.len());
+            if count == 0 {
+                return Ok(Vec::new());
+            }
+            if count < versions.len() {
+                versions.truncate(count);
+                // The merge operands left apply to no value
+                versions.push((
+                    InternalKey::new(user_key.clone(), covering, ValueType::Delete),
+                    Vec::new(),
+                ));
+            }
+        }
+
+        let mut versions = match &self.options.merge_operator {
+            Some(operator) if versions[0].0.value_type == ValueType::Merge => {
+                collapse_merges(operator.as_ref(), versions, oldest)
+            }
+            _ => versions,
+        };
+        // A single delete and the set right below it cancel out. Versions
+        // below the set, if any, broke the single delete contract.
+        while versions.len() >= 2
+            && versions[0].0.value_type == ValueType::SingleDelete
+            && versions[1].0.value_type == ValueType::Set
+        {
+            versions.drain(..2);
+        }
+        if versions.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Everything below the first version that is not a merge operand is
+        // shadowed by it
+        let count = versions
+            .iter()
+            .position(|(key, _)| key.value_type != ValueType::Merge)
+            .map_or(versions.len(), |index| index + 1);
+        versions.truncate(count);
+
+        let mut kept = Vec::with_capacity(versions.len());
+        for (mut key, mut value) in versions {
+            // Values seen by a snapshot are left as they are
+            if let (ValueType::Set, Some(filter), None) =
+                (key.value_type, &self.options.compaction_filter, above)
+            {
+                match filter.filter(self.compaction.level, &key.user_key, &value) {
+                    CompactionDecision::Keep => {}
+                    // Older versions may still be below, they must stay
+                    // shadowed
+                    CompactionDecision::Remove => {
+                        key.value_type = ValueType::Delete;
+                        value = Vec::new();
+                    }
+                    CompactionDecision::ChangeValue(new_value) => value = new_value,
+                }
+            }
+            // Deletes written after a snapshot stay, transactions reading
+            // from it check them for conflicts
+            let after_snapshot = self.snapshots.first().is_some_and(|s| *s < key.seq_num);
+            if key.value_type.is_delete() && oldest && !after_snapshot {
+                continue;
+            }
+            kept.push((key, value));
+        }
+        Ok(kept)
+    }
+
+    async fn write_output(
+        &self,
+        entries: Vec<(InternalKey, Vec<u8>)>,
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> Result<Output> {
+        let file_number = self.state.write().unwrap().new_file_number();
+        let file_path = self.path.join(format!("SST-{}", file_number));
+        let mut writer =
+            SstTableWriter::new(&file_path, entries.len(), self.options.clone()).await?;
+        for (key, value) in &entries {
+            writer.add(&key.encode(), value).await?;
+        }
+        for tombstone in &range_tombstones {
+            writer.add_range_tombstone(tombstone.clone());
+        }
+        let table = writer.finish().await?;
+
+        // Range tombstones widen the key range of the file
+        let bounds = table.range_tombstones().bounds();
+        let smallest = entries
+            .first()
+            .map(|(k, _)| k.user_key.as_slice())
+            .into_iter()
+            .chain(bounds.map(|(start, _)| start))
+            .min()
+            .unwrap()
+            .to_vec();
+        let largest = entries
+            .last()
+            .map(|(k, _)| k.user_key.as_slice())
+            .into_iter()
+            .chain(bounds.map(|(_, end)| end))
+            .max()
+            .unwrap()
+            .to_vec();
+        let seq_nums = entries
+            .iter()
+            .map(|(k, _)| k.seq_num)
+            .chain(range_tombstones.iter().map(|t| t.seq_num));
+        let meta = FileMetaData {
+            file_number,
+            file_size: metadata(&file_path).await?.len(),
+            smallest,
+            largest,
+            smallest_seqno: seq_nums.clone().min().unwrap(),
+            largest_seqno: seq_nums.max().unwrap(),
+            creation_time: Some(unix_time()?),
+        };
+        Ok((Arc::new(meta), Arc::new(table)))
+    }
+}
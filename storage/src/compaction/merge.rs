@@ -0,0 +1,117 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::Result;
+use std::pin::Pin;
+
+use tokio_stream::{Stream, StreamExt};
+
+use crate::key::internal_key::compare;
+
+pub type EntryStream<'a> = Pin<Box<dyn Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Send + 'a>>;
+
+struct HeapEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    source: usize,
+}
+
+// Reversed so that the max-heap pops the smallest internal key first
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&other.key, &self.key).then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+// Merges streams of internal key entries, each sorted, into a single sorted
+// stream.
+pub struct MergeIterator<'a> {
+    sources: Vec<EntryStream<'a>>,
+    heap: BinaryHeap<HeapEntry>,
+}
+
+impl<'a> MergeIterator<'a> {
+    pub async fn new(mut sources: Vec<EntryStream<'a>>) -> Result<Self> {
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (source, stream) in sources.iter_mut().enumerate() {
+            if let Some((key, value)) = stream.next().await.transpose()? {
+                heap.push(HeapEntry { key, value, source });
+            }
+        }
+        Ok(Self { sources, heap })
+    }
+
+    pub async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let entry = match self.heap.pop() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if let Some((key, value)) = self.sources[entry.source].next().await.transpose()? {
+            self.heap.push(HeapEntry {
+                key,
+                value,
+                source: entry.source,
+            });
+        }
+        Ok(Some((entry.key, entry.value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::internal_key::{InternalKey, ValueType};
+
+    fn source(entries: &[(&str, u64)]) -> EntryStream<'static> {
+        let entries: Vec<Result<(Vec<u8>, Vec<u8>)>> = entries
+            .iter()
+            .map(|(key, seq_num)| {
+                let key = InternalKey::new(key.as_bytes().to_vec(), *seq_num, ValueType::Set);
+                Ok((key.encode(), seq_num.to_string().into_bytes()))
+            })
+            .collect();
+        Box::pin(tokio_stream::iter(entries))
+    }
+
+    #[tokio::test]
+    async fn merge_sources() {
+        let mut merge = MergeIterator::new(vec![
+            source(&[("a", 1), ("c", 5), ("d", 2)]),
+            source(&[]),
+            source(&[("b", 3), ("c", 7), ("c", 4)]),
+            source(&[("a", 6)]),
+        ])
+        .await
+        .unwrap();
+
+        let mut merged = Vec::new();
+        while let Some((key, _)) = merge.next().await.unwrap() {
+            let key = InternalKey::decode(&key).unwrap();
+            merged.push((String::from_utf8(key.user_key).unwrap(), key.seq_num));
+        }
+        let expected = [
+            ("a", 6),
+            ("a", 1),
+            ("b", 3),
+            ("c", 7),
+            ("c", 5),
+            ("c", 4),
+            ("d", 2),
+        ];
+        let expected: Vec<_> = expected.iter().map(|(k, s)| (k.to_string(), *s)).collect();
+        assert_eq!(merged, expected);
+    }
+}
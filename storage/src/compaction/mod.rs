@@ -0,0 +1,3 @@
+pub mod compactor;
+pub mod merge;
+pub mod picker;
@@ -0,0 +1,600 @@
+use std::ops::Range;
+
+use crate::db::options::{DbOptions, FifoCompactionOptions, UniversalCompactionOptions};
+use crate::levels::levels::{LevelFile, Levels};
+
+// Unbounded on a missing side
+pub type KeyRange = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+#[derive(Debug)]
+pub struct Compaction {
+    pub level: usize,
+    pub output_level: usize,
+    // Input files of `level`, then of `output_level` if different
+    pub inputs: Vec<(usize, Vec<LevelFile>)>,
+    // Size past which outputs are split, they must stay in a single file when
+    // written to level 0.
+    pub target_file_size: u64,
+    // Inputs are deleted without writing anything
+    pub deletion: bool,
+    // Manual compactions rewrite their inputs even when they could be moved
+    pub manual: bool,
+}
+
+impl Compaction {
+    pub fn files(&self) -> impl Iterator<Item = (usize, &LevelFile)> {
+        self.inputs
+            .iter()
+            .flat_map(|(level, files)| files.iter().map(move |file| (*level, file)))
+    }
+
+    // A single file overlapping nothing in the output level can be moved
+    // there without being rewritten.
+    pub fn is_trivial_move(&self) -> bool {
+        !self.manual && self.level != self.output_level && self.files().count() == 1
+    }
+
+    // Splits the key range of the inputs at file boundaries into at most
+    // `count` ranges of user keys, each [start, end).
+    pub fn sub_ranges(&self, count: usize) -> Vec<KeyRange> {
+        let mut boundaries: Vec<&Vec<u8>> = self.files().map(|(_, f)| &f.meta.smallest).collect();
+        boundaries.sort();
+        boundaries.dedup();
+        // Nothing comes before the smallest key
+        let boundaries = boundaries.get(1..).unwrap_or_default();
+        let count = count.clamp(1, boundaries.len() + 1);
+        let splits: Vec<Option<Vec<u8>>> = (1..count)
+            .map(|i| Some(boundaries[i * boundaries.len() / count].clone()))
+            .collect();
+        let starts = std::iter::once(None).chain(splits.iter().cloned());
+        let ends = splits.iter().cloned().chain(std::iter::once(None));
+        starts.zip(ends).collect()
+    }
+
+    // Whether a delete of the key no longer shadows anything once compacted.
+    // Files left in level 0 may hold older versions of any key.
+    pub fn is_bottommost(&self, levels: &Levels, key: &[u8]) -> bool {
+        self.output_level > 0 && levels.is_bottommost(self.output_level, key)
+    }
+
+    // Whether a range tombstone of [start, end) no longer deletes anything
+    // once compacted
+    pub fn is_bottommost_range(&self, levels: &Levels, start: &[u8], end: &[u8]) -> bool {
+        self.output_level > 0 && levels.is_bottommost_range(self.output_level, start, end)
+    }
+}
+
+pub fn max_bytes_for_level(options: &DbOptions, level: usize) -> u64 {
+    let multiplier = options
+        .max_bytes_for_level_multiplier
+        .powi(level.saturating_sub(1) as i32);
+    (options.max_bytes_for_level_base as f64 * multiplier) as u64
+}
+
+// How far each level but the last is over its target, a level needs to be
+// compacted once its score reaches 1.
+pub fn level_scores(levels: &Levels, options: &DbOptions) -> Vec<f64> {
+    (0..levels.num_levels().saturating_sub(1))
+        .map(|level| {
+            if level == 0 {
+                let trigger = options.level0_file_num_compaction_trigger.max(1);
+                levels.files(0).len() as f64 / trigger as f64
+            } else {
+                levels.size(level) as f64 / max_bytes_for_level(options, level) as f64
+            }
+        })
+        .collect()
+}
+
+// Picks the level with the highest score. Level 0 files overlap so they are
+// all compacted at once, other levels hand over one file at a time, starting
+// after the largest key compacted out of the level the previous time.
+pub fn pick_leveled(
+    levels: &Levels,
+    options: &DbOptions,
+    compact_pointers: &[Vec<u8>],
+) -> Option<Compaction> {
+    let (level, _) = level_scores(levels, options)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, score)| *score >= 1.0)
+        .max_by(|(_, left), (_, right)| left.total_cmp(right))?;
+
+    let files: Vec<LevelFile> = if level == 0 {
+        levels.files(0).to_vec()
+    } else {
+        let files = levels.files(level);
+        let pointer = compact_pointers.get(level).filter(|p| !p.is_empty());
+        let file = pointer
+            .and_then(|pointer| files.iter().find(|f| f.meta.smallest > *pointer))
+            .or(files.first())?;
+        vec![file.clone()]
+    };
+
+    let smallest = files.iter().map(|f| &f.meta.smallest).min()?.clone();
+    let largest = files.iter().map(|f| &f.meta.largest).max()?.clone();
+    let output_level = level + 1;
+    let mut inputs = vec![(level, files)];
+    let overlapping = levels.overlapping(output_level, &smallest, &largest);
+    if !overlapping.is_empty() {
+        inputs.push((output_level, overlapping));
+    }
+    Some(Compaction {
+        level,
+        output_level,
+        inputs,
+        target_file_size: options.target_file_size_base,
+        deletion: false,
+        manual: false,
+    })
+}
+
+fn key_range(files: &[LevelFile]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let smallest = files.iter().map(|f| &f.meta.smallest).min()?;
+    let largest = files.iter().map(|f| &f.meta.largest).max()?;
+    Some((smallest.clone(), largest.clone()))
+}
+
+// Files of the level overlapping [start, end], compacted into the next level.
+// Level 0 files overlapping the ones picked go along with them, the older ones
+// would otherwise shadow the versions moved down.
+pub fn pick_range(
+    levels: &Levels,
+    options: &DbOptions,
+    level: usize,
+    start: Option<&[u8]>,
+    end: Option<&[u8]>,
+) -> Option<Compaction> {
+    let mut files: Vec<LevelFile> = levels
+        .files(level)
+        .iter()
+        .filter(|f| f.overlaps_range(start, end))
+        .cloned()
+        .collect();
+    let (mut smallest, mut largest) = key_range(&files)?;
+    if level == 0 {
+        loop {
+            let overlapping = levels.overlapping(0, &smallest, &largest);
+            if overlapping.len() == files.len() {
+                break;
+            }
+            files = overlapping;
+            (smallest, largest) = key_range(&files)?;
+        }
+    }
+
+    let output_level = level + 1;
+    let mut inputs = vec![(level, files)];
+    let overlapping = levels.overlapping(output_level, &smallest, &largest);
+    if !overlapping.is_empty() {
+        inputs.push((output_level, overlapping));
+    }
+    Some(Compaction {
+        level,
+        output_level,
+        inputs,
+        target_file_size: options.target_file_size_base,
+        deletion: false,
+        manual: true,
+    })
+}
+
+// Files of the last level overlapping [start, end], rewritten in place to
+// drop their deletes and shadowed versions. Files numbered from
+// `first_new_file` on were just written by the compactions above.
+pub fn pick_bottommost(
+    levels: &Levels,
+    options: &DbOptions,
+    level: usize,
+    start: Option<&[u8]>,
+    end: Option<&[u8]>,
+    first_new_file: u64,
+) -> Option<Compaction> {
+    let files: Vec<LevelFile> = levels
+        .files(level)
+        .iter()
+        .filter(|f| f.overlaps_range(start, end) && f.meta.file_number < first_new_file)
+        .cloned()
+        .collect();
+    if files.is_empty() {
+        return None;
+    }
+    Some(Compaction {
+        level,
+        output_level: level,
+        inputs: vec![(level, files)],
+        target_file_size: options.target_file_size_base,
+        deletion: false,
+        manual: true,
+    })
+}
+
+// Every level 0 file is a sorted run, newest first, followed by each non
+// empty level.
+fn sorted_runs(levels: &Levels) -> Vec<(usize, Vec<LevelFile>)> {
+    let mut runs: Vec<(usize, Vec<LevelFile>)> = levels
+        .files(0)
+        .iter()
+        .rev()
+        .map(|file| (0, vec![file.clone()]))
+        .collect();
+    for level in 1..levels.num_levels() {
+        if !levels.files(level).is_empty() {
+            runs.push((level, levels.files(level).to_vec()));
+        }
+    }
+    runs
+}
+
+fn run_size(run: &(usize, Vec<LevelFile>)) -> u64 {
+    run.1.iter().map(|f| f.meta.file_size).sum()
+}
+
+// Merges consecutive sorted runs once there are too many of them. All runs
+// are merged when the newer ones take too much space compared to the oldest,
+// otherwise the newest runs of similar sizes, and failing that just enough of
+// the newest runs to get back under the trigger.
+pub fn pick_universal(
+    levels: &Levels,
+    options: &DbOptions,
+    universal: &UniversalCompactionOptions,
+) -> Option<Compaction> {
+    let runs = sorted_runs(levels);
+    let trigger = options.level0_file_num_compaction_trigger.max(2);
+    if runs.len() < trigger {
+        return None;
+    }
+
+    let (oldest, newer) = runs.split_last()?;
+    let newer_size: u64 = newer.iter().map(run_size).sum();
+    let picked = if newer_size * 100 >= run_size(oldest) * universal.max_size_amplification_percent
+    {
+        0..runs.len()
+    } else {
+        let max_width = universal.max_merge_width.max(2);
+        let similar = (0..runs.len()).find_map(|start| {
+            let mut size = run_size(&runs[start]);
+            let mut end = start + 1;
+            while end < runs.len()
+                && end - start < max_width
+                && run_size(&runs[end]) * 100 <= size * (100 + universal.size_ratio)
+            {
+                size += run_size(&runs[end]);
+                end += 1;
+            }
+            (end - start >= universal.min_merge_width.max(2)).then_some(start..end)
+        });
+        similar.unwrap_or(0..runs.len() - trigger + 2)
+    };
+    Some(merge_runs(levels, options, &runs, picked))
+}
+
+// Merges every sorted run into the last level
+pub fn pick_universal_all(levels: &Levels, options: &DbOptions) -> Option<Compaction> {
+    let runs = sorted_runs(levels);
+    if runs.is_empty() {
+        return None;
+    }
+    Some(merge_runs(levels, options, &runs, 0..runs.len()))
+}
+
+fn merge_runs(
+    levels: &Levels,
+    options: &DbOptions,
+    runs: &[(usize, Vec<LevelFile>)],
+    picked: Range<usize>,
+) -> Compaction {
+    // The output replaces the picked runs, unless they include the oldest one
+    // the output then goes to the last level.
+    let output_level = if picked.end == runs.len() {
+        levels.num_levels() - 1
+    } else {
+        runs[picked.end - 1].0
+    };
+    let target_file_size = if output_level == 0 {
+        u64::MAX
+    } else {
+        options.target_file_size_base
+    };
+    let mut inputs: Vec<(usize, Vec<LevelFile>)> = Vec::new();
+    for (level, files) in &runs[picked] {
+        match inputs.last_mut() {
+            Some((last, last_files)) if last == level => last_files.extend(files.clone()),
+            _ => inputs.push((*level, files.clone())),
+        }
+    }
+    Compaction {
+        level: inputs[0].0,
+        output_level,
+        inputs,
+        target_file_size,
+        deletion: false,
+        manual: false,
+    }
+}
+
+// Deletes the oldest level 0 files, as long as they are expired or the files
+// take more space than allowed.
+pub fn pick_fifo(levels: &Levels, fifo: &FifoCompactionOptions, now: u64) -> Option<Compaction> {
+    let files = levels.files(0);
+    let mut total_size = levels.size(0);
+    let mut deleted = 0;
+    for file in files {
+        let expired = match (fifo.ttl, file.meta.creation_time) {
+            (Some(ttl), Some(time)) => time + ttl.as_secs() <= now,
+            _ => false,
+        };
+        if !expired && total_size <= fifo.max_table_files_size {
+            break;
+        }
+        total_size -= file.meta.file_size;
+        deleted += 1;
+    }
+    if deleted == 0 {
+        return None;
+    }
+    Some(Compaction {
+        level: 0,
+        output_level: 0,
+        inputs: vec![(0, files[..deleted].to_vec())],
+        target_file_size: u64::MAX,
+        deletion: true,
+        manual: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::range_tombstone::FragmentedRangeTombstones;
+    use crate::sst::filter::SstFilter;
+    use crate::sst::table::table::SstTable;
+    use crate::version::version::FileMetaData;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Adds a file without data, only its metadata matters to the picker
+    fn add_file(levels: &mut Levels, level: usize, file_number: u64, range: (&str, &str)) {
+        let meta = FileMetaData {
+            file_number,
+            file_size: 1024,
+            smallest: range.0.as_bytes().to_vec(),
+            largest: range.1.as_bytes().to_vec(),
+            smallest_seqno: 0,
+            largest_seqno: 0,
+            creation_time: None,
+        };
+        let table = SstTable::new(
+            "unused",
+            SstFilter::new(1, 0.01),
+            vec![],
+            FragmentedRangeTombstones::default(),
+        );
+        levels.add(level, Arc::new(meta), Arc::new(table));
+    }
+
+    // Adds a file spanning every key, created after the files added before it
+    fn add_sized_file(levels: &mut Levels, level: usize, file_number: u64, file_size: u64) {
+        let meta = FileMetaData {
+            file_number,
+            file_size,
+            smallest: b"a".to_vec(),
+            largest: b"z".to_vec(),
+            smallest_seqno: file_number,
+            largest_seqno: file_number,
+            creation_time: Some(file_number),
+        };
+        let table = SstTable::new(
+            "unused",
+            SstFilter::new(1, 0.01),
+            vec![],
+            FragmentedRangeTombstones::default(),
+        );
+        levels.add(level, Arc::new(meta), Arc::new(table));
+    }
+
+    fn file_numbers(compaction: &Compaction) -> Vec<(usize, u64)> {
+        compaction
+            .files()
+            .map(|(level, file)| (level, file.meta.file_number))
+            .collect()
+    }
+
+    #[test]
+    fn pick_level0() {
+        let options = DbOptions {
+            level0_file_num_compaction_trigger: 2,
+            ..Default::default()
+        };
+        let mut levels = Levels::new(3);
+        add_file(&mut levels, 0, 1, ("c", "f"));
+        add_file(&mut levels, 1, 2, ("a", "b"));
+        add_file(&mut levels, 1, 3, ("d", "e"));
+        add_file(&mut levels, 1, 4, ("h", "i"));
+        assert!(pick_leveled(&levels, &options, &[]).is_none());
+
+        add_file(&mut levels, 0, 5, ("e", "g"));
+        assert_eq!(level_scores(&levels, &options)[0], 1.0);
+        let compaction = pick_leveled(&levels, &options, &[]).unwrap();
+        assert_eq!(compaction.output_level, 1);
+        assert_eq!(file_numbers(&compaction), vec![(0, 1), (0, 5), (1, 3)]);
+        assert!(!compaction.is_trivial_move());
+    }
+
+    #[test]
+    fn pick_round_robin() {
+        let options = DbOptions {
+            max_bytes_for_level_base: 2048,
+            max_bytes_for_level_multiplier: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(max_bytes_for_level(&options, 2), 4096);
+
+        let mut levels = Levels::new(3);
+        add_file(&mut levels, 1, 1, ("a", "b"));
+        add_file(&mut levels, 1, 2, ("c", "d"));
+        add_file(&mut levels, 1, 3, ("e", "f"));
+        add_file(&mut levels, 2, 4, ("d", "e"));
+
+        let compaction = pick_leveled(&levels, &options, &[]).unwrap();
+        assert_eq!(file_numbers(&compaction), vec![(1, 1)]);
+        assert!(compaction.is_trivial_move());
+
+        let pointers = vec![Vec::new(), b"b".to_vec()];
+        let compaction = pick_leveled(&levels, &options, &pointers).unwrap();
+        assert_eq!(file_numbers(&compaction), vec![(1, 2), (2, 4)]);
+
+        // Wraps around past the last file
+        let pointers = vec![Vec::new(), b"f".to_vec()];
+        let compaction = pick_leveled(&levels, &options, &pointers).unwrap();
+        assert_eq!(file_numbers(&compaction), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn pick_universal_runs() {
+        let options = DbOptions {
+            level0_file_num_compaction_trigger: 3,
+            ..Default::default()
+        };
+        let universal = UniversalCompactionOptions::default();
+
+        let mut levels = Levels::new(3);
+        add_sized_file(&mut levels, 2, 1, 10000);
+        add_sized_file(&mut levels, 0, 2, 1000);
+        assert!(pick_universal(&levels, &options, &universal).is_none());
+
+        // The newest runs have similar sizes
+        add_sized_file(&mut levels, 0, 3, 100);
+        add_sized_file(&mut levels, 0, 4, 100);
+        let compaction = pick_universal(&levels, &options, &universal).unwrap();
+        assert_eq!(compaction.output_level, 0);
+        assert_eq!(file_numbers(&compaction), vec![(0, 4), (0, 3)]);
+        assert!(!compaction.is_trivial_move());
+
+        // None do, the newest runs are merged to get under the trigger
+        let mut levels = Levels::new(3);
+        add_sized_file(&mut levels, 2, 1, 100000);
+        add_sized_file(&mut levels, 0, 2, 4000);
+        add_sized_file(&mut levels, 0, 3, 400);
+        add_sized_file(&mut levels, 0, 4, 40);
+        let compaction = pick_universal(&levels, &options, &universal).unwrap();
+        assert_eq!(compaction.output_level, 0);
+        assert_eq!(file_numbers(&compaction), vec![(0, 4), (0, 3), (0, 2)]);
+    }
+
+    #[test]
+    fn pick_universal_size_amplification() {
+        let options = DbOptions {
+            level0_file_num_compaction_trigger: 2,
+            ..Default::default()
+        };
+        let universal = UniversalCompactionOptions::default();
+
+        let mut levels = Levels::new(3);
+        add_sized_file(&mut levels, 2, 1, 500);
+        add_sized_file(&mut levels, 0, 2, 4000);
+        add_sized_file(&mut levels, 0, 3, 40);
+        let compaction = pick_universal(&levels, &options, &universal).unwrap();
+        assert_eq!(compaction.output_level, 2);
+        assert_eq!(file_numbers(&compaction), vec![(0, 3), (0, 2), (2, 1)]);
+        assert_eq!(compaction.target_file_size, options.target_file_size_base);
+    }
+
+    #[test]
+    fn pick_fifo_oldest() {
+        let mut levels = Levels::new(3);
+        add_sized_file(&mut levels, 0, 1, 100);
+        add_sized_file(&mut levels, 0, 2, 100);
+        add_sized_file(&mut levels, 0, 3, 100);
+
+        let mut fifo = FifoCompactionOptions {
+            max_table_files_size: 300,
+            ttl: None,
+        };
+        assert!(pick_fifo(&levels, &fifo, 100).is_none());
+
+        fifo.max_table_files_size = 250;
+        let compaction = pick_fifo(&levels, &fifo, 100).unwrap();
+        assert!(compaction.deletion);
+        assert_eq!(file_numbers(&compaction), vec![(0, 1)]);
+
+        fifo.max_table_files_size = 1000;
+        fifo.ttl = Some(Duration::from_secs(10));
+        let compaction = pick_fifo(&levels, &fifo, 12).unwrap();
+        assert_eq!(file_numbers(&compaction), vec![(0, 1), (0, 2)]);
+        assert!(pick_fifo(&levels, &fifo, 10).is_none());
+    }
+
+    #[test]
+    fn pick_range_files() {
+        let options = DbOptions::default();
+        let mut levels = Levels::new(3);
+        add_file(&mut levels, 0, 1, ("a", "c"));
+        add_file(&mut levels, 0, 2, ("c", "e"));
+        add_file(&mut levels, 0, 3, ("f", "g"));
+        add_file(&mut levels, 1, 4, ("a", "b"));
+        add_file(&mut levels, 1, 5, ("d", "h"));
+
+        // File 1 overlaps file 2, which holds the range
+        let compaction = pick_range(&levels, &options, 0, Some(b"d"), Some(b"d")).unwrap();
+        assert_eq!(compaction.output_level, 1);
+        assert_eq!(
+            file_numbers(&compaction),
+            vec![(0, 1), (0, 2), (1, 4), (1, 5)]
+        );
+
+        let compaction = pick_range(&levels, &options, 1, Some(b"c"), None).unwrap();
+        assert_eq!(file_numbers(&compaction), vec![(1, 5)]);
+        assert!(pick_range(&levels, &options, 1, Some(b"i"), None).is_none());
+        assert!(pick_range(&levels, &options, 2, None, None).is_none());
+
+        // Never moved without being rewritten
+        add_file(&mut levels, 0, 6, ("x", "y"));
+        let compaction = pick_range(&levels, &options, 0, Some(b"x"), None).unwrap();
+        assert_eq!(file_numbers(&compaction), vec![(0, 6)]);
+        assert!(!compaction.is_trivial_move());
+    }
+
+    #[test]
+    fn pick_bottommost_files() {
+        let options = DbOptions::default();
+        let mut levels = Levels::new(3);
+        add_file(&mut levels, 2, 1, ("a", "c"));
+        add_file(&mut levels, 2, 2, ("d", "f"));
+        add_file(&mut levels, 2, 3, ("g", "i"));
+
+        let compaction = pick_bottommost(&levels, &options, 2, Some(b"b"), None, 10).unwrap();
+        assert_eq!((compaction.level, compaction.output_level), (2, 2));
+        assert_eq!(file_numbers(&compaction), vec![(2, 1), (2, 2), (2, 3)]);
+        // Files just written are left alone
+        let compaction = pick_bottommost(&levels, &options, 2, None, None, 3).unwrap();
+        assert_eq!(file_numbers(&compaction), vec![(2, 1), (2, 2)]);
+        assert!(pick_bottommost(&levels, &options, 2, Some(b"j"), None, 10).is_none());
+    }
+
+    #[test]
+    fn split_sub_ranges() {
+        let options = DbOptions::default();
+        let mut levels = Levels::new(3);
+        add_file(&mut levels, 1, 1, ("a", "b"));
+        add_file(&mut levels, 1, 2, ("c", "d"));
+        add_file(&mut levels, 1, 3, ("e", "f"));
+        add_file(&mut levels, 2, 4, ("c", "g"));
+        let compaction = pick_range(&levels, &options, 1, None, None).unwrap();
+
+        assert_eq!(compaction.sub_ranges(1), vec![(None, None)]);
+        assert_eq!(
+            compaction.sub_ranges(2),
+            vec![(None, Some(b"e".to_vec())), (Some(b"e".to_vec()), None)]
+        );
+        // No more ranges than distinct file boundaries
+        assert_eq!(
+            compaction.sub_ranges(10),
+            vec![
+                (None, Some(b"c".to_vec())),
+                (Some(b"c".to_vec()), Some(b"e".to_vec())),
+                (Some(b"e".to_vec()), None)
+            ]
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use crate::db::options::DbOptions;
+use crate::levels::levels::Levels;
+use crate::memtable::memtable::MemTable;
+
+pub const DEFAULT_COLUMN_FAMILY: u32 = 0;
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+// Names a column family in reads and writes. Using it once the family is
+// dropped fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnFamilyHandle {
+    id: u32,
+    name: String,
+}
+
+impl ColumnFamilyHandle {
+    pub(crate) fn new(id: u32, name: String) -> Self {
+        Self { id, name }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// A key space with its own memtables, levels and options. Column families
+// share the wal, so that a batch spanning several of them stays atomic, as
+// well as file and sequence numbers.
+#[derive(Debug, Clone)]
+pub struct ColumnFamily {
+    pub name: String,
+    pub options: DbOptions,
+    pub memtable: Arc<MemTable>,
+    // Oldest first
+    pub immutables: Vec<Arc<MemTable>>,
+    pub levels: Arc<Levels>,
+}
+
+impl ColumnFamily {
+    pub fn new(name: String, options: DbOptions, levels: Levels) -> Self {
+        Self {
+            name,
+            options,
+            memtable: Arc::new(MemTable::new()),
+            immutables: Vec::new(),
+            levels: Arc::new(levels),
+        }
+    }
+}
+
+pub fn column_family_dropped() -> Error {
+    Error::new(ErrorKind::InvalidInput, "Column family dropped")
+}
@@ -1,21 +1,48 @@
 use tokio::fs::{create_dir_all, read_to_string, write};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
 use tracing::info;
 use uuid::Uuid;
 
-use crate::db::options::DbOptions;
+pub use crate::compaction::compactor::CompactionStats;
+use crate::compaction::compactor::{CompactionRequest, Compactor};
+use crate::db::column_family::{
+    column_family_dropped, ColumnFamily, ColumnFamilyHandle, DEFAULT_COLUMN_FAMILY,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+use crate::db::flush::{FlushRequest, Flusher};
+use crate::db::iterator::{Cursor, DbIterator, LevelCursor, MemTableCursor};
+use crate::db::merge_operator::{merge_operands, no_merge_operator};
+use crate::db::options::{CompactRangeOptions, DbOptions, ReadOptions, WriteOptions};
+use crate::db::snapshot::Snapshot;
+use crate::db::state::DbState;
+use crate::db::transaction::{transaction_conflict, OptimisticTransaction};
+use crate::key::internal_key::{ValueType, MAX_SEQ_NUM};
+use crate::key::range_tombstone::FragmentedRangeTombstones;
+use crate::levels::levels::Levels;
 use crate::manifest::entry::ManifestLogEntry;
-use crate::manifest::manifest::{Manifest, ManifestRequest};
+use crate::manifest::manifest::{append_entries, Manifest, ManifestRequest};
+use crate::memtable::memtable::MemTableValue;
+use crate::sst::table::cursor::SstTableCursor;
+use crate::version::version::Version;
 use crate::wal::entry::WalEntry;
-use crate::wal::manager::{WalManager, WalRequest};
+use crate::wal::manager::{WalCommand, WalManager, WalRequest};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 use tracing::instrument;
 
 pub enum DbCmd {
     Set { key: Vec<u8>, value: Vec<u8> },
     Delete { key: Vec<u8> },
+    Merge { key: Vec<u8>, operand: Vec<u8> },
+    DeleteRange { start: Vec<u8>, end: Vec<u8> },
+    // Only for keys set once since they were last deleted, see
+    // Db::single_delete
+    SingleDelete { key: Vec<u8> },
 }
 
 impl Into<WalEntry> for DbCmd {
@@ -23,6 +50,9 @@ impl Into<WalEntry> for DbCmd {
         match self {
             DbCmd::Set { key, value } => WalEntry::Set { key, value },
             DbCmd::Delete { key } => WalEntry::Delete { key },
+            DbCmd::Merge { key, operand } => WalEntry::Merge { key, operand },
+            DbCmd::DeleteRange { start, end } => WalEntry::DeleteRange { start, end },
+            DbCmd::SingleDelete { key } => WalEntry::SingleDelete { key },
         }
     }
 }
@@ -30,20 +60,36 @@ impl Into<WalEntry> for DbCmd {
 pub struct Db {
     id: Uuid,
     path: PathBuf,
-    options: DbOptions,
-    manifest: Manifest,
-    wal: WalManager,
-    seq_num: u64,
-    wal_sender: Sender<WalRequest>,
-    db_receiver: Receiver<DbCmd>,
+    state: Arc<RwLock<DbState>>,
+    // Largest column family id ever used, ids are not reused once dropped.
+    // Held while a column family is created or dropped.
+    max_column_family: Mutex<u32>,
+    // Next sequence number to assign. Held while a batch is queued to the wal
+    // so that batches reach it in sequence order.
+    seq_num: Mutex<u64>,
+    wal_sender: Sender<WalCommand>,
+    wal_handle: JoinHandle<Result<()>>,
+    flush_handle: JoinHandle<Result<()>>,
+    compaction_sender: Sender<CompactionRequest>,
+    compaction_handle: JoinHandle<Result<()>>,
+    manifest_sender: Sender<ManifestRequest>,
+    manifest_handle: JoinHandle<Result<()>>,
 }
 
 impl Db {
+    pub async fn open<P: Into<PathBuf> + Debug>(path: P, options: DbOptions) -> Result<Self> {
+        Self::open_with_column_families(path, options, Vec::new()).await
+    }
+
+    // Opens the database with the options of some of its column families, the
+    // others get the database options. The default column family always
+    // does, unless listed.
     #[instrument]
-    pub async fn open<P: Into<PathBuf> + Debug>(
+    pub async fn open_with_column_families<P: Into<PathBuf> + Debug>(
         path: P,
         options: DbOptions,
-    ) -> Result<(Self, Sender<DbCmd>)> {
+        column_families: Vec<(String, DbOptions)>,
+    ) -> Result<Self> {
         let path = path.into();
         create_dir_all(&path).await?;
         // let manifest = Manifest::open(path.clone()).await?;
@@ -51,24 +97,95 @@ impl Db {
 
         let (id, created) = Self::open_identity(&path).await?;
 
-        let (manifest, manifest_sender) = Self::open_manifest(&path, created, id).await?;
+        let (mut manifest, manifest_sender) = Self::open_manifest(&path, created, id).await?;
+        let version = manifest.current();
+
+        let mut listed: BTreeMap<String, DbOptions> = column_families.into_iter().collect();
+        let mut families = BTreeMap::new();
+        let names = std::iter::once((&DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME)).chain(
+            version
+                .column_families()
+                .iter()
+                .map(|(id, name)| (id, name.as_str())),
+        );
+        for (id, name) in names {
+            let family_options = listed.remove(name).unwrap_or_else(|| options.clone());
+            let levels = Levels::open(&path, &version, *id, family_options.num_levels).await?;
+            families.insert(
+                *id,
+                ColumnFamily::new(name.to_string(), family_options, levels),
+            );
+        }
+        if let Some(name) = listed.keys().next() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Unknown column family {}", name),
+            ));
+        }
+        info!("Opened {} column families", families.len());
+
+        let manifest_handle = tokio::spawn(async move { manifest.run().await });
+
+        let state = Arc::new(RwLock::new(DbState::new(
+            families,
+            version.next_file_number(),
+        )));
 
-        let (wal, wal_sender) =
-            Self::open_wal(&path, options.clone(), created, manifest_sender).await?;
+        let (flush_sender, flush_receiver) = tokio::sync::mpsc::channel(1024);
+        let (mut wal, wal_sender) = Self::open_wal(
+            &path,
+            options.clone(),
+            created,
+            manifest_sender.clone(),
+            flush_sender,
+            state.clone(),
+            &version,
+        )
+        .await?;
 
-        let (db_sender, db_receiver) = channel(1024);
+        let last_sequence = wal.last_seq_num().max(version.last_sequence());
+        state.write().unwrap().last_sequence = last_sequence;
+        let seq_num = last_sequence.map_or(0, |seq_num| seq_num + 1);
+        info!("Next sequence number: {}", seq_num);
+
+        // A single pending request is enough, the compactor catches up with
+        // every level it is given
+        let (compaction_sender, compaction_receiver) = tokio::sync::mpsc::channel(1);
+        let mut flusher = Flusher::new(
+            path.clone(),
+            state.clone(),
+            manifest_sender.clone(),
+            flush_receiver,
+            compaction_sender.clone(),
+            version.log_number(),
+        );
+        let flush_handle = tokio::spawn(async move { flusher.run().await });
+        let mut compactor = Compactor::new(
+            path.clone(),
+            state.clone(),
+            manifest_sender.clone(),
+            compaction_receiver,
+        );
+        let compaction_handle = tokio::spawn(async move { compactor.run().await });
+        // Levels may have been left over their limits by the previous run
+        let _ = compaction_sender.try_send(CompactionRequest::Schedule);
+        let wal_handle = tokio::spawn(async move { wal.run().await });
 
         let db = Self {
             id,
             path,
-            options,
-            manifest,
-            wal,
-            seq_num: 0,
+            state,
+            max_column_family: Mutex::new(version.max_column_family()),
+            seq_num: Mutex::new(seq_num),
             wal_sender,
-            db_receiver,
+            wal_handle,
+            flush_handle,
+            compaction_sender,
+            compaction_handle,
+            manifest_sender,
+            manifest_handle,
         };
-        Ok((db, db_sender))
+        Ok(db)
     }
 
     #[instrument]
@@ -89,36 +206,493 @@ impl Db {
         }
     }
 
-    pub async fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>> {
-        Ok(None)
+    // Pins the writes acknowledged so far, see ReadOptions::snapshot
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.state.read().unwrap();
+        Snapshot::new(&state.snapshots, state.last_sequence)
     }
 
-    pub async fn set<'a>(&mut self, key: &'a [u8], value: &'a [u8]) -> Result<()> {
-        self.batch(vec![DbCmd::Set {
-            key: key.into(),
-            value: value.into(),
-        }])
-        .await
+    pub fn default_column_family(&self) -> ColumnFamilyHandle {
+        ColumnFamilyHandle::new(
+            DEFAULT_COLUMN_FAMILY,
+            DEFAULT_COLUMN_FAMILY_NAME.to_string(),
+        )
     }
 
-    pub async fn delete<'a>(&mut self, key: &'a [u8]) -> Result<()> {
-        self.batch(vec![DbCmd::Delete { key: key.into() }]).await
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamilyHandle> {
+        let state = self.state.read().unwrap();
+        state
+            .column_families
+            .iter()
+            .find(|(_, family)| family.name == name)
+            .map(|(id, family)| ColumnFamilyHandle::new(*id, family.name.clone()))
     }
 
-    fn incr_seq_num(&mut self) -> u64 {
-        let seq_num = self.seq_num;
-        self.seq_num += 1;
-        seq_num
+    // Names of the column families, default one included
+    pub fn column_family_names(&self) -> Vec<String> {
+        let state = self.state.read().unwrap();
+        state
+            .column_families
+            .values()
+            .map(|family| family.name.clone())
+            .collect()
+    }
+
+    pub async fn create_column_family(
+        &self,
+        name: &str,
+        options: DbOptions,
+    ) -> Result<ColumnFamilyHandle> {
+        let mut max_column_family = self.max_column_family.lock().await;
+        if self.column_family(name).is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Column family {} already exists", name),
+            ));
+        }
+        let id = *max_column_family + 1;
+        append_entries(
+            &self.manifest_sender,
+            vec![
+                ManifestLogEntry::ColumnFamilyAdd {
+                    column_family: id,
+                    name: name.to_string(),
+                },
+                ManifestLogEntry::MaxColumnFamily {
+                    max_column_family: id,
+                },
+            ],
+        )
+        .await?;
+        *max_column_family = id;
+        let levels = Levels::new(options.num_levels);
+        let family = ColumnFamily::new(name.to_string(), options, levels);
+        self.state
+            .write()
+            .unwrap()
+            .column_families
+            .insert(id, family);
+        info!("Created column family {} with id {}", name, id);
+        Ok(ColumnFamilyHandle::new(id, name.to_string()))
     }
 
-    pub async fn batch<'a>(&mut self, batch: Vec<DbCmd>) -> Result<()> {
-        let seq_num = self.incr_seq_num();
-        let batch = batch.into_iter().map(|cmd| cmd.into()).collect();
-        let req = WalRequest::new(seq_num, batch);
-        self.wal_sender.send(req).await.unwrap();
+    // Its files are deleted once no read uses them anymore, and its writes
+    // still in the wal are skipped when replayed
+    pub async fn drop_column_family(&self, column_family: &ColumnFamilyHandle) -> Result<()> {
+        if column_family.id() == DEFAULT_COLUMN_FAMILY {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The default column family cannot be dropped",
+            ));
+        }
+        let _guard = self.max_column_family.lock().await;
+        if !self
+            .state
+            .read()
+            .unwrap()
+            .column_families
+            .contains_key(&column_family.id())
+        {
+            return Err(column_family_dropped());
+        }
+        append_entries(
+            &self.manifest_sender,
+            vec![ManifestLogEntry::ColumnFamilyDrop {
+                column_family: column_family.id(),
+            }],
+        )
+        .await?;
+        let family = self
+            .state
+            .write()
+            .unwrap()
+            .column_families
+            .remove(&column_family.id());
+        if let Some(family) = family {
+            for level in 0..family.levels.num_levels() {
+                for file in family.levels.files(level) {
+                    file.table.mark_obsolete();
+                }
+            }
+        }
+        info!("Dropped column family {}", column_family.name());
         Ok(())
     }
 
+    // The column family along with the last sequence number applied
+    fn column_family_state(
+        &self,
+        column_family: &ColumnFamilyHandle,
+    ) -> Result<(ColumnFamily, Option<u64>)> {
+        let state = self.state.read().unwrap();
+        let family = state
+            .column_families
+            .get(&column_family.id())
+            .ok_or_else(column_family_dropped)?;
+        Ok((family.clone(), state.last_sequence))
+    }
+
+    // Starts a transaction reading from a snapshot taken now, see
+    // OptimisticTransaction
+    pub fn optimistic_transaction(&self) -> OptimisticTransaction<'_> {
+        OptimisticTransaction::new(self)
+    }
+
+    // Iterates over the keys visible to the options snapshot, or to the writes
+    // acknowledged so far without one. The iterator starts unpositioned.
+    pub fn iter(&self, options: &ReadOptions<'_>) -> DbIterator {
+        self.iter_cf(&self.default_column_family(), options)
+            .expect("The default column family is never dropped")
+    }
+
+    pub fn iter_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        options: &ReadOptions<'_>,
+    ) -> Result<DbIterator> {
+        let (family, last_sequence) = self.column_family_state(column_family)?;
+        let seq_num = match options.snapshot {
+            Some(snapshot) => snapshot.seq_num(),
+            None => last_sequence,
+        };
+        let mut cursors = Vec::new();
+        let mut range_tombstones = Vec::new();
+        let memtables = std::iter::once(&family.memtable).chain(family.immutables.iter());
+        for memtable in memtables {
+            cursors.push(Cursor::MemTable(MemTableCursor::new(memtable.clone())));
+            range_tombstones.extend(memtable.range_tombstones());
+        }
+        for level in 0..family.levels.num_levels() {
+            let files = family.levels.files(level);
+            for file in files {
+                range_tombstones.extend(file.table.range_tombstones().tombstones());
+                if level == 0 {
+                    cursors.push(Cursor::Table(SstTableCursor::new(file.table.clone())));
+                }
+            }
+            if level > 0 && !files.is_empty() {
+                cursors.push(Cursor::Level(LevelCursor::new(files.to_vec())));
+            }
+        }
+        Ok(DbIterator::new(
+            cursors,
+            FragmentedRangeTombstones::new(&range_tombstones),
+            family.options.merge_operator.clone(),
+            seq_num,
+            options.lower_bound.map(|key| key.to_vec()),
+            options.upper_bound.map(|key| key.to_vec()),
+        ))
+    }
+
+    pub async fn get(&self, key: &[u8], options: &ReadOptions<'_>) -> Result<Option<Vec<u8>>> {
+        self.get_cf(&self.default_column_family(), key, options)
+            .await
+    }
+
+    pub async fn get_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+        options: &ReadOptions<'_>,
+    ) -> Result<Option<Vec<u8>>> {
+        let (family, last_sequence) = self.column_family_state(column_family)?;
+        let merge = |existing, operands| {
+            merge_operands(
+                family.options.merge_operator.as_ref(),
+                key,
+                existing,
+                operands,
+            )
+        };
+        // Merge operands found on the way down, newest first
+        let mut operands = Vec::new();
+        // Writes still being applied are not acknowledged yet
+        let mut seq_num = match options.snapshot {
+            Some(snapshot) => snapshot.seq_num(),
+            None => last_sequence,
+        };
+        let memtables = std::iter::once(&family.memtable).chain(family.immutables.iter().rev());
+        for memtable in memtables {
+            while let Some((found, value)) = seq_num.and_then(|s| memtable.get_at(key, s)) {
+                match value {
+                    MemTableValue::Set(value) => return merge(Some(value), operands),
+                    MemTableValue::Delete | MemTableValue::SingleDelete => {
+                        return merge(None, operands)
+                    }
+                    MemTableValue::Merge(operand) => operands.push(operand),
+                }
+                seq_num = found.checked_sub(1);
+            }
+        }
+        while let Some(s) = seq_num {
+            let Some((found, value)) = family.levels.get(key, s).await? else {
+                break;
+            };
+            match found.value_type {
+                ValueType::Set => return merge(Some(value), operands),
+                ValueType::Delete | ValueType::SingleDelete => break,
+                ValueType::Merge => operands.push(value),
+            }
+            seq_num = found.seq_num.checked_sub(1);
+        }
+        merge(None, operands)
+    }
+
+    pub async fn set<'a>(
+        &self,
+        key: &'a [u8],
+        value: &'a [u8],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.batch(
+            vec![DbCmd::Set {
+                key: key.into(),
+                value: value.into(),
+            }],
+            options,
+        )
+        .await
+    }
+
+    pub async fn set_cf<'a>(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        key: &'a [u8],
+        value: &'a [u8],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.batch_cf(
+            vec![(
+                column_family,
+                DbCmd::Set {
+                    key: key.into(),
+                    value: value.into(),
+                },
+            )],
+            options,
+        )
+        .await
+    }
+
+    pub async fn delete(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        self.batch(vec![DbCmd::Delete { key: key.into() }], options)
+            .await
+    }
+
+    pub async fn delete_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.batch_cf(
+            vec![(column_family, DbCmd::Delete { key: key.into() })],
+            options,
+        )
+        .await
+    }
+
+    // Deletes a key written with a single set, both vanish from the files as
+    // soon as a compaction sees them together instead of the delete being
+    // carried down to the last level. The outcome is undefined when the key
+    // was set more than once, merged into, or deleted again since its last
+    // delete: older values may come back. Reads see it as a regular delete.
+    pub async fn single_delete(&self, key: &[u8], options: &WriteOptions) -> Result<()> {
+        self.batch(vec![DbCmd::SingleDelete { key: key.into() }], options)
+            .await
+    }
+
+    // Deletes every key of [start, end)
+    pub async fn delete_range<'a>(
+        &self,
+        start: &'a [u8],
+        end: &'a [u8],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.batch(
+            vec![DbCmd::DeleteRange {
+                start: start.into(),
+                end: end.into(),
+            }],
+            options,
+        )
+        .await
+    }
+
+    // Queues the operand to be combined with the value of the key by the
+    // merge operator, without reading it
+    pub async fn merge<'a>(
+        &self,
+        key: &'a [u8],
+        operand: &'a [u8],
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.batch(
+            vec![DbCmd::Merge {
+                key: key.into(),
+                operand: operand.into(),
+            }],
+            options,
+        )
+        .await
+    }
+
+    // Resolves once the batch is persisted in the wal and visible to reads
+    pub async fn batch(&self, batch: Vec<DbCmd>, options: &WriteOptions) -> Result<()> {
+        let column_family = self.default_column_family();
+        let batch = batch.into_iter().map(|cmd| (&column_family, cmd)).collect();
+        self.batch_cf(batch, options).await
+    }
+
+    // A batch spanning several column families is applied to all of them or
+    // to none
+    pub async fn batch_cf(
+        &self,
+        batch: Vec<(&ColumnFamilyHandle, DbCmd)>,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.batch_unless_changed(batch, &BTreeSet::new(), None, options)
+            .await
+    }
+
+    // Writes the batch unless one of the keys read, by column family, was
+    // written to or deleted after the snapshot
+    pub(crate) async fn batch_unless_changed(
+        &self,
+        batch: Vec<(&ColumnFamilyHandle, DbCmd)>,
+        reads: &BTreeSet<(u32, Vec<u8>)>,
+        snapshot: Option<u64>,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        {
+            let state = self.state.read().unwrap();
+            for (column_family, cmd) in &batch {
+                let family = state
+                    .column_families
+                    .get(&column_family.id())
+                    .ok_or_else(column_family_dropped)?;
+                if matches!(cmd, DbCmd::Merge { .. }) && family.options.merge_operator.is_none() {
+                    return Err(no_merge_operator());
+                }
+            }
+        }
+        if batch
+            .iter()
+            .any(|(_, cmd)| matches!(cmd, DbCmd::DeleteRange { start, end } if start > end))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Range start is after its end",
+            ));
+        }
+        let count = batch.len() as u64;
+        let batch = batch
+            .into_iter()
+            .map(|(column_family, cmd)| (column_family.id(), cmd.into()))
+            .collect();
+        let completion = {
+            let mut seq_num = self.seq_num.lock().await;
+            if !reads.is_empty() {
+                // Writes queued before this batch are checked against too,
+                // later ones wait for the lock
+                self.barrier().await?;
+                for (column_family, key) in reads {
+                    let latest = self.latest_seq_num(*column_family, key).await?;
+                    if latest.is_some_and(|latest| snapshot.is_none_or(|s| latest > s)) {
+                        return Err(transaction_conflict());
+                    }
+                }
+            }
+            // Every entry of a batch gets its own sequence number so that a
+            // key written twice in the same batch keeps its last value.
+            let (req, completion) = WalRequest::new(*seq_num, batch, options.sync);
+            *seq_num += count;
+            self.wal_sender
+                .send(req.into())
+                .await
+                .map_err(|_| wal_stopped())?;
+            completion
+        };
+        completion.await.map_err(|_| wal_stopped())?
+    }
+
+    // Resolves once every write queued so far is applied to the memtables
+    async fn barrier(&self) -> Result<()> {
+        let (completion, receiver) = oneshot::channel();
+        self.wal_sender
+            .send(WalCommand::Barrier { completion })
+            .await
+            .map_err(|_| wal_stopped())?;
+        receiver.await.map_err(|_| wal_stopped())
+    }
+
+    // Sequence number of the newest version of the key, deletes included
+    async fn latest_seq_num(&self, column_family: u32, key: &[u8]) -> Result<Option<u64>> {
+        let family = {
+            let state = self.state.read().unwrap();
+            state
+                .column_families
+                .get(&column_family)
+                .cloned()
+                .ok_or_else(column_family_dropped)?
+        };
+        let memtables = std::iter::once(&family.memtable).chain(family.immutables.iter().rev());
+        for memtable in memtables {
+            if let Some((found, _)) = memtable.get_at(key, MAX_SEQ_NUM) {
+                return Ok(Some(found));
+            }
+        }
+        let found = family.levels.get(key, MAX_SEQ_NUM).await?;
+        Ok(found.map(|(found, _)| found.seq_num))
+    }
+
+    // Resolves once every write acknowledged so far is persisted in sst files
+    pub async fn flush(&self) -> Result<()> {
+        let (completion, receiver) = oneshot::channel();
+        self.wal_sender
+            .send(WalCommand::Flush { completion })
+            .await
+            .map_err(|_| wal_stopped())?;
+        receiver.await.map_err(|_| wal_stopped())?
+    }
+
+    // Compacts every file holding keys of [start, end] down to the last level
+    // holding any of them, once the memtables are flushed, rewriting the
+    // files already there. Universal
+    // compaction merges every sorted run whatever the range, FIFO compaction
+    // never rewrites anything.
+    pub async fn compact_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        options: &CompactRangeOptions,
+    ) -> Result<CompactionStats> {
+        self.compact_range_cf(&self.default_column_family(), start, end, options)
+            .await
+    }
+
+    pub async fn compact_range_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        options: &CompactRangeOptions,
+    ) -> Result<CompactionStats> {
+        self.flush().await?;
+        let (completion, receiver) = oneshot::channel();
+        self.compaction_sender
+            .send(CompactionRequest::CompactRange {
+                column_family: column_family.id(),
+                start: start.map(|start| start.to_vec()),
+                end: end.map(|end| end.to_vec()),
+                options: options.clone(),
+                completion,
+            })
+            .await
+            .map_err(|_| compactor_stopped())?;
+        receiver.await.map_err(|_| compactor_stopped())?
+    }
+
     #[instrument]
     async fn open_manifest(
         path: &PathBuf,
@@ -143,8 +717,18 @@ impl Db {
         Ok((manifest, sender))
     }
 
-    pub async fn run(&mut self) {
-        let _ = self.wal.run().await;
+    pub async fn close(self) -> Result<()> {
+        // Closing the wal channel stops the wal manager, which in turn stops
+        // the flusher. The compactor stops once it is done with the last
+        // flushes, dropping the last manifest sender.
+        drop(self.wal_sender);
+        self.wal_handle.await??;
+        self.flush_handle.await??;
+        drop(self.compaction_sender);
+        self.compaction_handle.await??;
+        drop(self.manifest_sender);
+        self.manifest_handle.await??;
+        Ok(())
     }
 
     #[instrument]
@@ -153,58 +737,91 @@ impl Db {
         options: DbOptions,
         new: bool,
         manifest_sender: Sender<ManifestRequest>,
-    ) -> Result<(WalManager, Sender<WalRequest>)> {
+        flush_sender: Sender<FlushRequest>,
+        state: Arc<RwLock<DbState>>,
+        version: &Version,
+    ) -> Result<(WalManager, Sender<WalCommand>)> {
         let (wal_sender, wal_receiver) = tokio::sync::mpsc::channel(1024);
         let wal = if new {
             info!("Creating new wal");
-            WalManager::create(path.clone(), manifest_sender, wal_receiver, options).await?
+            WalManager::create(
+                path.clone(),
+                manifest_sender,
+                flush_sender,
+                wal_receiver,
+                state,
+                options,
+            )
+            .await?
         } else {
             info!("Opening existing wal");
-            WalManager::load(path.clone(), manifest_sender, wal_receiver, options).await?
+            WalManager::load(
+                path.clone(),
+                manifest_sender,
+                flush_sender,
+                wal_receiver,
+                state,
+                version,
+                options,
+            )
+            .await?
         };
         Ok((wal, wal_sender))
     }
 }
 
+fn wal_stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Wal manager stopped")
+}
+
+fn compactor_stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Compactor stopped")
+}
+
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
-
-    use crate::db::db::Db;
-    use crate::db::db::DbCmd;
-    use crate::db::options::DbOptions;
+    use crate::db::column_family::{ColumnFamily, DEFAULT_COLUMN_FAMILY};
+    use crate::db::db::{CompactionStats, Db, DbCmd};
+    use crate::db::merge_operator::U64AddOperator;
+    use crate::db::options::{
+        CompactRangeOptions, CompactionDecision, CompactionFilter, CompactionStyle, DbOptions,
+        FifoCompactionOptions, ReadOptions, UniversalCompactionOptions, WriteOptions,
+    };
+    use crate::memtable::memtable::MemTableValue;
     use crate::utils::tracing::init_tracer;
+    use std::collections::BTreeMap;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::time::Duration;
     use tempfile::tempdir;
-    use tokio::{
-        fs::{create_dir_all, read_to_string},
-        join,
-    };
+    use tokio::fs::{read_to_string, OpenOptions};
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::sleep;
     use tracing::{info_span, Instrument};
 
+    fn default_family(db: &Db) -> ColumnFamily {
+        db.state.read().unwrap().column_families[&DEFAULT_COLUMN_FAMILY].clone()
+    }
+
     #[tokio::test]
     async fn open_table() {
         init_tracer();
         let span = info_span!("open_table");
 
         async move {
-            // let tmpdir = tempdir().unwrap();
-            // let path = tmpdir.path();
-            let path = Path::new("tmp");
-            create_dir_all(path).await.unwrap();
-            let (mut db, db_sender) = Db::open(path, DbOptions::default()).await.unwrap();
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            let id = db.id;
+
+            db.set(b"foo", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.close().await.unwrap();
 
-            let _ = join!(db.run(), async {
-                db_sender.send(DbCmd::Set {
-                    key: b"foo".to_vec(),
-                    value: b"bar".to_vec(),
-                })
-            });
             let identity_path = path.join("IDENTITY");
             assert!(identity_path.exists());
-            assert_eq!(
-                read_to_string(identity_path).await.unwrap(),
-                db.id.to_string()
-            );
+            assert_eq!(read_to_string(identity_path).await.unwrap(), id.to_string());
 
             let current_path = path.join("CURRENT");
             assert!(current_path.exists());
@@ -216,4 +833,1613 @@ mod tests {
         .instrument(span)
         .await;
     }
+
+    #[tokio::test]
+    async fn replay_wal() {
+        init_tracer();
+        let span = info_span!("replay_wal");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            db.set(b"foo", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.batch(
+                vec![
+                    DbCmd::Set {
+                        key: b"baz".to_vec(),
+                        value: b"qux".to_vec(),
+                    },
+                    DbCmd::Set {
+                        key: b"deleted".to_vec(),
+                        value: b"value".to_vec(),
+                    },
+                ],
+                &WriteOptions::default(),
+            )
+            .await
+            .unwrap();
+            db.delete(b"deleted", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.close().await.unwrap();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            assert_eq!(*db.seq_num.lock().await, 4);
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar".to_vec())
+            );
+            assert_eq!(
+                db.get(b"baz", &ReadOptions::default()).await.unwrap(),
+                Some(b"qux".to_vec())
+            );
+            assert_eq!(
+                db.get(b"deleted", &ReadOptions::default()).await.unwrap(),
+                None
+            );
+
+            db.set(b"foo", b"bar2", &WriteOptions { sync: true })
+                .await
+                .unwrap();
+            db.close().await.unwrap();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            assert_eq!(*db.seq_num.lock().await, 5);
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar2".to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn manifest_torn_tail() {
+        init_tracer();
+        let span = info_span!("manifest_torn_tail");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let write = WriteOptions::default();
+            let read = ReadOptions::default();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            db.set(b"foo", b"bar", &write).await.unwrap();
+            db.flush().await.unwrap();
+            db.close().await.unwrap();
+
+            // The first byte of an entry cut short by a crash
+            let mut file = OpenOptions::new()
+                .append(true)
+                .open(path.join("MANIFEST-0"))
+                .await
+                .unwrap();
+            file.write_all(&[0x87]).await.unwrap();
+            file.sync_all().await.unwrap();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            assert_eq!(db.get(b"foo", &read).await.unwrap(), Some(b"bar".to_vec()));
+            db.set(b"baz", b"qux", &write).await.unwrap();
+            db.flush().await.unwrap();
+            db.close().await.unwrap();
+
+            // Entries appended after the torn one are read back
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            assert_eq!(db.get(b"foo", &read).await.unwrap(), Some(b"bar".to_vec()));
+            assert_eq!(db.get(b"baz", &read).await.unwrap(), Some(b"qux".to_vec()));
+            assert_eq!(default_family(&db).levels.files(0).len(), 2);
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes() {
+        init_tracer();
+        let span = info_span!("concurrent_writes");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let db = Arc::new(Db::open(path, DbOptions::default()).await.unwrap());
+
+            let writers: Vec<_> = (0..10)
+                .map(|writer| {
+                    let db = db.clone();
+                    tokio::spawn(async move {
+                        for i in 0..100 {
+                            let key = format!("foo{}-{}", writer, i);
+                            db.set(key.as_bytes(), b"bar", &WriteOptions::default())
+                                .await
+                                .unwrap();
+                            // Acknowledged writes are visible right away
+                            assert_eq!(
+                                db.get(key.as_bytes(), &ReadOptions::default())
+                                    .await
+                                    .unwrap(),
+                                Some(b"bar".to_vec())
+                            );
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.await.unwrap();
+            }
+            assert_eq!(*db.seq_num.lock().await, 1000);
+
+            let db = Arc::into_inner(db).unwrap();
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn write_after_wal_stopped() {
+        init_tracer();
+        let span = info_span!("write_after_wal_stopped");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+
+            db.wal_handle.abort();
+            let err = db
+                .set(b"foo", b"bar", &WriteOptions::default())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn flush_memtable() {
+        init_tracer();
+        let span = info_span!("flush_memtable");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            db.set(b"foo", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.set(b"baz", b"qux", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            assert!(default_family(&db).memtable.is_empty());
+            assert!(default_family(&db).immutables.is_empty());
+            assert!(path.join("SST-0").exists());
+            assert!(!path.join("WAL-0").exists());
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar".to_vec())
+            );
+
+            // Newer writes shadow flushed ones
+            db.set(b"foo", b"bar2", &WriteOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar2".to_vec())
+            );
+            db.close().await.unwrap();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            assert_eq!(*db.seq_num.lock().await, 3);
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar2".to_vec())
+            );
+            assert_eq!(
+                db.get(b"baz", &ReadOptions::default()).await.unwrap(),
+                Some(b"qux".to_vec())
+            );
+
+            // Nothing but the sst file is left once everything is flushed
+            db.flush().await.unwrap();
+            db.close().await.unwrap();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            assert_eq!(*db.seq_num.lock().await, 3);
+            assert!(default_family(&db).memtable.is_empty());
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar2".to_vec())
+            );
+            assert_eq!(
+                db.get(b"baz", &ReadOptions::default()).await.unwrap(),
+                Some(b"qux".to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn flush_full_memtable() {
+        init_tracer();
+        let span = info_span!("flush_full_memtable");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                write_buffer_size: 4096,
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for i in 0..100 {
+                let key = format!("foo{:0>2}", i);
+                db.set(key.as_bytes(), &[b'a'; 100], &WriteOptions::default())
+                    .await
+                    .unwrap();
+            }
+            // Waits for the memtables switched on their own
+            db.flush().await.unwrap();
+            assert!(path.join("SST-1").exists());
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options).await.unwrap();
+            assert_eq!(*db.seq_num.lock().await, 100);
+            for i in 0..100 {
+                let key = format!("foo{:0>2}", i);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    Some(vec![b'a'; 100])
+                );
+            }
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn delete_shadows_flushed_value() {
+        init_tracer();
+        let span = info_span!("delete_shadows_flushed_value");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            db.set(b"foo", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            db.delete(b"foo", &WriteOptions::default()).await.unwrap();
+            db.flush().await.unwrap();
+            assert_eq!(db.get(b"foo", &ReadOptions::default()).await.unwrap(), None);
+            db.close().await.unwrap();
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            assert_eq!(db.get(b"foo", &ReadOptions::default()).await.unwrap(), None);
+            db.set(b"foo", b"bar2", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar2".to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn compact_levels() {
+        init_tracer();
+        let span = info_span!("compact_levels");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                level0_file_num_compaction_trigger: 2,
+                max_bytes_for_level_base: 2048,
+                max_bytes_for_level_multiplier: 2.0,
+                target_file_size_base: 1024,
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for round in 0..10 {
+                for i in 0..50 {
+                    let key = format!("foo{:0>2}", i);
+                    let value = format!("bar{}", round).repeat(20);
+                    db.set(key.as_bytes(), value.as_bytes(), &WriteOptions::default())
+                        .await
+                        .unwrap();
+                }
+                for i in (round..50).step_by(10) {
+                    let key = format!("foo{:0>2}", i);
+                    db.delete(key.as_bytes(), &WriteOptions::default())
+                        .await
+                        .unwrap();
+                }
+                db.flush().await.unwrap();
+            }
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options).await.unwrap();
+            for i in 0..50 {
+                let key = format!("foo{:0>2}", i);
+                let expected = (i % 10 != 9).then(|| "bar9".repeat(20).into_bytes());
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    expected
+                );
+            }
+
+            let levels = default_family(&db).levels.clone();
+            assert!(levels.files(0).len() < 2);
+            assert!((1..levels.num_levels()).any(|level| !levels.files(level).is_empty()));
+            let live: usize = (0..levels.num_levels())
+                .map(|level| levels.files(level).len())
+                .sum();
+            let on_disk = std::fs::read_dir(path)
+                .unwrap()
+                .filter(|entry| {
+                    let name = entry.as_ref().unwrap().file_name();
+                    name.to_string_lossy().starts_with("SST-")
+                })
+                .count();
+            assert_eq!(live, on_disk);
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn compact_universal() {
+        init_tracer();
+        let span = info_span!("compact_universal");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                compaction_style: CompactionStyle::Universal(UniversalCompactionOptions {
+                    max_size_amplification_percent: 50,
+                    ..Default::default()
+                }),
+                level0_file_num_compaction_trigger: 3,
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            let mut expected = BTreeMap::new();
+            for round in 0..10 {
+                for i in (round..50).step_by(round + 1) {
+                    let key = format!("foo{:0>2}", i);
+                    let value = format!("bar{}", round);
+                    db.set(key.as_bytes(), value.as_bytes(), &WriteOptions::default())
+                        .await
+                        .unwrap();
+                    expected.insert(key, Some(value.into_bytes()));
+                }
+                let key = format!("foo{:0>2}", round * 5);
+                db.delete(key.as_bytes(), &WriteOptions::default())
+                    .await
+                    .unwrap();
+                expected.insert(key, None);
+                db.flush().await.unwrap();
+            }
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options).await.unwrap();
+            for (key, value) in &expected {
+                assert_eq!(
+                    &db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    value
+                );
+            }
+
+            // Sorted runs are only kept in level 0 and the last level
+            let levels = default_family(&db).levels.clone();
+            let last = levels.num_levels() - 1;
+            assert!((1..last).all(|level| levels.files(level).is_empty()));
+            assert!(!levels.files(last).is_empty());
+            assert!(levels.files(0).len() + 1 < 3);
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn compact_fifo() {
+        init_tracer();
+        let span = info_span!("compact_fifo");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let fifo = FifoCompactionOptions {
+                max_table_files_size: 4096,
+                ttl: None,
+            };
+            let options = DbOptions {
+                compaction_style: CompactionStyle::Fifo(fifo),
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for round in 0..20 {
+                for i in 0..10 {
+                    let key = format!("foo{:0>2}{}", round, i);
+                    db.set(key.as_bytes(), &[b'a'; 100], &WriteOptions::default())
+                        .await
+                        .unwrap();
+                }
+                db.flush().await.unwrap();
+            }
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options).await.unwrap();
+            let levels = default_family(&db).levels.clone();
+            assert!(levels.size(0) <= fifo.max_table_files_size);
+            assert!((1..levels.num_levels()).all(|level| levels.files(level).is_empty()));
+            // Only whole files of the oldest writes are gone
+            let kept = levels.files(0).len();
+            for round in 0..20 {
+                let key = format!("foo{:0>2}0", round);
+                let value = (round >= 20 - kept).then(|| vec![b'a'; 100]);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    value
+                );
+            }
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn compact_fifo_ttl() {
+        init_tracer();
+        let span = info_span!("compact_fifo_ttl");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                compaction_style: CompactionStyle::Fifo(FifoCompactionOptions {
+                    ttl: Some(Duration::from_secs(1)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options).await.unwrap();
+            db.set(b"foo", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar".to_vec())
+            );
+
+            // Expires without any other write
+            sleep(Duration::from_millis(3500)).await;
+            assert_eq!(db.get(b"foo", &ReadOptions::default()).await.unwrap(), None);
+            assert!(!path.join("SST-0").exists());
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn compact_range() {
+        init_tracer();
+        let span = info_span!("compact_range");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                level0_file_num_compaction_trigger: 100,
+                target_file_size_base: 1024,
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for round in 0..4 {
+                for i in 0..100 {
+                    let key = format!("foo{:0>3}", i);
+                    let value = format!("bar{}", round).repeat(10);
+                    db.set(key.as_bytes(), value.as_bytes(), &WriteOptions::default())
+                        .await
+                        .unwrap();
+                }
+                db.flush().await.unwrap();
+            }
+            for i in 20..80 {
+                let key = format!("foo{:0>3}", i);
+                db.delete(key.as_bytes(), &WriteOptions::default())
+                    .await
+                    .unwrap();
+            }
+
+            let options = CompactRangeOptions {
+                max_subcompactions: 4,
+            };
+            let stats = db.compact_range(None, None, &options).await.unwrap();
+            assert_eq!(stats.input_files, 5);
+            assert_eq!(stats.input_records, 460);
+            assert_eq!(stats.output_records, 40);
+            assert!(stats.output_files > 1);
+            assert!(stats.output_bytes < stats.input_bytes);
+
+            let levels = default_family(&db).levels.clone();
+            assert!(levels.files(0).is_empty());
+            assert_eq!(levels.files(1).len(), stats.output_files);
+            for i in 0..100 {
+                let key = format!("foo{:0>3}", i);
+                let expected = (!(20..80).contains(&i)).then(|| "bar3".repeat(10).into_bytes());
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    expected
+                );
+            }
+
+            // Files outside of the range are left alone
+            let stats = db
+                .compact_range(Some(b"zzz"), None, &options)
+                .await
+                .unwrap();
+            assert_eq!(stats, CompactionStats::default());
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    // Sets 100 keys then deletes 60 of them, flushed to a single file
+    async fn set_and_delete(db: &Db, round: usize) {
+        let write = WriteOptions::default();
+        for i in 0..100 {
+            let key = format!("foo{:0>3}", i);
+            let value = format!("bar{}", round).repeat(10);
+            db.set(key.as_bytes(), value.as_bytes(), &write)
+                .await
+                .unwrap();
+        }
+        for i in 20..80 {
+            let key = format!("foo{:0>3}", i);
+            db.delete(key.as_bytes(), &write).await.unwrap();
+        }
+        db.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn compact_range_reclaims_space() {
+        init_tracer();
+        let span = info_span!("compact_range_reclaims_space");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Db::open(tmpdir.path(), DbOptions::default()).await.unwrap();
+            let options = CompactRangeOptions::default();
+            // Rewritten rather than moved down to the last level
+            set_and_delete(&db, 0).await;
+            let stats = db.compact_range(None, None, &options).await.unwrap();
+            assert_eq!(stats.input_files, 1);
+            assert_eq!(stats.output_records, 40);
+            assert!(stats.output_bytes < stats.input_bytes);
+
+            // The deletes a snapshot kept are dropped from the last level
+            // once it is released
+            let snapshot = db.snapshot();
+            set_and_delete(&db, 1).await;
+            db.compact_range(None, None, &options).await.unwrap();
+            drop(snapshot);
+            let stats = db.compact_range(None, None, &options).await.unwrap();
+            assert_eq!(stats.output_records, 40);
+            assert!(stats.output_bytes < stats.input_bytes);
+            let levels = default_family(&db).levels.clone();
+            assert!(levels.files(0).is_empty());
+            assert_eq!(
+                db.get(b"foo050", &ReadOptions::default()).await.unwrap(),
+                None
+            );
+            assert_eq!(
+                db.get(b"foo090", &ReadOptions::default()).await.unwrap(),
+                Some("bar1".repeat(10).into_bytes())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    // Removes every key with the prefix, keeping track of the levels it saw
+    #[derive(Debug)]
+    struct PrefixFilter {
+        prefix: Vec<u8>,
+        levels: std::sync::Mutex<Vec<usize>>,
+    }
+
+    impl CompactionFilter for PrefixFilter {
+        fn filter(&self, level: usize, key: &[u8], _value: &[u8]) -> CompactionDecision {
+            self.levels.lock().unwrap().push(level);
+            if key.starts_with(&self.prefix) {
+                CompactionDecision::Remove
+            } else {
+                CompactionDecision::Keep
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn compaction_filter() {
+        init_tracer();
+        let span = info_span!("compaction_filter");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let filter = Arc::new(PrefixFilter {
+                prefix: b"tmp_".to_vec(),
+                levels: Default::default(),
+            });
+            let options = DbOptions {
+                compaction_filter: Some(filter.clone()),
+                level0_file_num_compaction_trigger: 100,
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for i in 0..10 {
+                for prefix in ["tmp_", "keep_"] {
+                    let key = format!("{}{}", prefix, i);
+                    db.set(key.as_bytes(), b"bar", &WriteOptions::default())
+                        .await
+                        .unwrap();
+                }
+                db.flush().await.unwrap();
+            }
+            let stats = db
+                .compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stats.output_records, 10);
+            assert_eq!(*filter.levels.lock().unwrap(), vec![0; 20]);
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options).await.unwrap();
+            for i in 0..10 {
+                let key = format!("tmp_{}", i);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    None
+                );
+                let key = format!("keep_{}", i);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    Some(b"bar".to_vec())
+                );
+            }
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn merge_u64_add() {
+        init_tracer();
+        let span = info_span!("merge_u64_add");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                merge_operator: Some(Arc::new(U64AddOperator)),
+                ..Default::default()
+            };
+            let add = |n: u64| n.to_le_bytes();
+            let write_options = WriteOptions::default();
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            db.set(b"foo", &add(10), &write_options).await.unwrap();
+            for _ in 0..5 {
+                db.merge(b"foo", &add(1), &write_options).await.unwrap();
+            }
+            db.merge(b"bar", &add(3), &write_options).await.unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(add(15).to_vec())
+            );
+            assert_eq!(
+                db.get(b"bar", &ReadOptions::default()).await.unwrap(),
+                Some(add(3).to_vec())
+            );
+
+            // Operands on top of values and operands of the levels
+            db.flush().await.unwrap();
+            db.merge(b"foo", &add(2), &write_options).await.unwrap();
+            db.merge(b"bar", &add(4), &write_options).await.unwrap();
+            db.flush().await.unwrap();
+            db.merge(b"bar", &add(5), &write_options).await.unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(add(17).to_vec())
+            );
+            assert_eq!(
+                db.get(b"bar", &ReadOptions::default()).await.unwrap(),
+                Some(add(12).to_vec())
+            );
+
+            // A delete resets the count
+            db.delete(b"foo", &write_options).await.unwrap();
+            db.merge(b"foo", &add(1), &write_options).await.unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(add(1).to_vec())
+            );
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options).await.unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(add(1).to_vec())
+            );
+            assert_eq!(
+                db.get(b"bar", &ReadOptions::default()).await.unwrap(),
+                Some(add(12).to_vec())
+            );
+            let stats = db
+                .compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stats.output_records, 2);
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(add(1).to_vec())
+            );
+            assert_eq!(
+                db.get(b"bar", &ReadOptions::default()).await.unwrap(),
+                Some(add(12).to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn merge_malformed_operand() {
+        init_tracer();
+        let span = info_span!("merge_malformed_operand");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let options = DbOptions {
+                merge_operator: Some(Arc::new(U64AddOperator)),
+                ..Default::default()
+            };
+            let write_options = WriteOptions::default();
+            let db = Db::open(tmpdir.path(), options).await.unwrap();
+            db.set(b"foo", &10u64.to_le_bytes(), &write_options)
+                .await
+                .unwrap();
+            db.merge(b"foo", b"bad", &write_options).await.unwrap();
+
+            // Only the reads of the key fail, flushes and compactions keep
+            // the operand as it is
+            db.flush().await.unwrap();
+            db.compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            let err = db.get(b"foo", &ReadOptions::default()).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            db.set(b"bar", b"baz", &write_options).await.unwrap();
+            db.set(b"foo", &1u64.to_le_bytes(), &write_options)
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            assert_eq!(
+                db.get(b"bar", &ReadOptions::default()).await.unwrap(),
+                Some(b"baz".to_vec())
+            );
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(1u64.to_le_bytes().to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn merge_without_operator() {
+        init_tracer();
+        let span = info_span!("merge_without_operator");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Db::open(tmpdir.path(), DbOptions::default()).await.unwrap();
+            let err = db
+                .merge(b"foo", b"bar", &WriteOptions::default())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(db.get(b"foo", &ReadOptions::default()).await.unwrap(), None);
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn delete_range() {
+        init_tracer();
+        let span = info_span!("delete_range");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                level0_file_num_compaction_trigger: 100,
+                target_file_size_base: 1024,
+                ..Default::default()
+            };
+            let expected = |i: usize| match i {
+                30 => Some(b"new".to_vec()),
+                20..80 => None,
+                80.. => Some(b"bar1".to_vec()),
+                _ => Some(b"bar0".to_vec()),
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for i in 0..100 {
+                let key = format!("foo{:0>3}", i);
+                db.set(key.as_bytes(), b"bar0", &WriteOptions::default())
+                    .await
+                    .unwrap();
+            }
+            db.flush().await.unwrap();
+            for i in 50..100 {
+                let key = format!("foo{:0>3}", i);
+                db.set(key.as_bytes(), b"bar1", &WriteOptions::default())
+                    .await
+                    .unwrap();
+            }
+            db.delete_range(b"foo020", b"foo080", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.set(b"foo030", b"new", &WriteOptions::default())
+                .await
+                .unwrap();
+            let err = db
+                .delete_range(b"foo080", b"foo020", &WriteOptions::default())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            for i in 0..100 {
+                let key = format!("foo{:0>3}", i);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    expected(i)
+                );
+            }
+            db.close().await.unwrap();
+
+            // Replayed from the wal, then read back from level 0
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for i in 0..100 {
+                let key = format!("foo{:0>3}", i);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    expected(i)
+                );
+            }
+            db.flush().await.unwrap();
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for i in 0..100 {
+                let key = format!("foo{:0>3}", i);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    expected(i)
+                );
+            }
+
+            // Covered versions and the tombstone itself are gone once
+            // compacted to the last level
+            let options = CompactRangeOptions {
+                max_subcompactions: 4,
+            };
+            let stats = db.compact_range(None, None, &options).await.unwrap();
+            assert_eq!(stats.output_records, 41);
+            let levels = default_family(&db).levels.clone();
+            assert!(levels.files(0).is_empty());
+            assert!(levels
+                .files(1)
+                .iter()
+                .all(|f| f.table.range_tombstones().is_empty()));
+            for i in 0..100 {
+                let key = format!("foo{:0>3}", i);
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    expected(i)
+                );
+            }
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn delete_range_only() {
+        init_tracer();
+        let span = info_span!("delete_range_only");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                level0_file_num_compaction_trigger: 100,
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for i in 0..10 {
+                let key = format!("foo{}", i);
+                db.set(key.as_bytes(), b"bar", &WriteOptions::default())
+                    .await
+                    .unwrap();
+            }
+            db.compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            // Flushed to a file holding the tombstone alone
+            db.delete_range(b"foo2", b"foo5", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            db.close().await.unwrap();
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            let levels = default_family(&db).levels.clone();
+            assert_eq!(levels.files(0).len(), 1);
+            assert_eq!(levels.files(0)[0].meta.smallest, b"foo2");
+            assert_eq!(levels.files(0)[0].meta.largest, b"foo5");
+            for i in 0..10 {
+                let key = format!("foo{}", i);
+                let expected = (!(2..5).contains(&i)).then(|| b"bar".to_vec());
+                assert_eq!(
+                    db.get(key.as_bytes(), &ReadOptions::default())
+                        .await
+                        .unwrap(),
+                    expected
+                );
+            }
+
+            let stats = db
+                .compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stats.output_records, 7);
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn single_delete() {
+        init_tracer();
+        let span = info_span!("single_delete");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                num_levels: 3,
+                level0_file_num_compaction_trigger: 100,
+                max_bytes_for_level_base: 1,
+                ..Default::default()
+            };
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            db.set(b"a", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.set(b"z", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            // Level 1 is over its target once compacted into, the request
+            // waits for it to be moved to the last level
+            db.compact_range(Some(b"zzz"), None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(default_family(&db).levels.files(2).len(), 1);
+
+            db.set(b"m", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.set(b"n", b"bar", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            db.single_delete(b"m", &WriteOptions::default())
+                .await
+                .unwrap();
+            db.delete(b"n", &WriteOptions::default()).await.unwrap();
+            assert_eq!(db.get(b"m", &ReadOptions::default()).await.unwrap(), None);
+            db.flush().await.unwrap();
+            assert_eq!(db.get(b"m", &ReadOptions::default()).await.unwrap(), None);
+
+            // The single delete and its set vanish when compacted to level 1,
+            // the delete is carried down to the last level along with the
+            // files holding a and z.
+            let stats = db
+                .compact_range(Some(b"m"), Some(b"n"), &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stats.input_records, 4 + 3);
+            assert_eq!(stats.output_records, 1 + 2);
+            let levels = default_family(&db).levels.clone();
+            assert!(levels.files(0).is_empty());
+            assert!(levels.files(1).is_empty());
+            assert_eq!(db.get(b"m", &ReadOptions::default()).await.unwrap(), None);
+            assert_eq!(db.get(b"n", &ReadOptions::default()).await.unwrap(), None);
+            assert_eq!(
+                db.get(b"a", &ReadOptions::default()).await.unwrap(),
+                Some(b"bar".to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn read_acknowledged_writes() {
+        init_tracer();
+        let span = info_span!("read_acknowledged_writes");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Db::open(tmpdir.path(), DbOptions::default()).await.unwrap();
+            db.set(b"foo", b"v1", &WriteOptions::default())
+                .await
+                .unwrap();
+
+            // Applied to the memtable but not acknowledged yet
+            default_family(&db).memtable.insert(
+                b"foo".to_vec(),
+                1,
+                MemTableValue::Set(b"v2".to_vec()),
+            );
+            let read = ReadOptions::default();
+            assert_eq!(db.get(b"foo", &read).await.unwrap(), Some(b"v1".to_vec()));
+            let mut iter = db.iter(&read);
+            iter.seek_to_first().await.unwrap();
+            assert_eq!(iter.value(), Some(b"v1".as_slice()));
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn snapshot_reads() {
+        init_tracer();
+        let span = info_span!("snapshot_reads");
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                level0_file_num_compaction_trigger: 100,
+                merge_operator: Some(Arc::new(U64AddOperator)),
+                ..Default::default()
+            };
+            let write = WriteOptions::default();
+
+            let db = Db::open(path, options.clone()).await.unwrap();
+            let empty = db.snapshot();
+            db.set(b"foo", b"v1", &write).await.unwrap();
+            db.set(b"bar", b"v1", &write).await.unwrap();
+            db.set(b"baz", b"v1", &write).await.unwrap();
+            db.merge(b"count", &1u64.to_le_bytes(), &write)
+                .await
+                .unwrap();
+            db.flush().await.unwrap();
+            let snapshot = db.snapshot();
+            db.set(b"foo", b"v2", &write).await.unwrap();
+            db.delete(b"bar", &write).await.unwrap();
+            db.delete_range(b"baz", b"bazz", &write).await.unwrap();
+            db.merge(b"count", &2u64.to_le_bytes(), &write)
+                .await
+                .unwrap();
+
+            let at_snapshot = ReadOptions {
+                snapshot: Some(&snapshot),
+                ..Default::default()
+            };
+            let at_empty = ReadOptions {
+                snapshot: Some(&empty),
+                ..Default::default()
+            };
+            let latest = ReadOptions::default();
+            async fn check(db: &Db, at_snapshot: &ReadOptions<'_>, at_empty: &ReadOptions<'_>) {
+                let latest = &ReadOptions::default();
+                assert_eq!(db.get(b"foo", at_empty).await.unwrap(), None);
+                assert_eq!(
+                    db.get(b"foo", at_snapshot).await.unwrap(),
+                    Some(b"v1".to_vec())
+                );
+                assert_eq!(
+                    db.get(b"bar", at_snapshot).await.unwrap(),
+                    Some(b"v1".to_vec())
+                );
+                assert_eq!(
+                    db.get(b"baz", at_snapshot).await.unwrap(),
+                    Some(b"v1".to_vec())
+                );
+                assert_eq!(
+                    db.get(b"count", at_snapshot).await.unwrap(),
+                    Some(1u64.to_le_bytes().to_vec())
+                );
+                assert_eq!(db.get(b"foo", latest).await.unwrap(), Some(b"v2".to_vec()));
+                assert_eq!(db.get(b"bar", latest).await.unwrap(), None);
+                assert_eq!(db.get(b"baz", latest).await.unwrap(), None);
+                assert_eq!(
+                    db.get(b"count", latest).await.unwrap(),
+                    Some(3u64.to_le_bytes().to_vec())
+                );
+            }
+            check(&db, &at_snapshot, &at_empty).await;
+            db.flush().await.unwrap();
+            check(&db, &at_snapshot, &at_empty).await;
+
+            // Versions seen by the snapshot survive compactions
+            let stats = db
+                .compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stats.output_records, 7);
+            check(&db, &at_snapshot, &at_empty).await;
+
+            // Released once the snapshot is dropped, the level 1 file is
+            // rewritten along with a new write over it
+            drop(snapshot);
+            db.set(b"foo", b"v3", &write).await.unwrap();
+            let stats = db
+                .compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(stats.input_records, 8);
+            assert_eq!(stats.output_records, 2);
+            assert_eq!(db.get(b"foo", &latest).await.unwrap(), Some(b"v3".to_vec()));
+            assert_eq!(
+                db.get(b"count", &latest).await.unwrap(),
+                Some(3u64.to_le_bytes().to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn iterate() {
+        init_tracer();
+        let span = info_span!("iterate");
+
+        // Every visible entry forwards, checked against the same entries
+        // read backwards
+        async fn scan(db: &Db, options: &ReadOptions<'_>) -> Vec<(Vec<u8>, Vec<u8>)> {
+            let mut iter = db.iter(options);
+            let mut forward = Vec::new();
+            iter.seek_to_first().await.unwrap();
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                forward.push((key.to_vec(), value.to_vec()));
+                iter.next().await.unwrap();
+            }
+            let mut backward = Vec::new();
+            iter.seek_to_last().await.unwrap();
+            while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+                backward.push((key.to_vec(), value.to_vec()));
+                iter.prev().await.unwrap();
+            }
+            backward.reverse();
+            assert_eq!(forward, backward);
+            forward
+        }
+
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let options = DbOptions {
+                level0_file_num_compaction_trigger: 100,
+                target_file_size_base: 1024,
+                merge_operator: Some(Arc::new(U64AddOperator)),
+                ..Default::default()
+            };
+            let write = WriteOptions::default();
+            let key = |i: usize| format!("key{:0>3}", i).into_bytes();
+            let mut model = BTreeMap::new();
+
+            // Level 1, split over several files
+            let db = Db::open(path, options.clone()).await.unwrap();
+            for i in 0..100 {
+                db.set(&key(i), b"v0", &write).await.unwrap();
+                model.insert(key(i), b"v0".to_vec());
+            }
+            db.merge(b"count", &1u64.to_le_bytes(), &write)
+                .await
+                .unwrap();
+            model.insert(b"count".to_vec(), 1u64.to_le_bytes().to_vec());
+            db.compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+
+            // Level 0
+            for i in 0..100 {
+                if i % 10 == 1 {
+                    db.delete(&key(i), &write).await.unwrap();
+                    model.remove(&key(i));
+                } else if i % 10 == 2 {
+                    db.set(&key(i), b"v1", &write).await.unwrap();
+                    model.insert(key(i), b"v1".to_vec());
+                }
+            }
+            db.delete_range(&key(50), &key(60), &write).await.unwrap();
+            for i in 50..60 {
+                model.remove(&key(i));
+            }
+            db.flush().await.unwrap();
+            let snapshot = db.snapshot();
+            let at_snapshot = model.clone();
+
+            // Memtable
+            db.set(&key(55), b"v2", &write).await.unwrap();
+            db.delete(&key(3), &write).await.unwrap();
+            db.set(&key(100), b"v2", &write).await.unwrap();
+            db.merge(b"count", &2u64.to_le_bytes(), &write)
+                .await
+                .unwrap();
+            model.insert(key(55), b"v2".to_vec());
+            model.remove(&key(3));
+            model.insert(key(100), b"v2".to_vec());
+            model.insert(b"count".to_vec(), 3u64.to_le_bytes().to_vec());
+
+            let entries = |model: &BTreeMap<Vec<u8>, Vec<u8>>| -> Vec<(Vec<u8>, Vec<u8>)> {
+                model.clone().into_iter().collect()
+            };
+            assert_eq!(scan(&db, &ReadOptions::default()).await, entries(&model));
+            let options = ReadOptions {
+                snapshot: Some(&snapshot),
+                ..Default::default()
+            };
+            assert_eq!(scan(&db, &options).await, entries(&at_snapshot));
+
+            // Bounds
+            let (lower, upper) = (key(48), key(62));
+            let options = ReadOptions {
+                lower_bound: Some(&lower),
+                upper_bound: Some(&upper),
+                ..Default::default()
+            };
+            let bounded: BTreeMap<Vec<u8>, Vec<u8>> = model
+                .range(lower.clone()..upper.clone())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            assert_eq!(scan(&db, &options).await, entries(&bounded));
+
+            // Seeks land on the next visible key and move both ways from it
+            let mut iter = db.iter(&ReadOptions::default());
+            assert!(!iter.valid());
+            iter.seek(&key(50)).await.unwrap();
+            assert_eq!(iter.key(), Some(key(55).as_slice()));
+            assert_eq!(iter.value(), Some(&b"v2"[..]));
+            iter.prev().await.unwrap();
+            assert_eq!(iter.key(), Some(key(49).as_slice()));
+            iter.next().await.unwrap();
+            iter.next().await.unwrap();
+            assert_eq!(iter.key(), Some(key(60).as_slice()));
+            iter.seek(&key(1)).await.unwrap();
+            assert_eq!(iter.key(), Some(key(2).as_slice()));
+            iter.prev().await.unwrap();
+            assert_eq!(iter.key(), Some(key(0).as_slice()));
+            iter.prev().await.unwrap();
+            assert_eq!(iter.key(), Some(&b"count"[..]));
+            iter.prev().await.unwrap();
+            assert!(!iter.valid());
+            iter.seek(b"zzz").await.unwrap();
+            assert!(!iter.valid());
+
+            let mut iter = db.iter(&options);
+            iter.seek(b"a").await.unwrap();
+            assert_eq!(iter.key(), Some(key(48).as_slice()));
+            iter.seek(&key(61)).await.unwrap();
+            assert!(!iter.valid());
+
+            drop(snapshot);
+            db.close().await.unwrap();
+
+            // Nothing written yet
+            let tmpdir = tempdir().unwrap();
+            let db = Db::open(tmpdir.path(), DbOptions::default()).await.unwrap();
+            assert!(scan(&db, &ReadOptions::default()).await.is_empty());
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn column_families() {
+        init_tracer();
+        let span = info_span!("column_families");
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let path = tmpdir.path();
+            let write = WriteOptions::default();
+            let read = ReadOptions::default();
+            let counters = DbOptions {
+                merge_operator: Some(Arc::new(U64AddOperator)),
+                ..Default::default()
+            };
+
+            let db = Db::open(path, DbOptions::default()).await.unwrap();
+            let users = db
+                .create_column_family("users", DbOptions::default())
+                .await
+                .unwrap();
+            let stats = db
+                .create_column_family("stats", counters.clone())
+                .await
+                .unwrap();
+            let err = db
+                .create_column_family("users", DbOptions::default())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+            assert_eq!(db.column_family("users"), Some(users.clone()));
+            assert_eq!(db.column_family_names(), vec!["default", "users", "stats"]);
+
+            // The same key in each column family
+            let default = db.default_column_family();
+            db.batch_cf(
+                vec![
+                    (
+                        &default,
+                        DbCmd::Set {
+                            key: b"foo".to_vec(),
+                            value: b"default".to_vec(),
+                        },
+                    ),
+                    (
+                        &users,
+                        DbCmd::Set {
+                            key: b"foo".to_vec(),
+                            value: b"users".to_vec(),
+                        },
+                    ),
+                    (
+                        &stats,
+                        DbCmd::Merge {
+                            key: b"foo".to_vec(),
+                            operand: 2u64.to_le_bytes().to_vec(),
+                        },
+                    ),
+                ],
+                &write,
+            )
+            .await
+            .unwrap();
+            // Only the stats column family has a merge operator, the batch
+            // is rejected as a whole
+            let err = db
+                .batch_cf(
+                    vec![
+                        (
+                            &users,
+                            DbCmd::Set {
+                                key: b"bar".to_vec(),
+                                value: b"users".to_vec(),
+                            },
+                        ),
+                        (
+                            &users,
+                            DbCmd::Merge {
+                                key: b"foo".to_vec(),
+                                operand: 2u64.to_le_bytes().to_vec(),
+                            },
+                        ),
+                    ],
+                    &write,
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(db.get_cf(&users, b"bar", &read).await.unwrap(), None);
+
+            assert_eq!(
+                db.get(b"foo", &read).await.unwrap(),
+                Some(b"default".to_vec())
+            );
+            assert_eq!(
+                db.get_cf(&users, b"foo", &read).await.unwrap(),
+                Some(b"users".to_vec())
+            );
+            assert_eq!(
+                db.get_cf(&stats, b"foo", &read).await.unwrap(),
+                Some(2u64.to_le_bytes().to_vec())
+            );
+
+            // Flushed together, then written to again
+            db.flush().await.unwrap();
+            assert!(default_family(&db).memtable.is_empty());
+            assert_eq!(default_family(&db).levels.files(0).len(), 1);
+            db.set_cf(&users, b"baz", b"users", &write).await.unwrap();
+            db.delete_cf(&users, b"foo", &write).await.unwrap();
+            db.compact_range_cf(&users, None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(
+                db.get(b"foo", &read).await.unwrap(),
+                Some(b"default".to_vec())
+            );
+            assert_eq!(db.get_cf(&users, b"foo", &read).await.unwrap(), None);
+
+            let mut iter = db.iter_cf(&users, &read).unwrap();
+            iter.seek_to_first().await.unwrap();
+            assert_eq!(iter.key(), Some(&b"baz"[..]));
+            iter.next().await.unwrap();
+            assert!(!iter.valid());
+
+            // Left in the wal
+            db.batch_cf(
+                vec![(
+                    &stats,
+                    DbCmd::Merge {
+                        key: b"foo".to_vec(),
+                        operand: 3u64.to_le_bytes().to_vec(),
+                    },
+                )],
+                &write,
+            )
+            .await
+            .unwrap();
+            db.close().await.unwrap();
+
+            // Unknown column families cannot be opened
+            let err = Db::open_with_column_families(
+                path,
+                DbOptions::default(),
+                vec![("unknown".to_string(), DbOptions::default())],
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+
+            let db = Db::open_with_column_families(
+                path,
+                DbOptions::default(),
+                vec![("stats".to_string(), counters.clone())],
+            )
+            .await
+            .unwrap();
+            let users = db.column_family("users").unwrap();
+            let stats = db.column_family("stats").unwrap();
+            assert_eq!(
+                db.get_cf(&users, b"baz", &read).await.unwrap(),
+                Some(b"users".to_vec())
+            );
+            assert_eq!(
+                db.get_cf(&stats, b"foo", &read).await.unwrap(),
+                Some(5u64.to_le_bytes().to_vec())
+            );
+
+            // Dropped along with its files
+            let files: Vec<u64> = {
+                let state = db.state.read().unwrap();
+                let levels = &state.column_families[&users.id()].levels;
+                (0..levels.num_levels())
+                    .flat_map(|level| levels.files(level))
+                    .map(|file| file.meta.file_number)
+                    .collect()
+            };
+            assert!(!files.is_empty());
+            db.drop_column_family(&users).await.unwrap();
+            for file_number in files {
+                assert!(!path.join(format!("SST-{}", file_number)).exists());
+            }
+            let err = db.get_cf(&users, b"baz", &read).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            let err = db
+                .set_cf(&users, b"baz", b"users", &write)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            let err = db.drop_column_family(&users).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            let err = db.drop_column_family(&default).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+            // Ids are not reused
+            let users = db
+                .create_column_family("users", DbOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(users.id(), 3);
+            assert_eq!(db.get_cf(&users, b"baz", &read).await.unwrap(), None);
+            db.close().await.unwrap();
+
+            let db = Db::open_with_column_families(
+                path,
+                DbOptions::default(),
+                vec![("stats".to_string(), counters)],
+            )
+            .await
+            .unwrap();
+            assert_eq!(db.column_family_names(), vec!["default", "stats", "users"]);
+            assert_eq!(db.column_family("users").unwrap().id(), 3);
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
 }
@@ -0,0 +1,239 @@
+use std::io::{Error, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use tokio::fs::metadata;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
+use tracing::info;
+
+use crate::compaction::compactor::CompactionRequest;
+use crate::db::merge_operator::collapse_all_merges;
+use crate::db::options::DbOptions;
+use crate::db::state::DbState;
+use crate::key::range_tombstone::FragmentedRangeTombstones;
+use crate::manifest::entry::ManifestLogEntry;
+use crate::manifest::manifest::{append_entries, ManifestRequest};
+use crate::memtable::memtable::MemTable;
+use crate::sst::table::table::SstTable;
+use crate::sst::table::writer::SstTableWriter;
+use crate::utils::fs::sync_dir;
+use crate::utils::time::unix_time;
+use crate::version::version::FileMetaData;
+use crate::wal::manager::delete_obsolete_wals;
+
+pub struct FlushRequest {
+    // The memtables of the column families switched together, none when
+    // there was nothing to flush. The request then only waits for the
+    // flushes queued before it.
+    memtables: Vec<(u32, Arc<MemTable>)>,
+    // First log holding writes that are not in the memtables
+    log_number: u32,
+    completion: Option<oneshot::Sender<Result<()>>>,
+}
+
+impl FlushRequest {
+    pub fn new(
+        memtables: Vec<(u32, Arc<MemTable>)>,
+        log_number: u32,
+        completion: Option<oneshot::Sender<Result<()>>>,
+    ) -> Self {
+        Self {
+            memtables,
+            log_number,
+            completion,
+        }
+    }
+}
+
+// Writes immutable memtables to level 0, in the order they were switched
+pub struct Flusher {
+    path: PathBuf,
+    state: Arc<RwLock<DbState>>,
+    manifest_sender: Sender<ManifestRequest>,
+    receiver: Receiver<FlushRequest>,
+    compaction_sender: Sender<CompactionRequest>,
+    // Logs before this one only hold flushed writes
+    log_number: u32,
+}
+
+impl Flusher {
+    pub fn new(
+        path: PathBuf,
+        state: Arc<RwLock<DbState>>,
+        manifest_sender: Sender<ManifestRequest>,
+        receiver: Receiver<FlushRequest>,
+        compaction_sender: Sender<CompactionRequest>,
+        log_number: u32,
+    ) -> Self {
+        Self {
+            path,
+            state,
+            manifest_sender,
+            receiver,
+            compaction_sender,
+            log_number,
+        }
+    }
+
+    // Writes a memtable to a new sst file, if it holds anything
+    async fn write_table(
+        &self,
+        options: &DbOptions,
+        memtable: &MemTable,
+    ) -> Result<Option<(Arc<FileMetaData>, SstTable)>> {
+        // Every version is kept, deletes included so that they keep shadowing
+        // older values of the lower levels. Merge operands are folded into
+        // the values they apply to when those are in the memtable too, and
+        // seen by the same snapshots.
+        let mut memtable_entries = memtable.entries();
+        if let Some(operator) = &options.merge_operator {
+            let snapshots = self.state.read().unwrap().snapshots.seq_nums();
+            memtable_entries = collapse_all_merges(operator.as_ref(), memtable_entries, &snapshots);
+        }
+        let range_tombstones = memtable.range_tombstones();
+        let seq_nums = memtable_entries
+            .iter()
+            .map(|(k, _)| k.seq_num)
+            .chain(range_tombstones.iter().map(|t| t.seq_num));
+        let smallest_seqno = seq_nums.clone().min();
+        let largest_seqno = seq_nums.max();
+        // Range tombstones widen the key range of the file
+        let fragmented = FragmentedRangeTombstones::new(&range_tombstones);
+        let smallest = memtable_entries
+            .first()
+            .map(|(k, _)| k.user_key.as_slice())
+            .into_iter()
+            .chain(fragmented.bounds().map(|(start, _)| start))
+            .min();
+        let largest = memtable_entries
+            .last()
+            .map(|(k, _)| k.user_key.as_slice())
+            .into_iter()
+            .chain(fragmented.bounds().map(|(_, end)| end))
+            .max();
+        let (Some(smallest), Some(largest), Some(smallest_seqno), Some(largest_seqno)) =
+            (smallest, largest, smallest_seqno, largest_seqno)
+        else {
+            return Ok(None);
+        };
+
+        let file_number = self.state.write().unwrap().new_file_number();
+        let file_path = self.path.join(format!("SST-{}", file_number));
+        let mut writer =
+            SstTableWriter::new(&file_path, memtable_entries.len(), options.clone()).await?;
+        for (key, value) in &memtable_entries {
+            writer.add(&key.encode(), value).await?;
+        }
+        for tombstone in range_tombstones.iter() {
+            writer.add_range_tombstone(tombstone.clone());
+        }
+        let table = writer.finish().await?;
+        info!(
+            "Flushed {} entries and {} range tombstones to SST-{}",
+            memtable_entries.len(),
+            range_tombstones.len(),
+            file_number
+        );
+
+        let meta = Arc::new(FileMetaData {
+            file_number,
+            file_size: metadata(&file_path).await?.len(),
+            smallest: smallest.to_vec(),
+            largest: largest.to_vec(),
+            smallest_seqno,
+            largest_seqno,
+            creation_time: Some(unix_time()?),
+        });
+        Ok(Some((meta, table)))
+    }
+
+    async fn flush(&mut self, memtables: &[(u32, Arc<MemTable>)], log_number: u32) -> Result<()> {
+        let mut tables = Vec::new();
+        for (column_family, memtable) in memtables {
+            let options = {
+                let state = self.state.read().unwrap();
+                state
+                    .column_families
+                    .get(column_family)
+                    .map(|family| family.options.clone())
+            };
+            // Dropped since the memtable was switched
+            let Some(options) = options else {
+                continue;
+            };
+            if let Some((meta, table)) = self.write_table(&options, memtable).await? {
+                tables.push((*column_family, meta, table));
+            }
+        }
+        if !tables.is_empty() {
+            sync_dir(&self.path).await?;
+        }
+
+        // The memtables of every column family are flushed at once so that
+        // the logs they were written to can be deleted
+        let mut entries: Vec<ManifestLogEntry> = tables
+            .iter()
+            .map(|(column_family, meta, _)| meta.new_file_entry(*column_family, 0))
+            .collect();
+        if let Some(last_sequence) = tables.iter().map(|(_, meta, _)| meta.largest_seqno).max() {
+            entries.push(ManifestLogEntry::NextFileNumber {
+                next_file_number: self.state.read().unwrap().next_file_number,
+            });
+            entries.push(ManifestLogEntry::LastSequence { last_sequence });
+        }
+        entries.push(ManifestLogEntry::LogNumber {
+            log_number: log_number as u64,
+        });
+        append_entries(&self.manifest_sender, entries).await?;
+
+        {
+            let mut state = self.state.write().unwrap();
+            for (column_family, meta, table) in tables {
+                match state.column_families.get_mut(&column_family) {
+                    Some(family) => Arc::make_mut(&mut family.levels).add(0, meta, Arc::new(table)),
+                    None => table.mark_obsolete(),
+                }
+            }
+            for (column_family, memtable) in memtables {
+                if let Some(family) = state.column_families.get_mut(column_family) {
+                    family
+                        .immutables
+                        .retain(|immutable| !Arc::ptr_eq(immutable, memtable));
+                }
+            }
+        }
+
+        let obsolete: Vec<u32> = (self.log_number..log_number).collect();
+        if !obsolete.is_empty() {
+            delete_obsolete_wals(&self.path, &self.manifest_sender, &obsolete).await?;
+        }
+        self.log_number = log_number;
+        Ok(())
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        while let Some(mut request) = self.receiver.recv().await {
+            let memtables = std::mem::take(&mut request.memtables);
+            let result = if memtables.is_empty() {
+                Ok(())
+            } else {
+                self.flush(&memtables, request.log_number).await
+            };
+            // The memtable stays readable after a failed flush, but nothing
+            // flushed after it could be recovered in order.
+            if let Err(err) = result {
+                if let Some(completion) = request.completion.take() {
+                    let _ = completion.send(Err(Error::new(err.kind(), err.to_string())));
+                }
+                return Err(err);
+            }
+            // A full channel already holds a pending request
+            let _ = self.compaction_sender.try_send(CompactionRequest::Schedule);
+            if let Some(completion) = request.completion.take() {
+                let _ = completion.send(Ok(()));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,507 @@
+use std::cmp::Ordering;
+use std::io::Result;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::db::merge_operator::merge_operands;
+use crate::db::options::MergeOperator;
+use crate::key::internal_key::{
+    compare, lookup_key, user_key, InternalKey, ValueType, MAX_SEQ_NUM,
+};
+use crate::key::range_tombstone::FragmentedRangeTombstones;
+use crate::levels::levels::LevelFile;
+use crate::memtable::memtable::MemTable;
+use crate::sst::table::cursor::SstTableCursor;
+
+pub struct MemTableCursor {
+    memtable: Arc<MemTable>,
+    current: Option<(InternalKey, Vec<u8>, Vec<u8>)>,
+}
+
+impl MemTableCursor {
+    pub fn new(memtable: Arc<MemTable>) -> Self {
+        Self {
+            memtable,
+            current: None,
+        }
+    }
+
+    fn current(&self) -> Option<(&[u8], &[u8])> {
+        self.current
+            .as_ref()
+            .map(|(_, key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    fn position(&mut self, from: Bound<&InternalKey>) {
+        self.current = self
+            .memtable
+            .seek(from)
+            .map(|(key, value)| (key.clone(), key.encode(), value));
+    }
+
+    fn seek(&mut self, target: Option<&[u8]>) -> Result<()> {
+        match target {
+            Some(target) => self.position(Bound::Included(&InternalKey::decode(target)?)),
+            None => self.position(Bound::Unbounded),
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) {
+        if let Some((key, _, _)) = self.current.take() {
+            self.position(Bound::Excluded(&key));
+        }
+    }
+
+    fn position_before(&mut self, to: Option<&InternalKey>) {
+        self.current = self
+            .memtable
+            .last_before(to)
+            .map(|(key, value)| (key.clone(), key.encode(), value));
+    }
+
+    fn seek_for_prev(&mut self, target: Option<&[u8]>) -> Result<()> {
+        let target = target.map(InternalKey::decode).transpose()?;
+        self.position_before(target.as_ref());
+        Ok(())
+    }
+
+    fn prev(&mut self) {
+        if let Some((key, _, _)) = self.current.take() {
+            self.position_before(Some(&key));
+        }
+    }
+}
+
+// Walks the files of a level above 0, which hold disjoint key ranges
+pub struct LevelCursor {
+    files: Vec<LevelFile>,
+    index: usize,
+    cursor: Option<SstTableCursor>,
+}
+
+impl LevelCursor {
+    pub fn new(files: Vec<LevelFile>) -> Self {
+        Self {
+            files,
+            index: 0,
+            cursor: None,
+        }
+    }
+
+    fn current(&self) -> Option<(&[u8], &[u8])> {
+        self.cursor.as_ref().and_then(|cursor| cursor.current())
+    }
+
+    // Moves on to the next files while past the end of the current one
+    async fn skip_exhausted(&mut self) -> Result<()> {
+        while self.current().is_none() && self.index + 1 < self.files.len() {
+            self.index += 1;
+            let mut cursor = SstTableCursor::new(self.files[self.index].table.clone());
+            cursor.seek(None).await?;
+            self.cursor = Some(cursor);
+        }
+        Ok(())
+    }
+
+    async fn seek(&mut self, target: Option<&[u8]>) -> Result<()> {
+        self.index = match target {
+            Some(target) => {
+                let key = user_key(target);
+                self.files
+                    .partition_point(|f| f.meta.largest.as_slice() < key)
+            }
+            None => 0,
+        };
+        self.cursor = None;
+        if let Some(file) = self.files.get(self.index) {
+            let mut cursor = SstTableCursor::new(file.table.clone());
+            cursor.seek(target).await?;
+            self.cursor = Some(cursor);
+            self.skip_exhausted().await?;
+        }
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Result<()> {
+        if let Some(cursor) = &mut self.cursor {
+            cursor.next().await?;
+            self.skip_exhausted().await?;
+        }
+        Ok(())
+    }
+
+    // Moves back to the previous files while before the start of the current
+    // one, a file may only hold range tombstones
+    async fn skip_exhausted_back(&mut self) -> Result<()> {
+        while self.current().is_none() && self.index > 0 {
+            self.index -= 1;
+            let mut cursor = SstTableCursor::new(self.files[self.index].table.clone());
+            cursor.seek_for_prev(None).await?;
+            self.cursor = Some(cursor);
+        }
+        Ok(())
+    }
+
+    async fn seek_for_prev(&mut self, target: Option<&[u8]>) -> Result<()> {
+        let count = match target {
+            Some(target) => {
+                let key = user_key(target);
+                self.files
+                    .partition_point(|f| f.meta.smallest.as_slice() <= key)
+            }
+            None => self.files.len(),
+        };
+        self.index = count.saturating_sub(1);
+        self.cursor = None;
+        if count > 0 {
+            let mut cursor = SstTableCursor::new(self.files[self.index].table.clone());
+            cursor.seek_for_prev(target).await?;
+            self.cursor = Some(cursor);
+            self.skip_exhausted_back().await?;
+        }
+        Ok(())
+    }
+
+    async fn prev(&mut self) -> Result<()> {
+        if let Some(cursor) = &mut self.cursor {
+            cursor.prev().await?;
+            self.skip_exhausted_back().await?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Cursor {
+    MemTable(MemTableCursor),
+    Table(SstTableCursor),
+    Level(LevelCursor),
+}
+
+impl Cursor {
+    // Encoded internal key and value of the current entry
+    fn current(&self) -> Option<(&[u8], &[u8])> {
+        match self {
+            Cursor::MemTable(cursor) => cursor.current(),
+            Cursor::Table(cursor) => cursor.current(),
+            Cursor::Level(cursor) => cursor.current(),
+        }
+    }
+
+    async fn seek(&mut self, target: Option<&[u8]>) -> Result<()> {
+        match self {
+            Cursor::MemTable(cursor) => cursor.seek(target),
+            Cursor::Table(cursor) => cursor.seek(target).await,
+            Cursor::Level(cursor) => cursor.seek(target).await,
+        }
+    }
+
+    async fn next(&mut self) -> Result<()> {
+        match self {
+            Cursor::MemTable(cursor) => {
+                cursor.next();
+                Ok(())
+            }
+            Cursor::Table(cursor) => cursor.next().await,
+            Cursor::Level(cursor) => cursor.next().await,
+        }
+    }
+
+    async fn seek_for_prev(&mut self, target: Option<&[u8]>) -> Result<()> {
+        match self {
+            Cursor::MemTable(cursor) => cursor.seek_for_prev(target),
+            Cursor::Table(cursor) => cursor.seek_for_prev(target).await,
+            Cursor::Level(cursor) => cursor.seek_for_prev(target).await,
+        }
+    }
+
+    async fn prev(&mut self) -> Result<()> {
+        match self {
+            Cursor::MemTable(cursor) => {
+                cursor.prev();
+                Ok(())
+            }
+            Cursor::Table(cursor) => cursor.prev().await,
+            Cursor::Level(cursor) => cursor.prev().await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+// Every version of every key of the cursors, in internal key order. Seeking
+// sets the direction the cursors move in, next only goes forward and prev
+// only backward.
+struct MergingCursor {
+    cursors: Vec<Cursor>,
+    direction: Direction,
+}
+
+impl MergingCursor {
+    // Cursor on the smallest entry going forward, on the largest backward
+    fn closest(&self) -> Option<usize> {
+        let wanted = match self.direction {
+            Direction::Forward => Ordering::Less,
+            Direction::Backward => Ordering::Greater,
+        };
+        let mut closest: Option<(usize, &[u8])> = None;
+        for (i, cursor) in self.cursors.iter().enumerate() {
+            if let Some((key, _)) = cursor.current() {
+                match closest {
+                    Some((_, found)) if compare(key, found) != wanted => {}
+                    _ => closest = Some((i, key)),
+                }
+            }
+        }
+        closest.map(|(i, _)| i)
+    }
+
+    fn current(&self) -> Option<(&[u8], &[u8])> {
+        self.closest().and_then(|i| self.cursors[i].current())
+    }
+
+    async fn seek(&mut self, target: Option<&[u8]>) -> Result<()> {
+        self.direction = Direction::Forward;
+        for cursor in &mut self.cursors {
+            cursor.seek(target).await?;
+        }
+        Ok(())
+    }
+
+    // Positions on the last entry before the encoded key, or on the last
+    // entry without one
+    async fn seek_for_prev(&mut self, target: Option<&[u8]>) -> Result<()> {
+        self.direction = Direction::Backward;
+        for cursor in &mut self.cursors {
+            cursor.seek_for_prev(target).await?;
+        }
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Result<()> {
+        debug_assert_eq!(self.direction, Direction::Forward);
+        if let Some(i) = self.closest() {
+            self.cursors[i].next().await?;
+        }
+        Ok(())
+    }
+
+    async fn prev(&mut self) -> Result<()> {
+        debug_assert_eq!(self.direction, Direction::Backward);
+        if let Some(i) = self.closest() {
+            self.cursors[i].prev().await?;
+        }
+        Ok(())
+    }
+}
+
+// Latest visible value of each user key of the database as of a sequence
+// number, in key order. Deleted keys are skipped and merge operands are
+// applied to the value they were written over.
+pub struct DbIterator {
+    merged: MergingCursor,
+    range_tombstones: FragmentedRangeTombstones,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    // None when nothing was written yet
+    seq_num: Option<u64>,
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl DbIterator {
+    pub fn new(
+        cursors: Vec<Cursor>,
+        range_tombstones: FragmentedRangeTombstones,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        seq_num: Option<u64>,
+        lower_bound: Option<Vec<u8>>,
+        upper_bound: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            merged: MergingCursor {
+                cursors,
+                direction: Direction::Forward,
+            },
+            range_tombstones,
+            merge_operator,
+            seq_num,
+            lower_bound,
+            upper_bound,
+            current: None,
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(key, _)| key.as_slice())
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(_, value)| value.as_slice())
+    }
+
+    // Positions on the first key at or after the given one
+    pub async fn seek(&mut self, key: &[u8]) -> Result<()> {
+        let key = match &self.lower_bound {
+            Some(lower_bound) if key < lower_bound.as_slice() => lower_bound.clone(),
+            _ => key.to_vec(),
+        };
+        self.merged
+            .seek(Some(&lookup_key(&key, MAX_SEQ_NUM)))
+            .await?;
+        self.find_next().await
+    }
+
+    pub async fn seek_to_first(&mut self) -> Result<()> {
+        match self.lower_bound.clone() {
+            Some(lower_bound) => self.seek(&lower_bound).await,
+            None => {
+                self.merged.seek(None).await?;
+                self.find_next().await
+            }
+        }
+    }
+
+    pub async fn seek_to_last(&mut self) -> Result<()> {
+        let target = self
+            .upper_bound
+            .as_ref()
+            .map(|upper_bound| lookup_key(upper_bound, MAX_SEQ_NUM));
+        self.merged.seek_for_prev(target.as_deref()).await?;
+        self.find_prev().await
+    }
+
+    // The merged cursor is only sought again when changing direction
+    pub async fn next(&mut self) -> Result<()> {
+        let Some((key, _)) = &self.current else {
+            return Ok(());
+        };
+        if self.merged.direction == Direction::Backward {
+            let start = lookup_key(key, MAX_SEQ_NUM);
+            self.merged.seek(Some(&start)).await?;
+            self.take_versions().await?;
+        }
+        self.find_next().await
+    }
+
+    pub async fn prev(&mut self) -> Result<()> {
+        let Some((key, _)) = &self.current else {
+            return Ok(());
+        };
+        if self.merged.direction == Direction::Forward {
+            let start = lookup_key(key, MAX_SEQ_NUM);
+            self.merged.seek_for_prev(Some(&start)).await?;
+        }
+        self.find_prev().await
+    }
+
+    // Takes in the versions of the key the merged cursor is on, leaving it on
+    // the first entry of the next key
+    async fn take_versions(&mut self) -> Result<Option<(Vec<u8>, Vec<(InternalKey, Vec<u8>)>)>> {
+        let Some((key, _)) = self.merged.current() else {
+            return Ok(None);
+        };
+        let user_key = user_key(key).to_vec();
+        let mut versions = Vec::new();
+        while let Some((key, value)) = self.merged.current() {
+            let key = InternalKey::decode(key)?;
+            if key.user_key != user_key {
+                break;
+            }
+            versions.push((key, value.to_vec()));
+            self.merged.next().await?;
+        }
+        Ok(Some((user_key, versions)))
+    }
+
+    // Same going backward, the versions are still returned newest first and
+    // the merged cursor is left on the last entry of the previous key
+    async fn take_versions_back(
+        &mut self,
+    ) -> Result<Option<(Vec<u8>, Vec<(InternalKey, Vec<u8>)>)>> {
+        let Some((key, _)) = self.merged.current() else {
+            return Ok(None);
+        };
+        let user_key = user_key(key).to_vec();
+        let mut versions = Vec::new();
+        while let Some((key, value)) = self.merged.current() {
+            let key = InternalKey::decode(key)?;
+            if key.user_key != user_key {
+                break;
+            }
+            versions.push((key, value.to_vec()));
+            self.merged.prev().await?;
+        }
+        versions.reverse();
+        Ok(Some((user_key, versions)))
+    }
+
+    // Value of the key visible at the iterator sequence number, from its
+    // versions newest first
+    fn resolve(
+        &self,
+        key: &[u8],
+        versions: Vec<(InternalKey, Vec<u8>)>,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(seq_num) = self.seq_num else {
+            return Ok(None);
+        };
+        let operator = self.merge_operator.as_ref();
+        let covering = self.range_tombstones.covering(key, seq_num);
+        let mut operands = Vec::new();
+        for (version, value) in versions {
+            if version.seq_num > seq_num {
+                continue;
+            }
+            if covering.is_some_and(|covering| version.seq_num < covering) {
+                break;
+            }
+            match version.value_type {
+                ValueType::Set => return merge_operands(operator, key, Some(value), operands),
+                ValueType::Delete | ValueType::SingleDelete => break,
+                ValueType::Merge => operands.push(value),
+            }
+        }
+        merge_operands(operator, key, None, operands)
+    }
+
+    async fn find_next(&mut self) -> Result<()> {
+        self.current = None;
+        while let Some((key, versions)) = self.take_versions().await? {
+            if let Some(upper_bound) = &self.upper_bound {
+                if key >= *upper_bound {
+                    return Ok(());
+                }
+            }
+            if let Some(value) = self.resolve(&key, versions)? {
+                self.current = Some((key, value));
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    async fn find_prev(&mut self) -> Result<()> {
+        self.current = None;
+        while let Some((key, versions)) = self.take_versions_back().await? {
+            if let Some(lower_bound) = &self.lower_bound {
+                if key < *lower_bound {
+                    return Ok(());
+                }
+            }
+            if let Some(value) = self.resolve(&key, versions)? {
+                self.current = Some((key, value));
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::{timeout_at, Instant};
+
+// Exclusive locks on keys, by column family, each held by a transaction until
+// it releases all of them at once
+#[derive(Debug, Default)]
+pub struct LockManager {
+    state: Mutex<LockState>,
+    // Wakes up the waiting transactions whenever locks are released
+    released: Notify,
+    next_transaction_id: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    holders: HashMap<(u32, Vec<u8>), u64>,
+    // Transaction holding the lock each waiting transaction waits for. A
+    // cycle is a deadlock.
+    wait_for: HashMap<u64, u64>,
+}
+
+impl LockState {
+    // Whether `from` waits for `to`, directly or through other transactions
+    fn waits_for(&self, mut from: u64, to: u64) -> bool {
+        for _ in 0..=self.wait_for.len() {
+            if from == to {
+                return true;
+            }
+            match self.wait_for.get(&from) {
+                Some(holder) => from = *holder,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_transaction_id(&self) -> u64 {
+        self.next_transaction_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Waits for the lock until the timeout. The transaction closing a cycle of
+    // waits is the one failing, with ErrorKind::Deadlock, so that the others
+    // go on once it releases its locks.
+    pub async fn lock(
+        &self,
+        transaction: u64,
+        column_family: u32,
+        key: &[u8],
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Registered before checking the lock, not to miss a release in
+            // between
+            let mut released = pin!(self.released.notified());
+            released.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                let holder = *state
+                    .holders
+                    .entry((column_family, key.to_vec()))
+                    .or_insert(transaction);
+                if holder == transaction {
+                    state.wait_for.remove(&transaction);
+                    return Ok(());
+                }
+                if state.waits_for(holder, transaction) {
+                    state.wait_for.remove(&transaction);
+                    return Err(Error::new(
+                        ErrorKind::Deadlock,
+                        "Deadlock detected, transaction aborted",
+                    ));
+                }
+                state.wait_for.insert(transaction, holder);
+            }
+            if timeout_at(deadline, released).await.is_err() {
+                self.state.lock().unwrap().wait_for.remove(&transaction);
+                return Err(Error::new(ErrorKind::TimedOut, "Lock wait timed out"));
+            }
+        }
+    }
+
+    pub fn unlock(&self, transaction: u64, keys: &BTreeSet<(u32, Vec<u8>)>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            for key in keys {
+                if state.holders.get(key) == Some(&transaction) {
+                    state.holders.remove(key);
+                }
+            }
+            state.wait_for.remove(&transaction);
+        }
+        self.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn keys(keys: &[&[u8]]) -> BTreeSet<(u32, Vec<u8>)> {
+        keys.iter().map(|key| (0, key.to_vec())).collect()
+    }
+
+    #[tokio::test]
+    async fn wait_for_release() {
+        let locks = Arc::new(LockManager::new());
+        let (first, second) = (locks.new_transaction_id(), locks.new_transaction_id());
+        let timeout = Duration::from_secs(5);
+        locks.lock(first, 0, b"foo", timeout).await.unwrap();
+        // Locks are reentrant, and distinct by column family
+        locks.lock(first, 0, b"foo", timeout).await.unwrap();
+        locks.lock(second, 1, b"foo", timeout).await.unwrap();
+
+        let err = locks
+            .lock(second, 0, b"foo", Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let waiting = {
+            let locks = locks.clone();
+            tokio::spawn(async move { locks.lock(second, 0, b"foo", timeout).await })
+        };
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        locks.unlock(first, &keys(&[b"foo"]));
+        waiting.await.unwrap().unwrap();
+        // Not released by a transaction that does not hold it anymore
+        locks.unlock(first, &keys(&[b"foo"]));
+        let err = locks
+            .lock(first, 0, b"foo", Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn detect_deadlock() {
+        let locks = Arc::new(LockManager::new());
+        let ids: Vec<u64> = (0..3).map(|_| locks.new_transaction_id()).collect();
+        let timeout = Duration::from_secs(5);
+        let names: [&[u8]; 3] = [b"a", b"b", b"c"];
+        for (id, key) in ids.iter().zip(names) {
+            locks.lock(*id, 0, key, timeout).await.unwrap();
+        }
+
+        // 0 waits for 1, which waits for 2
+        let spawn_lock = |id: u64, key: &'static [u8]| {
+            let locks = locks.clone();
+            tokio::spawn(async move { locks.lock(id, 0, key, timeout).await })
+        };
+        let first = spawn_lock(ids[0], b"b");
+        tokio::task::yield_now().await;
+        let second = spawn_lock(ids[1], b"c");
+        tokio::task::yield_now().await;
+
+        // 2 closes the cycle and is aborted, the others go on once it
+        // releases its locks
+        let err = locks.lock(ids[2], 0, b"a", timeout).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Deadlock);
+        locks.unlock(ids[2], &keys(&[b"c"]));
+        second.await.unwrap().unwrap();
+        locks.unlock(ids[1], &keys(&[b"b", b"c"]));
+        first.await.unwrap().unwrap();
+    }
+}
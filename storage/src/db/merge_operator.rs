@@ -0,0 +1,197 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem::take;
+use std::sync::Arc;
+
+use crate::db::options::MergeOperator;
+use crate::db::snapshot::split_stripes;
+use crate::key::internal_key::{InternalKey, ValueType};
+use tracing::warn;
+
+// Applies merge operands found newest first to the value they were written
+// over
+pub fn merge_operands(
+    operator: Option<&Arc<dyn MergeOperator>>,
+    key: &[u8],
+    existing: Option<Vec<u8>>,
+    mut operands: Vec<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    if operands.is_empty() {
+        return Ok(existing);
+    }
+    operands.reverse();
+    let operator = operator.ok_or_else(no_merge_operator)?;
+    Ok(Some(operator.full_merge(
+        key,
+        existing.as_deref(),
+        &operands,
+    )?))
+}
+
+pub fn no_merge_operator() -> Error {
+    Error::new(ErrorKind::InvalidInput, "No merge operator configured")
+}
+
+// Adds up little endian u64 operands, wrapping on overflow
+#[derive(Debug, Default)]
+pub struct U64AddOperator;
+
+fn decode_u64(value: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(value.try_into().ok()?))
+}
+
+impl MergeOperator for U64AddOperator {
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>> {
+        let mut sum = existing.map_or(Some(0), decode_u64);
+        for operand in operands {
+            sum = sum.zip(decode_u64(operand)).map(|(a, b)| a.wrapping_add(b));
+        }
+        match sum {
+            Some(sum) => Ok(sum.to_le_bytes().to_vec()),
+            None => Err(Error::new(ErrorKind::InvalidData, "Value is not a u64")),
+        }
+    }
+
+    fn partial_merge(&self, _key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>> {
+        let sum = decode_u64(older)?.wrapping_add(decode_u64(newer)?);
+        Some(sum.to_le_bytes().to_vec())
+    }
+}
+
+// Folds the merge operands on top of the versions of a user key, newest
+// first, into the version they apply to. Without it the operands are only
+// combined together, unless no older version can exist elsewhere. The
+// versions below the operands are returned untouched. Operands that fail to
+// merge are kept as they are, the error is left to the reads.
+pub fn collapse_merges(
+    operator: &dyn MergeOperator,
+    mut versions: Vec<(InternalKey, Vec<u8>)>,
+    bottommost: bool,
+) -> Vec<(InternalKey, Vec<u8>)> {
+    let count = versions
+        .iter()
+        .take_while(|(key, _)| key.value_type == ValueType::Merge)
+        .count();
+    if count == 0 {
+        return versions;
+    }
+    let rest = versions.split_off(count);
+    let newest = versions[0].0.clone();
+    let operands: Vec<Vec<u8>> = versions.iter().rev().map(|(_, v)| v.clone()).collect();
+
+    let existing = match rest.first() {
+        Some((key, value)) if key.value_type == ValueType::Set => Some(Some(value.as_slice())),
+        Some(_) => Some(None),
+        None if bottommost => Some(None),
+        None => None,
+    };
+    if let Some(existing) = existing {
+        return match operator.full_merge(&newest.user_key, existing, &operands) {
+            Ok(value) => {
+                let key = InternalKey::new(newest.user_key, newest.seq_num, ValueType::Set);
+                [(key, value)].into_iter().chain(rest).collect()
+            }
+            Err(err) => {
+                warn!("Merge operands of {:?} left uncollapsed: {}", newest, err);
+                versions.extend(rest);
+                versions
+            }
+        };
+    }
+
+    let mut combined = operands[0].clone();
+    for operand in &operands[1..] {
+        match operator.partial_merge(&newest.user_key, &combined, operand) {
+            Some(value) => combined = value,
+            None => {
+                versions.extend(rest);
+                return versions;
+            }
+        }
+    }
+    [(newest, combined)].into_iter().chain(rest).collect()
+}
+
+// Collapses the merge operands of every key of entries sorted in internal key
+// order, apart for each snapshot. Older versions of the keys may exist
+// elsewhere.
+pub fn collapse_all_merges(
+    operator: &dyn MergeOperator,
+    entries: Vec<(InternalKey, Vec<u8>)>,
+    snapshots: &[u64],
+) -> Vec<(InternalKey, Vec<u8>)> {
+    let mut collapsed = Vec::with_capacity(entries.len());
+    let mut versions: Vec<(InternalKey, Vec<u8>)> = Vec::new();
+    for (key, value) in entries {
+        if versions
+            .first()
+            .is_some_and(|(first, _)| first.user_key != key.user_key)
+        {
+            for stripe in split_stripes(take(&mut versions), snapshots) {
+                collapsed.extend(collapse_merges(operator, stripe, false));
+            }
+        }
+        versions.push((key, value));
+    }
+    for stripe in split_stripes(versions, snapshots) {
+        collapsed.extend(collapse_merges(operator, stripe, false));
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(seq_num: u64, value_type: ValueType, value: u64) -> (InternalKey, Vec<u8>) {
+        let key = InternalKey::new(b"foo".to_vec(), seq_num, value_type);
+        (key, value.to_le_bytes().to_vec())
+    }
+
+    #[test]
+    fn collapse_u64_add() {
+        use ValueType::{Delete, Merge, Set};
+        let operator = U64AddOperator;
+
+        // Applied to the value they were written over
+        let versions = vec![
+            version(4, Merge, 3),
+            version(3, Merge, 2),
+            version(2, Set, 10),
+        ];
+        assert_eq!(
+            collapse_merges(&operator, versions, false),
+            vec![version(4, Set, 15), version(2, Set, 10)]
+        );
+
+        // Or to nothing past a delete or at the bottom
+        let versions = vec![version(4, Merge, 3), version(2, Delete, 0)];
+        assert_eq!(
+            collapse_merges(&operator, versions, false)[0],
+            version(4, Set, 3)
+        );
+        let versions = vec![version(4, Merge, 3), version(3, Merge, 2)];
+        assert_eq!(
+            collapse_merges(&operator, versions.clone(), true),
+            vec![version(4, Set, 5)]
+        );
+
+        // Otherwise only combined together
+        assert_eq!(
+            collapse_merges(&operator, versions, false),
+            vec![version(4, Merge, 5)]
+        );
+
+        let versions = vec![version(4, Merge, 3), (version(3, Merge, 0).0, vec![1])];
+        assert_eq!(
+            collapse_merges(&operator, versions.clone(), false),
+            versions
+        );
+        // Kept as they are when they fail to merge
+        assert_eq!(collapse_merges(&operator, versions.clone(), true), versions);
+    }
+}
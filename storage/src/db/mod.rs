@@ -1,2 +1,11 @@
+pub mod column_family;
 pub mod db;
+pub mod flush;
+pub mod iterator;
+pub mod lock_manager;
+pub mod merge_operator;
 pub mod options;
+pub mod snapshot;
+pub mod state;
+pub mod transaction;
+pub mod transaction_db;
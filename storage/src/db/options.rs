@@ -1,9 +1,148 @@
+use std::fmt::Debug;
+use std::io::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::snapshot::Snapshot;
+
+/// How `Db::open` reacts to damaged records found while replaying the WAL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalRecoveryMode {
+    /// Ignore a record left incomplete at the end of a log by a crash, fail on
+    /// any other corruption.
+    TolerateCorruptedTailRecords,
+    /// Fail on any damaged record, including an incomplete last one.
+    AbsoluteConsistency,
+    /// Stop at the first damaged record and keep everything written before it.
+    PointInTimeRecovery,
+    /// Drop damaged records and keep replaying the ones that follow.
+    SkipAnyCorruptedRecords,
+}
+
+/// When the WAL is synced to disk, on top of writes asking for it explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalSyncPolicy {
+    /// Sync after every write.
+    Always,
+    /// Sync pending writes periodically.
+    Interval(Duration),
+    /// Leave it to the OS.
+    Never,
+}
+
+/// How sst files are laid out across levels and compacted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStyle {
+    /// Every level past 0 is a single sorted run, each one a fixed multiple
+    /// larger than the previous one. Lowest read and space amplification.
+    Leveled,
+    /// Sorted runs of similar sizes are merged together, rewriting data less
+    /// often at the cost of more runs to read from.
+    Universal(UniversalCompactionOptions),
+    /// Every file stays in level 0 until it is deleted, oldest first, data is
+    /// never rewritten.
+    Fifo(FifoCompactionOptions),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UniversalCompactionOptions {
+    /// How much larger, in percent, the next older run may be than the runs
+    /// already picked and still be merged with them.
+    pub size_ratio: u64,
+    /// Fewest runs merged together because of their sizes.
+    pub min_merge_width: usize,
+    /// Most runs merged together because of their sizes.
+    pub max_merge_width: usize,
+    /// Size of all the runs but the oldest, in percent of the oldest one,
+    /// past which every run is merged together.
+    pub max_size_amplification_percent: u64,
+}
+
+impl Default for UniversalCompactionOptions {
+    fn default() -> Self {
+        Self {
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: usize::MAX,
+            max_size_amplification_percent: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FifoCompactionOptions {
+    /// Total size of the sst files past which the oldest ones are deleted.
+    pub max_table_files_size: u64,
+    /// Age past which sst files are deleted. Files are checked for it every
+    /// `ttl`, and at most every second.
+    pub ttl: Option<Duration>,
+}
+
+impl Default for FifoCompactionOptions {
+    fn default() -> Self {
+        Self {
+            max_table_files_size: 1024 * 1024 * 1024,
+            ttl: None,
+        }
+    }
+}
+
+/// What a compaction does with the value it hands to a compaction filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactionDecision {
+    Keep,
+    /// Drop the key as if it was deleted.
+    Remove,
+    /// Keep the key with another value.
+    ChangeValue(Vec<u8>),
+}
+
+/// Called by compactions for the latest value of every key they rewrite,
+/// along with the level the key is compacted out of. Keys that are only moved
+/// to another level are not filtered.
+pub trait CompactionFilter: Debug + Send + Sync {
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> CompactionDecision;
+}
+
+/// Combines the operands written with `Db::merge` into values, when they are
+/// read, flushed or compacted.
+pub trait MergeOperator: Debug + Send + Sync {
+    /// Applies the operands, oldest first, to the value they were written
+    /// over, if any.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Result<Vec<u8>>;
+    /// Combines two consecutive operands into one when the value they apply
+    /// to is not known yet, or returns None if they must be kept apart.
+    fn partial_merge(&self, key: &[u8], older: &[u8], newer: &[u8]) -> Option<Vec<u8>>;
+}
+
 #[derive(Debug, Clone)]
 pub struct DbOptions {
     pub sst_block_restart_interval: usize,
     pub sst_index_restart_interval: usize,
     pub sst_block_size: usize,
     pub wal_block_size: usize,
+    pub max_wal_size: u64,
+    pub num_levels: usize,
+    pub write_buffer_size: usize,
+    pub compaction_style: CompactionStyle,
+    /// Number of level 0 files that triggers their compaction into level 1,
+    /// or number of sorted runs that triggers a universal compaction.
+    pub level0_file_num_compaction_trigger: usize,
+    /// Target size of level 1, the target of each following level is this
+    /// many times larger than the previous one.
+    pub max_bytes_for_level_base: u64,
+    pub max_bytes_for_level_multiplier: f64,
+    /// Size past which compaction outputs are split into a new file.
+    pub target_file_size_base: u64,
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    pub wal_recovery_mode: WalRecoveryMode,
+    pub wal_sync_policy: WalSyncPolicy,
 }
 
 impl Default for DbOptions {
@@ -13,6 +152,65 @@ impl Default for DbOptions {
             sst_index_restart_interval: 16,
             sst_block_size: 4 * 1024,
             wal_block_size: 32 * 1024,
+            max_wal_size: 64 * 1024 * 1024,
+            num_levels: 7,
+            write_buffer_size: 64 * 1024 * 1024,
+            compaction_style: CompactionStyle::Leveled,
+            level0_file_num_compaction_trigger: 4,
+            max_bytes_for_level_base: 256 * 1024 * 1024,
+            max_bytes_for_level_multiplier: 10.0,
+            target_file_size_base: 64 * 1024 * 1024,
+            compaction_filter: None,
+            merge_operator: None,
+            wal_recovery_mode: WalRecoveryMode::PointInTimeRecovery,
+            wal_sync_policy: WalSyncPolicy::Never,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Sync the WAL before the write is acknowledged, so that it survives an
+    /// OS crash.
+    pub sync: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions<'a> {
+    /// Read the database as it was when the snapshot was taken instead of
+    /// its latest state.
+    pub snapshot: Option<&'a Snapshot>,
+    /// Smallest key iterators return, inclusive.
+    pub lower_bound: Option<&'a [u8]>,
+    /// Key iterators stop at, exclusive.
+    pub upper_bound: Option<&'a [u8]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactRangeOptions {
+    /// Most key ranges of a level compacted in parallel.
+    pub max_subcompactions: usize,
+}
+
+impl Default for CompactRangeOptions {
+    fn default() -> Self {
+        Self {
+            max_subcompactions: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransactionOptions {
+    /// How long to wait for a key locked by another transaction before giving
+    /// up with `ErrorKind::TimedOut`.
+    pub lock_timeout: Duration,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        Self {
+            lock_timeout: Duration::from_secs(1),
         }
     }
 }
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::key::internal_key::InternalKey;
+
+// Sequence numbers of the live snapshots, with the number of snapshots
+// pinned at each of them
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    seq_nums: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Ascending
+    pub fn seq_nums(&self) -> Vec<u64> {
+        self.seq_nums.lock().unwrap().keys().copied().collect()
+    }
+
+    fn acquire(&self, seq_num: u64) {
+        *self.seq_nums.lock().unwrap().entry(seq_num).or_default() += 1;
+    }
+
+    fn release(&self, seq_num: u64) {
+        let mut seq_nums = self.seq_nums.lock().unwrap();
+        if let Some(count) = seq_nums.get_mut(&seq_num) {
+            *count -= 1;
+            if *count == 0 {
+                seq_nums.remove(&seq_num);
+            }
+        }
+    }
+}
+
+// The database as it was when the snapshot was taken. Compactions keep every
+// version it sees until it is dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    // None when nothing was written yet
+    seq_num: Option<u64>,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub fn new(list: &Arc<SnapshotList>, seq_num: Option<u64>) -> Self {
+        if let Some(seq_num) = seq_num {
+            list.acquire(seq_num);
+        }
+        Self {
+            seq_num,
+            list: list.clone(),
+        }
+    }
+
+    // Latest sequence number visible to the snapshot
+    pub fn seq_num(&self) -> Option<u64> {
+        self.seq_num
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Some(seq_num) = self.seq_num {
+            self.list.release(seq_num);
+        }
+    }
+}
+
+// Splits the versions of a key, newest first, by the oldest snapshot seeing
+// them. The newest version of a stripe is the only one of the stripe any
+// reader can see, unless it is a merge operand.
+pub fn split_stripes(
+    versions: Vec<(InternalKey, Vec<u8>)>,
+    snapshots: &[u64],
+) -> Vec<Vec<(InternalKey, Vec<u8>)>> {
+    let mut stripes: Vec<Vec<(InternalKey, Vec<u8>)>> = Vec::new();
+    let mut current = None;
+    for (key, value) in versions {
+        let stripe = snapshots.partition_point(|s| *s < key.seq_num);
+        match stripes.last_mut() {
+            Some(last) if current == Some(stripe) => last.push((key, value)),
+            _ => stripes.push(vec![(key, value)]),
+        }
+        current = Some(stripe);
+    }
+    stripes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::internal_key::ValueType;
+
+    #[test]
+    fn pin_and_release() {
+        let list = Arc::new(SnapshotList::new());
+        let first = Snapshot::new(&list, Some(5));
+        let second = Snapshot::new(&list, Some(5));
+        let third = Snapshot::new(&list, Some(2));
+        let empty = Snapshot::new(&list, None);
+        assert_eq!(list.seq_nums(), vec![2, 5]);
+
+        drop(first);
+        drop(empty);
+        assert_eq!(list.seq_nums(), vec![2, 5]);
+        drop(second);
+        assert_eq!(list.seq_nums(), vec![2]);
+        drop(third);
+        assert!(list.seq_nums().is_empty());
+    }
+
+    #[test]
+    fn split_by_snapshots() {
+        let versions: Vec<(InternalKey, Vec<u8>)> = [9, 7, 5, 4, 1]
+            .into_iter()
+            .map(|seq_num| {
+                let key = InternalKey::new(b"foo".to_vec(), seq_num, ValueType::Set);
+                (key, Vec::new())
+            })
+            .collect();
+        let seq_nums = |stripes: Vec<Vec<(InternalKey, Vec<u8>)>>| -> Vec<Vec<u64>> {
+            stripes
+                .iter()
+                .map(|stripe| stripe.iter().map(|(k, _)| k.seq_num).collect())
+                .collect()
+        };
+
+        assert_eq!(
+            seq_nums(split_stripes(versions.clone(), &[])),
+            vec![vec![9, 7, 5, 4, 1]]
+        );
+        // A snapshot sees the versions up to its own sequence number
+        assert_eq!(
+            seq_nums(split_stripes(versions, &[3, 5, 6, 8])),
+            vec![vec![9], vec![7], vec![5, 4], vec![1]]
+        );
+    }
+}
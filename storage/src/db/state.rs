@@ -0,0 +1,35 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::db::column_family::ColumnFamily;
+use crate::db::snapshot::SnapshotList;
+
+// What reads go through, newest data first: the memtable of a column family
+// receiving writes, the memtables waiting to be flushed, then the sst files.
+#[derive(Debug, Clone)]
+pub struct DbState {
+    pub column_families: BTreeMap<u32, ColumnFamily>,
+    // Shared by flushes and compactions, which both create sst files
+    pub next_file_number: u64,
+    // Sequence number of the last write applied to the memtable, None until
+    // the first one
+    pub last_sequence: Option<u64>,
+    pub snapshots: Arc<SnapshotList>,
+}
+
+impl DbState {
+    pub fn new(column_families: BTreeMap<u32, ColumnFamily>, next_file_number: u64) -> Self {
+        Self {
+            column_families,
+            next_file_number,
+            last_sequence: None,
+            snapshots: Arc::new(SnapshotList::new()),
+        }
+    }
+
+    pub fn new_file_number(&mut self) -> u64 {
+        let file_number = self.next_file_number;
+        self.next_file_number += 1;
+        file_number
+    }
+}
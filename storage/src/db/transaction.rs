@@ -0,0 +1,288 @@
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::db::column_family::ColumnFamilyHandle;
+use crate::db::db::{Db, DbCmd};
+use crate::db::options::{ReadOptions, WriteOptions};
+use crate::db::snapshot::Snapshot;
+
+// Reads from the snapshot taken when it starts and buffers its writes until
+// committed. The commit writes nothing and fails with a conflict if one of
+// the keys read was written to since. Dropping the transaction discards its
+// writes.
+pub struct OptimisticTransaction<'a> {
+    db: &'a Db,
+    snapshot: Snapshot,
+    // Keys read from the database, by column family
+    reads: BTreeSet<(u32, Vec<u8>)>,
+    writes: Vec<(ColumnFamilyHandle, DbCmd)>,
+}
+
+impl<'a> OptimisticTransaction<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self {
+            db,
+            snapshot: db.snapshot(),
+            reads: BTreeSet::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(&self.db.default_column_family(), key).await
+    }
+
+    // Sees the writes of the transaction, only the keys read from the
+    // database are checked for conflicts
+    pub async fn get_cf(
+        &mut self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = written_value(&self.writes, column_family, key) {
+            return Ok(value);
+        }
+
+        let options = ReadOptions {
+            snapshot: Some(&self.snapshot),
+            ..Default::default()
+        };
+        let value = self.db.get_cf(column_family, key, &options).await?;
+        self.reads.insert((column_family.id(), key.to_vec()));
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        let column_family = self.db.default_column_family();
+        self.set_cf(&column_family, key, value);
+    }
+
+    pub fn set_cf(&mut self, column_family: &ColumnFamilyHandle, key: &[u8], value: &[u8]) {
+        self.writes.push((
+            column_family.clone(),
+            DbCmd::Set {
+                key: key.into(),
+                value: value.into(),
+            },
+        ));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        let column_family = self.db.default_column_family();
+        self.delete_cf(&column_family, key);
+    }
+
+    pub fn delete_cf(&mut self, column_family: &ColumnFamilyHandle, key: &[u8]) {
+        self.writes
+            .push((column_family.clone(), DbCmd::Delete { key: key.into() }));
+    }
+
+    // Writes the buffered writes as a single batch. Fails with
+    // ErrorKind::ResourceBusy on a conflict, the transaction can then be
+    // retried from the start.
+    pub async fn commit(self, options: &WriteOptions) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let (column_families, cmds): (Vec<_>, Vec<_>) = self.writes.into_iter().unzip();
+        let batch = column_families.iter().zip(cmds).collect();
+        // The snapshot keeps the versions written after it until they are
+        // checked
+        self.db
+            .batch_unless_changed(batch, &self.reads, self.snapshot.seq_num(), options)
+            .await
+    }
+}
+
+// Value of the last write of a transaction to the key, Some(None) when it
+// deleted it
+pub fn written_value(
+    writes: &[(ColumnFamilyHandle, DbCmd)],
+    column_family: &ColumnFamilyHandle,
+    key: &[u8],
+) -> Option<Option<Vec<u8>>> {
+    writes.iter().rev().find_map(|(cf, cmd)| match cmd {
+        DbCmd::Set { key: k, value } if cf == column_family && k == key => {
+            Some(Some(value.clone()))
+        }
+        DbCmd::Delete { key: k } if cf == column_family && k == key => Some(None),
+        _ => None,
+    })
+}
+
+pub fn transaction_conflict() -> Error {
+    Error::new(
+        ErrorKind::ResourceBusy,
+        "Transaction conflicts with a later write",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::options::{CompactRangeOptions, DbOptions};
+    use crate::utils::tracing::init_tracer;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use tracing::{info_span, Instrument};
+
+    #[tokio::test]
+    async fn conflicting_commit() {
+        init_tracer();
+        let span = info_span!("conflicting_commit");
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Db::open(tmpdir.path(), DbOptions::default()).await.unwrap();
+            let write = WriteOptions::default();
+            let read = ReadOptions::default();
+            db.set(b"foo", b"1", &write).await.unwrap();
+
+            // Written to after being read, even from a flushed memtable
+            let mut txn = db.optimistic_transaction();
+            assert_eq!(txn.get(b"foo").await.unwrap(), Some(b"1".to_vec()));
+            txn.set(b"foo", b"2");
+            txn.set(b"bar", b"2");
+            assert_eq!(txn.get(b"foo").await.unwrap(), Some(b"2".to_vec()));
+            assert_eq!(db.get(b"bar", &read).await.unwrap(), None);
+            db.set(b"foo", b"3", &write).await.unwrap();
+            db.flush().await.unwrap();
+            let err = txn.commit(&write).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+            assert_eq!(db.get(b"foo", &read).await.unwrap(), Some(b"3".to_vec()));
+            assert_eq!(db.get(b"bar", &read).await.unwrap(), None);
+
+            // A key missing when read, then written and deleted again, even
+            // once compacted
+            let mut txn = db.optimistic_transaction();
+            assert_eq!(txn.get(b"baz").await.unwrap(), None);
+            txn.set(b"baz", b"1");
+            db.set(b"baz", b"2", &write).await.unwrap();
+            db.delete(b"baz", &write).await.unwrap();
+            db.compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            let err = txn.commit(&write).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+
+            // Writes to other keys, or to keys only written to, do not
+            // conflict
+            let mut txn = db.optimistic_transaction();
+            assert_eq!(txn.get(b"foo").await.unwrap(), Some(b"3".to_vec()));
+            txn.set(b"bar", b"4");
+            txn.delete(b"foo");
+            assert_eq!(txn.get(b"foo").await.unwrap(), None);
+            db.set(b"bar", b"5", &write).await.unwrap();
+            db.set(b"qux", b"5", &write).await.unwrap();
+            txn.commit(&write).await.unwrap();
+            assert_eq!(db.get(b"foo", &read).await.unwrap(), None);
+            assert_eq!(db.get(b"bar", &read).await.unwrap(), Some(b"4".to_vec()));
+
+            // Across column families
+            let other = db
+                .create_column_family("other", DbOptions::default())
+                .await
+                .unwrap();
+            let mut txn = db.optimistic_transaction();
+            assert_eq!(txn.get_cf(&other, b"foo").await.unwrap(), None);
+            txn.set_cf(&other, b"foo", b"6");
+            txn.set(b"foo", b"6");
+            db.set(b"foo", b"7", &write).await.unwrap();
+            txn.commit(&write).await.unwrap();
+            assert_eq!(db.get(b"foo", &read).await.unwrap(), Some(b"6".to_vec()));
+            assert_eq!(
+                db.get_cf(&other, b"foo", &read).await.unwrap(),
+                Some(b"6".to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn compact_deleted_read() {
+        init_tracer();
+        let span = info_span!("compact_deleted_read");
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Db::open(tmpdir.path(), DbOptions::default()).await.unwrap();
+            let write = WriteOptions::default();
+            db.set(b"foo", b"1", &write).await.unwrap();
+            db.flush().await.unwrap();
+            db.delete(b"foo", &write).await.unwrap();
+            db.flush().await.unwrap();
+
+            // The compaction drops the delete written before the snapshot,
+            // which is not a conflict
+            let mut txn = db.optimistic_transaction();
+            assert_eq!(txn.get(b"foo").await.unwrap(), None);
+            txn.set(b"foo", b"2");
+            db.compact_range(None, None, &CompactRangeOptions::default())
+                .await
+                .unwrap();
+            txn.commit(&write).await.unwrap();
+            assert_eq!(
+                db.get(b"foo", &ReadOptions::default()).await.unwrap(),
+                Some(b"2".to_vec())
+            );
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_increments() {
+        init_tracer();
+        let span = info_span!("concurrent_increments");
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Arc::new(Db::open(tmpdir.path(), DbOptions::default()).await.unwrap());
+            let write = WriteOptions::default();
+            db.set(b"counter", &0u64.to_le_bytes(), &write)
+                .await
+                .unwrap();
+
+            let tasks: Vec<_> = (0..8)
+                .map(|_| {
+                    let db = db.clone();
+                    tokio::spawn(async move {
+                        let mut conflicts = 0;
+                        for _ in 0..20 {
+                            loop {
+                                let mut txn = db.optimistic_transaction();
+                                let value = txn.get(b"counter").await.unwrap().unwrap();
+                                let counter = u64::from_le_bytes(value.try_into().unwrap());
+                                txn.set(b"counter", &(counter + 1).to_le_bytes());
+                                match txn.commit(&WriteOptions::default()).await {
+                                    Ok(()) => break,
+                                    Err(err) if err.kind() == ErrorKind::ResourceBusy => {
+                                        conflicts += 1
+                                    }
+                                    Err(err) => panic!("{}", err),
+                                }
+                            }
+                        }
+                        conflicts
+                    })
+                })
+                .collect();
+            let mut conflicts = 0;
+            for task in tasks {
+                conflicts += task.await.unwrap();
+            }
+            tracing::info!("Retried {} conflicting transactions", conflicts);
+
+            let value = db
+                .get(b"counter", &ReadOptions::default())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 160);
+            let db = Arc::try_unwrap(db).ok().unwrap();
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+}
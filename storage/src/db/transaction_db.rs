@@ -0,0 +1,344 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::io::Result;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::db::column_family::ColumnFamilyHandle;
+use crate::db::db::{Db, DbCmd};
+use crate::db::lock_manager::LockManager;
+use crate::db::options::{DbOptions, ReadOptions, TransactionOptions, WriteOptions};
+use crate::db::transaction::written_value;
+
+// A database whose transactions lock the keys they write, or read for update,
+// until they end
+pub struct TransactionDb {
+    db: Db,
+    locks: LockManager,
+}
+
+impl TransactionDb {
+    pub async fn open<P: Into<PathBuf> + Debug>(path: P, options: DbOptions) -> Result<Self> {
+        Ok(Self {
+            db: Db::open(path, options).await?,
+            locks: LockManager::new(),
+        })
+    }
+
+    // Writes made directly to the database do not take any lock
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn begin_transaction(&self, options: &TransactionOptions) -> Transaction<'_> {
+        Transaction {
+            db: &self.db,
+            locks: &self.locks,
+            id: self.locks.new_transaction_id(),
+            lock_timeout: options.lock_timeout,
+            locked: BTreeSet::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    pub async fn close(self) -> Result<()> {
+        self.db.close().await
+    }
+}
+
+// Buffers its writes until committed, the keys written or read for update
+// stay locked until then. Waiting for a lock fails once the transaction
+// options timeout is over, or right away with ErrorKind::Deadlock when the
+// transaction would wait for itself. The transaction should then be rolled
+// back. Dropping it rolls it back too.
+pub struct Transaction<'a> {
+    db: &'a Db,
+    locks: &'a LockManager,
+    id: u64,
+    lock_timeout: Duration,
+    // Keys locked by the transaction, by column family
+    locked: BTreeSet<(u32, Vec<u8>)>,
+    writes: Vec<(ColumnFamilyHandle, DbCmd)>,
+}
+
+impl Transaction<'_> {
+    async fn lock(&mut self, column_family: &ColumnFamilyHandle, key: &[u8]) -> Result<()> {
+        self.locks
+            .lock(self.id, column_family.id(), key, self.lock_timeout)
+            .await?;
+        self.locked.insert((column_family.id(), key.to_vec()));
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_cf(&self.db.default_column_family(), key).await
+    }
+
+    // Latest value of the key, or the one the transaction wrote. Other
+    // transactions may still change it unless it is locked.
+    pub async fn get_cf(
+        &self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = written_value(&self.writes, column_family, key) {
+            return Ok(value);
+        }
+        self.db
+            .get_cf(column_family, key, &ReadOptions::default())
+            .await
+    }
+
+    pub async fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let column_family = self.db.default_column_family();
+        self.get_for_update_cf(&column_family, key).await
+    }
+
+    // Locks the key before reading it, no other transaction can write it
+    // until this one ends
+    pub async fn get_for_update_cf(
+        &mut self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        self.lock(column_family, key).await?;
+        self.get_cf(column_family, key).await
+    }
+
+    pub async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let column_family = self.db.default_column_family();
+        self.set_cf(&column_family, key, value).await
+    }
+
+    pub async fn set_cf(
+        &mut self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        self.lock(column_family, key).await?;
+        self.writes.push((
+            column_family.clone(),
+            DbCmd::Set {
+                key: key.into(),
+                value: value.into(),
+            },
+        ));
+        Ok(())
+    }
+
+    pub async fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let column_family = self.db.default_column_family();
+        self.delete_cf(&column_family, key).await
+    }
+
+    pub async fn delete_cf(
+        &mut self,
+        column_family: &ColumnFamilyHandle,
+        key: &[u8],
+    ) -> Result<()> {
+        self.lock(column_family, key).await?;
+        self.writes
+            .push((column_family.clone(), DbCmd::Delete { key: key.into() }));
+        Ok(())
+    }
+
+    // Writes the buffered writes as a single batch, then releases the locks
+    pub async fn commit(mut self, options: &WriteOptions) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let (column_families, cmds): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.writes).into_iter().unzip();
+        let batch = column_families.iter().zip(cmds).collect();
+        self.db.batch_cf(batch, options).await
+    }
+
+    // Discards the buffered writes and releases the locks
+    pub fn rollback(self) {}
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.locks.unlock(self.id, &self.locked);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tracing::init_tracer;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use tracing::{info_span, Instrument};
+
+    #[tokio::test]
+    async fn lock_keys() {
+        init_tracer();
+        let span = info_span!("lock_keys");
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = TransactionDb::open(tmpdir.path(), DbOptions::default())
+                .await
+                .unwrap();
+            let write = WriteOptions::default();
+            let options = TransactionOptions {
+                lock_timeout: Duration::from_millis(50),
+            };
+            db.db().set(b"apples", b"10", &write).await.unwrap();
+
+            let mut first = db.begin_transaction(&options);
+            assert_eq!(
+                first.get_for_update(b"apples").await.unwrap(),
+                Some(b"10".to_vec())
+            );
+            first.set(b"apples", b"9").await.unwrap();
+            assert_eq!(first.get(b"apples").await.unwrap(), Some(b"9".to_vec()));
+
+            // Reads do not wait, writes and reads for update time out
+            let mut second = db.begin_transaction(&options);
+            assert_eq!(second.get(b"apples").await.unwrap(), Some(b"10".to_vec()));
+            let err = second.get_for_update(b"apples").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            let err = second.delete(b"apples").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            second.set(b"pears", b"5").await.unwrap();
+
+            first.commit(&write).await.unwrap();
+            assert_eq!(
+                second.get_for_update(b"apples").await.unwrap(),
+                Some(b"9".to_vec())
+            );
+            second.delete(b"apples").await.unwrap();
+            second.rollback();
+            let read = ReadOptions::default();
+            assert_eq!(
+                db.db().get(b"apples", &read).await.unwrap(),
+                Some(b"9".to_vec())
+            );
+            assert_eq!(db.db().get(b"pears", &read).await.unwrap(), None);
+
+            // Released by the rollback
+            let mut third = db.begin_transaction(&options);
+            third.delete(b"apples").await.unwrap();
+            third.commit(&write).await.unwrap();
+            assert_eq!(db.db().get(b"apples", &read).await.unwrap(), None);
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn abort_deadlocked() {
+        init_tracer();
+        let span = info_span!("abort_deadlocked");
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Arc::new(
+                TransactionDb::open(tmpdir.path(), DbOptions::default())
+                    .await
+                    .unwrap(),
+            );
+            let options = TransactionOptions {
+                lock_timeout: Duration::from_secs(5),
+            };
+
+            let mut first = db.begin_transaction(&options);
+            first.set(b"apples", b"1").await.unwrap();
+            let (locked, pears_locked) = tokio::sync::oneshot::channel();
+            let waiting = {
+                let db = db.clone();
+                let options = options.clone();
+                tokio::spawn(async move {
+                    let mut second = db.begin_transaction(&options);
+                    second.set(b"pears", b"2").await?;
+                    let _ = locked.send(());
+                    second.set(b"apples", b"2").await?;
+                    second.commit(&WriteOptions::default()).await
+                })
+            };
+            // The task waits for apples while holding pears by then
+            pears_locked.await.unwrap();
+            let err = first.set(b"pears", b"1").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Deadlock);
+            first.rollback();
+
+            waiting.await.unwrap().unwrap();
+            let read = ReadOptions::default();
+            assert_eq!(
+                db.db().get(b"apples", &read).await.unwrap(),
+                Some(b"2".to_vec())
+            );
+            assert_eq!(
+                db.db().get(b"pears", &read).await.unwrap(),
+                Some(b"2".to_vec())
+            );
+            let db = Arc::try_unwrap(db).ok().unwrap();
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_orders() {
+        init_tracer();
+        let span = info_span!("concurrent_orders");
+        async move {
+            let tmpdir = tempdir().unwrap();
+            let db = Arc::new(
+                TransactionDb::open(tmpdir.path(), DbOptions::default())
+                    .await
+                    .unwrap(),
+            );
+            db.db()
+                .set(b"stock", &100u64.to_le_bytes(), &WriteOptions::default())
+                .await
+                .unwrap();
+
+            // Every order takes one item out of the stock, never going below
+            // zero
+            let tasks: Vec<_> = (0..8)
+                .map(|_| {
+                    let db = db.clone();
+                    tokio::spawn(async move {
+                        let mut sold = 0;
+                        for _ in 0..20 {
+                            let options = TransactionOptions::default();
+                            let mut txn = db.begin_transaction(&options);
+                            let value = txn.get_for_update(b"stock").await.unwrap().unwrap();
+                            let stock = u64::from_le_bytes(value.try_into().unwrap());
+                            if stock == 0 {
+                                txn.rollback();
+                                continue;
+                            }
+                            txn.set(b"stock", &(stock - 1).to_le_bytes()).await.unwrap();
+                            txn.commit(&WriteOptions::default()).await.unwrap();
+                            sold += 1;
+                        }
+                        sold
+                    })
+                })
+                .collect();
+            let mut sold = 0;
+            for task in tasks {
+                sold += task.await.unwrap();
+            }
+            assert_eq!(sold, 100);
+
+            let value = db
+                .db()
+                .get(b"stock", &ReadOptions::default())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(u64::from_le_bytes(value.try_into().unwrap()), 0);
+            let db = Arc::try_unwrap(db).ok().unwrap();
+            db.close().await.unwrap();
+        }
+        .instrument(span)
+        .await;
+    }
+}
@@ -0,0 +1,7 @@
+use std::cmp::Ordering;
+
+pub type Comparator = fn(&[u8], &[u8]) -> Ordering;
+
+pub fn bytewise_compare(left: &[u8], right: &[u8]) -> Ordering {
+    left.cmp(right)
+}
@@ -0,0 +1,166 @@
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::cmp::{Ordering, Reverse};
+use std::io::{Error, ErrorKind, Result};
+use std::mem::size_of;
+
+pub const MAX_SEQ_NUM: u64 = (1 << 56) - 1;
+
+const TRAILER_SIZE: usize = size_of::<u64>();
+
+#[repr(u8)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValueType {
+    Delete = 0,
+    Set = 1,
+    Merge = 2,
+    SingleDelete = 3,
+}
+
+impl ValueType {
+    // The highest type, sorting first among the entries of a key with the
+    // same sequence number
+    pub const FOR_SEEK: ValueType = ValueType::SingleDelete;
+
+    pub fn is_delete(self) -> bool {
+        matches!(self, ValueType::Delete | ValueType::SingleDelete)
+    }
+}
+
+// A version of a user key: the user key followed by a little endian trailer
+// packing the 56-bit sequence number with the value type in the low byte.
+// Keys are ordered by user key ascending, then by sequence number descending
+// so that the latest version of a key comes first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InternalKey {
+    pub user_key: Vec<u8>,
+    pub seq_num: u64,
+    pub value_type: ValueType,
+}
+
+impl InternalKey {
+    pub fn new(user_key: Vec<u8>, seq_num: u64, value_type: ValueType) -> Self {
+        debug_assert!(seq_num <= MAX_SEQ_NUM);
+        Self {
+            user_key,
+            seq_num,
+            value_type,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode(&self.user_key, self.seq_num, self.value_type as u8)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let (user_key, trailer) = split(data)?;
+        let value_type_value = trailer as u8;
+        match FromPrimitive::from_u8(value_type_value) {
+            Some(value_type) => Ok(Self {
+                user_key: user_key.to_vec(),
+                seq_num: trailer >> 8,
+                value_type,
+            }),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown value type: {}", value_type_value),
+            )),
+        }
+    }
+}
+
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (
+            &self.user_key,
+            Reverse(self.seq_num),
+            Reverse(self.value_type),
+        )
+            .cmp(&(
+                &other.user_key,
+                Reverse(other.seq_num),
+                Reverse(other.value_type),
+            ))
+    }
+}
+
+impl PartialOrd for InternalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Encoded key sorting right before the versions of `user_key` visible at
+// `seq_num`
+pub fn lookup_key(user_key: &[u8], seq_num: u64) -> Vec<u8> {
+    encode(user_key, seq_num, ValueType::FOR_SEEK as u8)
+}
+
+fn encode(user_key: &[u8], seq_num: u64, value_type: u8) -> Vec<u8> {
+    let mut data = Vec::with_capacity(user_key.len() + TRAILER_SIZE);
+    data.extend_from_slice(user_key);
+    data.extend_from_slice(&(seq_num << 8 | value_type as u64).to_le_bytes());
+    data
+}
+
+fn split(data: &[u8]) -> Result<(&[u8], u64)> {
+    if data.len() < TRAILER_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Internal key shorter than its trailer",
+        ));
+    }
+    let (user_key, trailer) = data.split_at(data.len() - TRAILER_SIZE);
+    Ok((user_key, u64::from_le_bytes(trailer.try_into().unwrap())))
+}
+
+pub fn user_key(data: &[u8]) -> &[u8] {
+    &data[..data.len() - TRAILER_SIZE]
+}
+
+// Compares encoded internal keys
+pub fn compare(left: &[u8], right: &[u8]) -> Ordering {
+    let (left_key, left_trailer) = split(left).unwrap();
+    let (right_key, right_trailer) = split(right).unwrap();
+    left_key
+        .cmp(right_key)
+        .then(right_trailer.cmp(&left_trailer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let key = InternalKey::new(b"foo".to_vec(), MAX_SEQ_NUM, ValueType::Delete);
+        let data = key.encode();
+        assert_eq!(user_key(&data), b"foo");
+        assert_eq!(InternalKey::decode(&data).unwrap(), key);
+        assert!(InternalKey::decode(b"foo").is_err());
+    }
+
+    #[test]
+    fn ordering() {
+        let keys = [
+            InternalKey::new(b"a".to_vec(), 2, ValueType::Set),
+            InternalKey::new(b"a".to_vec(), 1, ValueType::Delete),
+            InternalKey::new(b"ab".to_vec(), 5, ValueType::Set),
+            InternalKey::new(b"b".to_vec(), 300, ValueType::Set),
+            InternalKey::new(b"b".to_vec(), 4, ValueType::Set),
+        ];
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert_eq!(
+                compare(&pair[0].encode(), &pair[1].encode()),
+                Ordering::Less
+            );
+        }
+
+        let lookup = lookup_key(b"a", 1);
+        assert_eq!(compare(&lookup, &keys[0].encode()), Ordering::Greater);
+        assert_eq!(compare(&lookup, &keys[1].encode()), Ordering::Less);
+        let lookup = lookup_key(b"a", 2);
+        assert_ne!(compare(&lookup, &keys[0].encode()), Ordering::Greater);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod comparator;
+pub mod internal_key;
+pub mod range_tombstone;
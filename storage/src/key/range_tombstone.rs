@@ -0,0 +1,183 @@
+// Deletes the versions of the user keys in [start, end) written before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seq_num: u64,
+}
+
+impl RangeTombstone {
+    pub fn new(start: Vec<u8>, end: Vec<u8>, seq_num: u64) -> Self {
+        Self {
+            start,
+            end,
+            seq_num,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fragment {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    // Newest first
+    seq_nums: Vec<u64>,
+}
+
+// Range tombstones split at each of their bounds into fragments that do not
+// overlap, sorted by start key. A fragment keeps the sequence numbers of all
+// the tombstones covering it so that older reads still see the right one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FragmentedRangeTombstones {
+    fragments: Vec<Fragment>,
+}
+
+impl FragmentedRangeTombstones {
+    pub fn new<'a, I: IntoIterator<Item = &'a RangeTombstone>>(tombstones: I) -> Self {
+        let tombstones: Vec<&RangeTombstone> =
+            tombstones.into_iter().filter(|t| t.start < t.end).collect();
+        let mut bounds: Vec<&[u8]> = tombstones
+            .iter()
+            .flat_map(|t| [t.start.as_slice(), t.end.as_slice()])
+            .collect();
+        bounds.sort();
+        bounds.dedup();
+
+        let mut fragments: Vec<Fragment> = Vec::new();
+        for window in bounds.windows(2) {
+            let (start, end) = (window[0], window[1]);
+            let mut seq_nums: Vec<u64> = tombstones
+                .iter()
+                .filter(|t| t.start.as_slice() <= start && end <= t.end.as_slice())
+                .map(|t| t.seq_num)
+                .collect();
+            if seq_nums.is_empty() {
+                continue;
+            }
+            seq_nums.sort_by(|a, b| b.cmp(a));
+            seq_nums.dedup();
+            // Neighbours covered by the same tombstones make a single fragment
+            match fragments.last_mut() {
+                Some(last) if last.end == start && last.seq_nums == seq_nums => {
+                    last.end = end.to_vec();
+                }
+                _ => fragments.push(Fragment {
+                    start: start.to_vec(),
+                    end: end.to_vec(),
+                    seq_nums,
+                }),
+            }
+        }
+        Self { fragments }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    // Smallest start and largest end of the fragments
+    pub fn bounds(&self) -> Option<(&[u8], &[u8])> {
+        match (self.fragments.first(), self.fragments.last()) {
+            (Some(first), Some(last)) => Some((&first.start, &last.end)),
+            _ => None,
+        }
+    }
+
+    // Sequence number of the newest tombstone covering the key visible at
+    // `seq_num`
+    pub fn covering(&self, key: &[u8], seq_num: u64) -> Option<u64> {
+        let index = self.fragments.partition_point(|f| f.end.as_slice() <= key);
+        self.fragments
+            .get(index)
+            .filter(|f| f.start.as_slice() <= key)
+            .and_then(|f| f.seq_nums.iter().find(|s| **s <= seq_num).copied())
+    }
+
+    // One tombstone per fragment and sequence number
+    pub fn tombstones(&self) -> Vec<RangeTombstone> {
+        self.clip(None, None)
+    }
+
+    // Tombstones cut to [start, end), a missing bound being unbounded
+    pub fn clip(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Vec<RangeTombstone> {
+        let mut tombstones = Vec::new();
+        for fragment in &self.fragments {
+            let clipped_start = match start {
+                Some(start) if fragment.start.as_slice() < start => start,
+                _ => fragment.start.as_slice(),
+            };
+            let clipped_end = match end {
+                Some(end) if end < fragment.end.as_slice() => end,
+                _ => fragment.end.as_slice(),
+            };
+            if clipped_start >= clipped_end {
+                continue;
+            }
+            for seq_num in &fragment.seq_nums {
+                tombstones.push(RangeTombstone::new(
+                    clipped_start.to_vec(),
+                    clipped_end.to_vec(),
+                    *seq_num,
+                ));
+            }
+        }
+        tombstones
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tombstone(start: &[u8], end: &[u8], seq_num: u64) -> RangeTombstone {
+        RangeTombstone::new(start.to_vec(), end.to_vec(), seq_num)
+    }
+
+    #[test]
+    fn fragment_overlapping() {
+        let fragmented = FragmentedRangeTombstones::new(&[
+            tombstone(b"a", b"e", 5),
+            tombstone(b"c", b"g", 8),
+            tombstone(b"x", b"x", 9),
+        ]);
+        assert_eq!(
+            fragmented.tombstones(),
+            vec![
+                tombstone(b"a", b"c", 5),
+                tombstone(b"c", b"e", 8),
+                tombstone(b"c", b"e", 5),
+                tombstone(b"e", b"g", 8),
+            ]
+        );
+        assert_eq!(fragmented.bounds(), Some((&b"a"[..], &b"g"[..])));
+
+        assert_eq!(fragmented.covering(b"a", 10), Some(5));
+        assert_eq!(fragmented.covering(b"d", 10), Some(8));
+        assert_eq!(fragmented.covering(b"d", 7), Some(5));
+        assert_eq!(fragmented.covering(b"d", 4), None);
+        assert_eq!(fragmented.covering(b"f", 10), Some(8));
+        assert_eq!(fragmented.covering(b"g", 10), None);
+        assert_eq!(fragmented.covering(b"0", 10), None);
+
+        // Fragmenting again changes nothing
+        assert_eq!(
+            FragmentedRangeTombstones::new(&fragmented.tombstones()),
+            fragmented
+        );
+    }
+
+    #[test]
+    fn clip_fragments() {
+        let fragmented =
+            FragmentedRangeTombstones::new(&[tombstone(b"a", b"e", 5), tombstone(b"g", b"k", 6)]);
+        assert_eq!(
+            fragmented.clip(Some(b"c"), Some(b"h")),
+            vec![tombstone(b"c", b"e", 5), tombstone(b"g", b"h", 6)]
+        );
+        assert_eq!(fragmented.clip(Some(b"e"), Some(b"g")), vec![]);
+        assert_eq!(
+            fragmented.clip(None, Some(b"b")),
+            vec![tombstone(b"a", b"b", 5)]
+        );
+    }
+}
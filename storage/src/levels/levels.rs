@@ -1,9 +1,249 @@
+use std::io::Result;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::key::internal_key::InternalKey;
+use crate::sst::table::reader::sst_table_writer_new;
 use crate::sst::table::table::SstTable;
+use crate::version::version::{FileMetaData, Version};
+
+#[derive(Debug, Clone)]
+pub struct LevelFile {
+    pub meta: Arc<FileMetaData>,
+    pub table: Arc<SstTable>,
+}
+
+impl LevelFile {
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.meta.smallest.as_slice() <= key && key <= self.meta.largest.as_slice()
+    }
 
+    fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.meta.smallest.as_slice() <= largest && smallest <= self.meta.largest.as_slice()
+    }
+
+    // Whether the file may hold keys of [start, end], a missing bound being
+    // unbounded
+    pub fn overlaps_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> bool {
+        start.is_none_or(|start| start <= self.meta.largest.as_slice())
+            && end.is_none_or(|end| self.meta.smallest.as_slice() <= end)
+    }
+}
+
+// Files of level 0 may overlap and are kept oldest first. Files of the other
+// levels cover disjoint key ranges and are kept sorted by key.
+#[derive(Debug, Default, Clone)]
 pub struct Level {
-    tables: Vec<SstTable>,
+    files: Vec<LevelFile>,
 }
 
+#[derive(Debug, Clone)]
 pub struct Levels {
     levels: Vec<Level>,
 }
+
+impl Levels {
+    pub fn new(level_count: usize) -> Self {
+        let levels = (0..level_count).map(|_| Level::default()).collect();
+        Self { levels }
+    }
+
+    // Opens the sst files of a column family of a version
+    pub async fn open(
+        path: &Path,
+        version: &Version,
+        column_family: u32,
+        level_count: usize,
+    ) -> Result<Self> {
+        let mut levels = Self::new(level_count.max(version.num_levels(column_family)));
+        for level in 0..version.num_levels(column_family) {
+            for meta in version.files(column_family, level) {
+                let file_path = path.join(format!("SST-{}", meta.file_number));
+                let table = sst_table_writer_new(file_path).await?;
+                levels.add(level, meta.clone(), Arc::new(table));
+            }
+        }
+        Ok(levels)
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn files(&self, level: usize) -> &[LevelFile] {
+        &self.levels[level].files
+    }
+
+    pub fn size(&self, level: usize) -> u64 {
+        self.files(level).iter().map(|f| f.meta.file_size).sum()
+    }
+
+    // Files of the level whose key range intersects [smallest, largest]
+    pub fn overlapping(&self, level: usize, smallest: &[u8], largest: &[u8]) -> Vec<LevelFile> {
+        self.files(level)
+            .iter()
+            .filter(|f| f.overlaps(smallest, largest))
+            .cloned()
+            .collect()
+    }
+
+    // Whether no level below the given one may hold a version of the key
+    pub fn is_bottommost(&self, level: usize, key: &[u8]) -> bool {
+        self.levels[level + 1..]
+            .iter()
+            .all(|l| !l.files.iter().any(|f| f.may_contain(key)))
+    }
+
+    // Whether no level below the given one may hold a key of [start, end)
+    pub fn is_bottommost_range(&self, level: usize, start: &[u8], end: &[u8]) -> bool {
+        self.levels[level + 1..]
+            .iter()
+            .all(|l| !l.files.iter().any(|f| f.overlaps(start, end)))
+    }
+
+    pub fn add(&mut self, level: usize, meta: Arc<FileMetaData>, table: Arc<SstTable>) {
+        let files = &mut self.levels[level].files;
+        let file = LevelFile { meta, table };
+        let index = if level == 0 {
+            // Compaction outputs may be numbered after files flushed while
+            // they were written, but hold older writes.
+            files.partition_point(|f| f.meta.largest_seqno <= file.meta.largest_seqno)
+        } else {
+            files.partition_point(|f| f.meta.smallest < file.meta.smallest)
+        };
+        files.insert(index, file);
+    }
+
+    pub fn remove(&mut self, level: usize, file_number: u64) {
+        self.levels[level]
+            .files
+            .retain(|f| f.meta.file_number != file_number);
+    }
+
+    // Newest version of the key visible at `seq_num`, deletes included so
+    // that they shadow older values.
+    pub async fn get(&self, key: &[u8], seq_num: u64) -> Result<Option<(InternalKey, Vec<u8>)>> {
+        if let Some((first, rest)) = self.levels.split_first() {
+            // Newer files shadow older ones
+            for file in first.files.iter().rev() {
+                if file.may_contain(key) {
+                    if let Some(value) = file.table.get(key, seq_num).await? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+            for level in rest {
+                let index = level
+                    .files
+                    .partition_point(|f| f.meta.largest.as_slice() < key);
+                // A file whose range tombstones end at the key shares it as a
+                // bound with the next file
+                for file in level.files[index..]
+                    .iter()
+                    .take_while(|f| f.may_contain(key))
+                {
+                    if let Some(value) = file.table.get(key, seq_num).await? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::column_family::DEFAULT_COLUMN_FAMILY;
+    use crate::db::options::DbOptions;
+    use crate::key::internal_key::{ValueType, MAX_SEQ_NUM};
+    use crate::sst::table::writer::SstTableWriter;
+    use crate::version::version_set::VersionSet;
+    use tempfile::tempdir;
+
+    // Writes the given keys, each with the file number as sequence number and
+    // as value
+    async fn written_file(
+        path: &Path,
+        file_number: u64,
+        keys: &[(&str, ValueType)],
+    ) -> FileMetaData {
+        let file_path = path.join(format!("SST-{}", file_number));
+        let mut writer = SstTableWriter::new(file_path, keys.len(), DbOptions::default())
+            .await
+            .unwrap();
+        for (key, value_type) in keys {
+            let key = InternalKey::new(key.as_bytes().to_vec(), file_number, *value_type);
+            writer
+                .add(&key.encode(), file_number.to_string().as_bytes())
+                .await
+                .unwrap();
+        }
+        writer.finish().await.unwrap();
+        FileMetaData {
+            file_number,
+            file_size: 0,
+            smallest: keys[0].0.as_bytes().to_vec(),
+            largest: keys[keys.len() - 1].0.as_bytes().to_vec(),
+            smallest_seqno: file_number,
+            largest_seqno: file_number,
+            creation_time: None,
+        }
+    }
+
+    async fn get(levels: &Levels, key: &[u8], seq_num: u64) -> Option<(ValueType, Vec<u8>)> {
+        let found = levels.get(key, seq_num).await.unwrap();
+        found.map(|(key, value)| (key.value_type, value))
+    }
+
+    fn set(file_number: u64) -> Option<(ValueType, Vec<u8>)> {
+        Some((ValueType::Set, file_number.to_string().into_bytes()))
+    }
+
+    #[tokio::test]
+    async fn get_across_levels() {
+        use ValueType::{Delete, Set};
+
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let files = [
+            (
+                1,
+                written_file(path, 0, &[("a", Set), ("f", Set), ("k", Set)]).await,
+            ),
+            (1, written_file(path, 1, &[("m", Set), ("p", Set)]).await),
+            (1, written_file(path, 2, &[("s", Set), ("z", Set)]).await),
+            (0, written_file(path, 3, &[("b", Set), ("f", Set)]).await),
+            (0, written_file(path, 4, &[("f", Set), ("g", Set)]).await),
+            (0, written_file(path, 5, &[("p", Delete)]).await),
+        ];
+        let mut versions = VersionSet::default();
+        let entries: Vec<_> = files
+            .iter()
+            .map(|(level, file)| file.new_file_entry(DEFAULT_COLUMN_FAMILY, *level))
+            .collect();
+        versions.apply(&entries);
+
+        let levels = Levels::open(path, &versions.current(), DEFAULT_COLUMN_FAMILY, 7)
+            .await
+            .unwrap();
+        assert_eq!(get(&levels, b"f", MAX_SEQ_NUM).await, set(4));
+        assert_eq!(get(&levels, b"b", MAX_SEQ_NUM).await, set(3));
+        assert_eq!(get(&levels, b"g", MAX_SEQ_NUM).await, set(4));
+        assert_eq!(get(&levels, b"a", MAX_SEQ_NUM).await, set(0));
+        assert_eq!(get(&levels, b"m", MAX_SEQ_NUM).await, set(1));
+        assert_eq!(get(&levels, b"z", MAX_SEQ_NUM).await, set(2));
+        assert_eq!(get(&levels, b"l", MAX_SEQ_NUM).await, None);
+        assert_eq!(get(&levels, b"q", MAX_SEQ_NUM).await, None);
+        assert_eq!(get(&levels, b"zz", MAX_SEQ_NUM).await, None);
+
+        // Deletes shadow the values of lower levels
+        assert_eq!(get(&levels, b"p", MAX_SEQ_NUM).await.unwrap().0, Delete);
+
+        // Versions newer than the given sequence number are not visible
+        assert_eq!(get(&levels, b"f", 3).await, set(3));
+        assert_eq!(get(&levels, b"f", 2).await, set(0));
+        assert_eq!(get(&levels, b"p", 4).await, set(1));
+    }
+}
@@ -1,6 +1,10 @@
+mod compaction;
 pub mod db;
+mod key;
 mod levels;
 mod manifest;
+mod memtable;
 mod sst;
 mod utils;
+mod version;
 mod wal;
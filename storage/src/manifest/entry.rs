@@ -20,6 +20,8 @@ pub enum ManifestLogEntryType {
     DbId = 9,
     WalAddition = 10,
     WalDeletion = 11,
+    ColumnFamilyAdd = 12,
+    ColumnFamilyDrop = 13,
 }
 
 #[derive(Debug)]
@@ -66,6 +68,13 @@ pub enum ManifestLogEntry {
     WalDeletion {
         log_number: u64,
     },
+    ColumnFamilyAdd {
+        column_family: u32,
+        name: String,
+    },
+    ColumnFamilyDrop {
+        column_family: u32,
+    },
 }
 
 impl ManifestLogEntry {
@@ -84,6 +93,12 @@ impl ManifestLogEntry {
             ManifestLogEntry::DbId { .. } => ManifestLogEntryType::DbId as u32,
             ManifestLogEntry::WalAddition { .. } => ManifestLogEntryType::WalAddition as u32,
             ManifestLogEntry::WalDeletion { .. } => ManifestLogEntryType::WalDeletion as u32,
+            ManifestLogEntry::ColumnFamilyAdd { .. } => {
+                ManifestLogEntryType::ColumnFamilyAdd as u32
+            }
+            ManifestLogEntry::ColumnFamilyDrop { .. } => {
+                ManifestLogEntryType::ColumnFamilyDrop as u32
+            }
         }
     }
     pub async fn write<W: AsyncWriteExt + Unpin>(&self, writer: &mut W) -> Result<()> {
@@ -147,6 +162,16 @@ impl ManifestLogEntry {
             ManifestLogEntry::WalDeletion { log_number } => {
                 async_write_varint(*log_number, writer).await?;
             }
+            ManifestLogEntry::ColumnFamilyAdd {
+                column_family,
+                name,
+            } => {
+                async_write_varint(*column_family, writer).await?;
+                async_write_string(name, writer).await?;
+            }
+            ManifestLogEntry::ColumnFamilyDrop { column_family } => {
+                async_write_varint(*column_family, writer).await?;
+            }
         }
         Ok(())
     }
@@ -242,6 +267,18 @@ impl ManifestLogEntry {
                 let log_number = async_read_varint(reader).await?;
                 Ok(ManifestLogEntry::WalDeletion { log_number })
             }
+            Some(ManifestLogEntryType::ColumnFamilyAdd) => {
+                let column_family = async_read_varint(reader).await?;
+                let name = async_read_string(reader).await?;
+                Ok(ManifestLogEntry::ColumnFamilyAdd {
+                    column_family,
+                    name,
+                })
+            }
+            Some(ManifestLogEntryType::ColumnFamilyDrop) => {
+                let column_family = async_read_varint(reader).await?;
+                Ok(ManifestLogEntry::ColumnFamilyDrop { column_family })
+            }
             None => {
                 panic!("Unknown entry type: {}", entry_type_value);
             }
@@ -303,6 +340,7 @@ pub enum NewFileTagType {
     FileCreationTime,
     FileCheckSum,
     FileCheckSumFuncName,
+    ColumnFamily,
 }
 
 #[derive(Debug)]
@@ -312,6 +350,8 @@ pub enum NewFileTag {
     FileCreationTime { time: u64 },
     FileCheckSum { chec_sum: u32 },
     FileCheckSumFuncName { func_name: String },
+    // Left out for the default column family
+    ColumnFamily { column_family: u32 },
 }
 
 impl NewFileTag {
@@ -322,6 +362,7 @@ impl NewFileTag {
             NewFileTag::FileCreationTime { .. } => NewFileTagType::FileCreationTime as u32,
             NewFileTag::FileCheckSum { .. } => NewFileTagType::FileCheckSum as u32,
             NewFileTag::FileCheckSumFuncName { .. } => NewFileTagType::FileCheckSumFuncName as u32,
+            NewFileTag::ColumnFamily { .. } => NewFileTagType::ColumnFamily as u32,
         }
     }
 
@@ -339,6 +380,9 @@ impl NewFileTag {
             NewFileTag::FileCheckSumFuncName { func_name } => {
                 async_write_string(func_name, writer).await?;
             }
+            NewFileTag::ColumnFamily { column_family } => {
+                async_write_varint(*column_family, writer).await?;
+            }
         }
         Ok(())
     }
@@ -361,6 +405,10 @@ impl NewFileTag {
                 let func_name = async_read_string(reader).await?;
                 Ok(NewFileTag::FileCheckSumFuncName { func_name })
             }
+            Some(NewFileTagType::ColumnFamily) => {
+                let column_family = async_read_varint(reader).await?;
+                Ok(NewFileTag::ColumnFamily { column_family })
+            }
             None => {
                 panic!("Unknown tag type: {}", tag_type_value);
             }
@@ -1,24 +1,55 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
-use tokio::fs::{read_to_string, write};
-use tokio::sync::mpsc::Receiver;
+use std::sync::Arc;
+use tokio::fs::{read_to_string, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
 
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
+
+use crate::utils::fs::sync_dir;
+use crate::version::version::Version;
+use crate::version::version_set::VersionSet;
 
 use super::entry::ManifestLogEntry;
 use super::writer::ManifestWriter;
 
 pub enum ManifestRequest {
-    Append { entries: Vec<ManifestLogEntry> },
+    Append {
+        entries: Vec<ManifestLogEntry>,
+        completion: oneshot::Sender<Result<()>>,
+    },
     Close,
 }
 
+// Resolves once the entries are durably written to the manifest
+pub async fn append_entries(
+    sender: &Sender<ManifestRequest>,
+    entries: Vec<ManifestLogEntry>,
+) -> Result<()> {
+    let (completion, receiver) = oneshot::channel();
+    sender
+        .send(ManifestRequest::Append {
+            entries,
+            completion,
+        })
+        .await
+        .map_err(|_| manifest_stopped())?;
+    receiver.await.map_err(|_| manifest_stopped())?
+}
+
+fn manifest_stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Manifest stopped")
+}
+
 #[derive(Debug)]
 pub struct Manifest {
     seq_num: u64,
     path: PathBuf,
     current: ManifestWriter,
     receiver: Receiver<ManifestRequest>,
+    versions: VersionSet,
 }
 
 impl Manifest {
@@ -28,17 +59,21 @@ impl Manifest {
 
         let current_name = "MANIFEST-0";
 
-        let current_path = path.join("CURRENT");
-        write(current_path, current_name).await?;
+        // Synced before the directory, an empty CURRENT could not be loaded
+        let mut current_file = File::create(path.join("CURRENT")).await?;
+        current_file.write_all(current_name.as_bytes()).await?;
+        current_file.sync_all().await?;
         info!("Created manifest with seq_num: 0");
 
         let current = ManifestWriter::new(seq_num, path.join(current_name)).await?;
+        sync_dir(&path).await?;
 
         Ok(Self {
             seq_num,
             path,
             current,
             receiver,
+            versions: VersionSet::default(),
         })
     }
 
@@ -50,43 +85,93 @@ impl Manifest {
         let seq_num = current_name[9..].parse::<u64>().unwrap();
         info!("Loaded manifest with seq_num: {}", seq_num);
 
-        let current = ManifestWriter::new(seq_num, path.join(current_name)).await?;
+        let (versions, end) = VersionSet::recover(path.clone()).await?;
+        // Whatever follows the last entry was cut short by a crash, entries
+        // appended behind it could not be read back
+        let log_path = path.join(&current_name);
+        let file = OpenOptions::new().write(true).open(&log_path).await?;
+        let size = file.metadata().await?.len();
+        if size > end {
+            warn!("Truncating {} from {} to {} bytes", current_name, size, end);
+            file.set_len(end).await?;
+            file.sync_all().await?;
+        }
+        let current = ManifestWriter::new(seq_num, log_path).await?;
 
         Ok(Self {
             seq_num,
             path,
             current,
             receiver,
+            versions,
         })
     }
 
     #[instrument]
     pub async fn append(&mut self, entries: Vec<ManifestLogEntry>) -> Result<()> {
-        self.current.append(entries).await?;
+        // Entries appended together are replayed all or none
+        let mut log_entries = Vec::with_capacity(entries.len() + 1);
+        if entries.len() > 1 {
+            log_entries.push(ManifestLogEntry::InAtomicGroup {
+                version_edit_count: entries.len() as u32,
+            });
+        }
+        log_entries.extend(entries);
+        self.current.append(&log_entries).await?;
+        self.versions.apply(&log_entries);
         Ok(())
     }
 
-    pub async fn run(&mut self) {
+    pub fn current(&self) -> Arc<Version> {
+        self.versions.current()
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
         while let Some(msg) = self.receiver.recv().await {
             match msg {
-                ManifestRequest::Append { entries } => {
-                    self.append(entries).await.unwrap();
+                ManifestRequest::Append {
+                    entries,
+                    completion,
+                } => {
+                    // A failed append leaves the manifest in an unknown state,
+                    // nothing can be committed after it.
+                    if let Err(err) = self.append(entries).await {
+                        let _ = completion.send(Err(Error::new(err.kind(), err.to_string())));
+                        return Err(err);
+                    }
+                    let _ = completion.send(Ok(()));
                 }
                 ManifestRequest::Close => {
                     break;
                 }
             }
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::db::column_family::DEFAULT_COLUMN_FAMILY;
     use crate::manifest::entry::WalTag;
 
     use super::*;
     use std::path::PathBuf;
     use tempfile::tempdir;
+    use tokio::fs::write;
+
+    fn new_file(file_number: u64) -> ManifestLogEntry {
+        ManifestLogEntry::NewFile {
+            level: 0,
+            file_number,
+            file_size: 100,
+            smallest: b"a".to_vec(),
+            largest: b"z".to_vec(),
+            smallest_seqno: file_number,
+            largest_seqno: file_number,
+            tags: vec![],
+        }
+    }
 
     #[tokio::test]
     async fn test_manifest_create() {
@@ -94,6 +179,7 @@ mod tests {
         let path = PathBuf::from(dir.path());
         let (sender, receiver) = tokio::sync::mpsc::channel(1024);
         let mut manifest = Manifest::create(path.clone(), receiver).await.unwrap();
+        let (completion, receiver) = oneshot::channel();
         sender
             .send(ManifestRequest::Append {
                 entries: vec![
@@ -105,11 +191,51 @@ mod tests {
                         tags: vec![WalTag::SyncedSize { size: 0 }],
                     },
                 ],
+                completion,
             })
             .await
             .unwrap();
         sender.send(ManifestRequest::Close).await.unwrap();
-        manifest.run().await;
+        manifest.run().await.unwrap();
+        receiver.await.unwrap().unwrap();
         assert_eq!(manifest.seq_num, 0);
     }
+
+    #[tokio::test]
+    async fn append_after_partial_atomic_group() {
+        let dir = tempdir().unwrap();
+        let path = PathBuf::from(dir.path());
+        write(path.join("CURRENT"), "MANIFEST-0").await.unwrap();
+        let mut writer = ManifestWriter::new(0, path.join("MANIFEST-0"))
+            .await
+            .unwrap();
+        // A compaction cut short after its new file, before its deleted one
+        writer
+            .append(&[
+                new_file(0),
+                ManifestLogEntry::InAtomicGroup {
+                    version_edit_count: 2,
+                },
+                new_file(1),
+            ])
+            .await
+            .unwrap();
+        drop(writer);
+
+        let (_, receiver) = tokio::sync::mpsc::channel(1);
+        let mut manifest = Manifest::load(path.clone(), receiver).await.unwrap();
+        manifest.append(vec![new_file(2)]).await.unwrap();
+        drop(manifest);
+
+        // The new edit does not complete the group
+        let (_, receiver) = tokio::sync::mpsc::channel(1);
+        let manifest = Manifest::load(path, receiver).await.unwrap();
+        let files: Vec<u64> = manifest
+            .current()
+            .files(DEFAULT_COLUMN_FAMILY, 0)
+            .iter()
+            .map(|f| f.file_number)
+            .collect();
+        assert_eq!(files, vec![0, 2]);
+    }
 }
@@ -1,24 +1,30 @@
-use std::{io::Result, path::PathBuf};
+use std::{
+    io::{Cursor, ErrorKind, Result},
+    path::PathBuf,
+};
 
 use async_stream::try_stream;
-use tokio::{
-    fs::{read_to_string, File},
-    io::BufReader,
-};
+use tokio::fs::{read, read_to_string};
 use tokio_stream::StreamExt;
 
 use super::entry::ManifestLogEntry;
 
-pub async fn iter_from(path: PathBuf) -> impl StreamExt<Item = Result<ManifestLogEntry>> {
+// Each entry of the current manifest along with the offset it ends at
+pub async fn iter_from(path: PathBuf) -> impl StreamExt<Item = Result<(ManifestLogEntry, u64)>> {
     try_stream! {
         let current_path = path.join("CURRENT");
         let current = read_to_string(current_path).await?;
 
         let log_path = path.join(current);
-        let mut reader = BufReader::new(File::open(log_path).await?);
+        let mut reader = Cursor::new(read(log_path).await?);
         loop {
-            let entry = ManifestLogEntry::read(&mut reader).await?;
-            yield entry;
+            match ManifestLogEntry::read(&mut reader).await {
+                Ok(entry) => yield (entry, reader.position()),
+                // Either the end of the log or an entry cut short by a crash,
+                // which was never committed.
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => Err(err)?,
+            }
         }
     }
 }
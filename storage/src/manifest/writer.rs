@@ -26,11 +26,13 @@ impl ManifestWriter {
     }
 
     #[instrument]
-    pub async fn append(&mut self, entries: Vec<ManifestLogEntry>) -> Result<()> {
+    pub async fn append(&mut self, entries: &[ManifestLogEntry]) -> Result<()> {
         for entry in entries {
             entry.write(&mut self.writer).await?;
         }
         self.writer.flush().await?;
+        // Edits are only committed once they are on disk
+        self.writer.get_ref().sync_data().await?;
         Ok(())
     }
 }
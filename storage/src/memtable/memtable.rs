@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use crate::key::internal_key::{InternalKey, ValueType, MAX_SEQ_NUM};
+use crate::key::range_tombstone::RangeTombstone;
+use crate::wal::entry::WalEntry;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemTableValue {
+    Set(Vec<u8>),
+    Delete,
+    Merge(Vec<u8>),
+    // Read back as a delete
+    SingleDelete,
+}
+
+#[derive(Debug, Default)]
+pub struct MemTable {
+    // The first entry found for a key is always its latest version
+    entries: RwLock<BTreeMap<InternalKey, Vec<u8>>>,
+    // In insertion order, fragmented when flushed
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
+    approximate_size: AtomicUsize,
+}
+
+impl MemTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, key: Vec<u8>, seq_num: u64, value: MemTableValue) {
+        let (value_type, value) = match value {
+            MemTableValue::Set(value) => (ValueType::Set, value),
+            MemTableValue::Delete => (ValueType::Delete, Vec::new()),
+            MemTableValue::Merge(operand) => (ValueType::Merge, operand),
+            MemTableValue::SingleDelete => (ValueType::SingleDelete, Vec::new()),
+        };
+        self.approximate_size.fetch_add(
+            key.len() + value.len() + size_of::<u64>(),
+            Ordering::Relaxed,
+        );
+        let key = InternalKey::new(key, seq_num, value_type);
+        self.entries.write().unwrap().insert(key, value);
+    }
+
+    pub fn insert_range_tombstone(&self, start: Vec<u8>, end: Vec<u8>, seq_num: u64) {
+        self.approximate_size.fetch_add(
+            start.len() + end.len() + size_of::<u64>(),
+            Ordering::Relaxed,
+        );
+        self.range_tombstones
+            .write()
+            .unwrap()
+            .push(RangeTombstone::new(start, end, seq_num));
+    }
+
+    pub fn apply(&self, seq_num: u64, entry: WalEntry) {
+        match entry {
+            WalEntry::Set { key, value } => {
+                self.insert(key, seq_num, MemTableValue::Set(value));
+            }
+            WalEntry::Delete { key } => {
+                self.insert(key, seq_num, MemTableValue::Delete);
+            }
+            WalEntry::Merge { key, operand } => {
+                self.insert(key, seq_num, MemTableValue::Merge(operand));
+            }
+            WalEntry::SingleDelete { key } => {
+                self.insert(key, seq_num, MemTableValue::SingleDelete);
+            }
+            WalEntry::DeleteRange { start, end } => {
+                self.insert_range_tombstone(start, end, seq_num);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<MemTableValue> {
+        self.get_at(key, MAX_SEQ_NUM).map(|(_, value)| value)
+    }
+
+    // Newest version of the key visible at `seq_num`, with its sequence number.
+    // A range tombstone written after that version reads as a delete.
+    pub fn get_at(&self, key: &[u8], seq_num: u64) -> Option<(u64, MemTableValue)> {
+        let from = InternalKey::new(key.to_vec(), seq_num, ValueType::FOR_SEEK);
+        let found = {
+            let entries = self.entries.read().unwrap();
+            entries
+                .range(from..)
+                .next()
+                .filter(|(k, _)| k.user_key == key)
+                .map(|(k, v)| {
+                    let value = match k.value_type {
+                        ValueType::Set => MemTableValue::Set(v.clone()),
+                        ValueType::Delete | ValueType::SingleDelete => MemTableValue::Delete,
+                        ValueType::Merge => MemTableValue::Merge(v.clone()),
+                    };
+                    (k.seq_num, value)
+                })
+        };
+        let covering = self
+            .range_tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|t| t.start.as_slice() <= key && key < t.end.as_slice())
+            .map(|t| t.seq_num)
+            .filter(|s| *s <= seq_num)
+            .max();
+        match (found, covering) {
+            (Some((found_seq, _)), Some(covering)) if found_seq < covering => {
+                Some((covering, MemTableValue::Delete))
+            }
+            (None, Some(covering)) => Some((covering, MemTableValue::Delete)),
+            (found, _) => found,
+        }
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.approximate_size.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.read().unwrap().is_empty() && self.range_tombstones.read().unwrap().is_empty()
+    }
+
+    // Every version of every key, in internal key order
+    pub fn entries(&self) -> Vec<(InternalKey, Vec<u8>)> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    // First entry at or after the key, or right after it when exclusive
+    pub fn seek(&self, from: Bound<&InternalKey>) -> Option<(InternalKey, Vec<u8>)> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .range((from, Bound::Unbounded))
+            .next()
+            .map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    // Last entry before the key, or the last entry without one
+    pub fn last_before(&self, key: Option<&InternalKey>) -> Option<(InternalKey, Vec<u8>)> {
+        let entries = self.entries.read().unwrap();
+        let to = key.map_or(Bound::Unbounded, Bound::Excluded);
+        entries
+            .range((Bound::Unbounded, to))
+            .next_back()
+            .map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_latest_version() {
+        let memtable = MemTable::new();
+        memtable.insert(b"foo".to_vec(), 1, MemTableValue::Set(b"bar1".to_vec()));
+        memtable.insert(b"foo".to_vec(), 3, MemTableValue::Set(b"bar3".to_vec()));
+        memtable.insert(b"foo".to_vec(), 2, MemTableValue::Set(b"bar2".to_vec()));
+        memtable.insert(b"fo".to_vec(), 4, MemTableValue::Set(b"baz".to_vec()));
+
+        assert_eq!(
+            memtable.get(b"foo"),
+            Some(MemTableValue::Set(b"bar3".to_vec()))
+        );
+        assert_eq!(
+            memtable.get(b"fo"),
+            Some(MemTableValue::Set(b"baz".to_vec()))
+        );
+        assert_eq!(memtable.get(b"f"), None);
+        assert_eq!(memtable.get(b"fooo"), None);
+
+        // Older versions stay visible at their sequence numbers
+        assert_eq!(
+            memtable.get_at(b"foo", 2),
+            Some((2, MemTableValue::Set(b"bar2".to_vec())))
+        );
+        assert_eq!(memtable.get_at(b"foo", 0), None);
+    }
+
+    #[test]
+    fn apply_batch() {
+        let memtable = MemTable::new();
+        let entries = [
+            WalEntry::Set {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            },
+            WalEntry::Set {
+                key: b"baz".to_vec(),
+                value: b"qux".to_vec(),
+            },
+            WalEntry::Delete {
+                key: b"foo".to_vec(),
+            },
+        ];
+        for (i, entry) in entries.into_iter().enumerate() {
+            memtable.apply(10 + i as u64, entry);
+        }
+
+        assert_eq!(memtable.get(b"foo"), Some(MemTableValue::Delete));
+        assert_eq!(
+            memtable.get(b"baz"),
+            Some(MemTableValue::Set(b"qux".to_vec()))
+        );
+    }
+
+    #[test]
+    fn range_tombstone_hides_older() {
+        let memtable = MemTable::new();
+        memtable.insert(b"b".to_vec(), 1, MemTableValue::Set(b"old".to_vec()));
+        memtable.insert(b"d".to_vec(), 1, MemTableValue::Set(b"end".to_vec()));
+        memtable.insert_range_tombstone(b"a".to_vec(), b"d".to_vec(), 2);
+        memtable.insert(b"c".to_vec(), 3, MemTableValue::Set(b"new".to_vec()));
+
+        assert_eq!(memtable.get_at(b"b", 3), Some((2, MemTableValue::Delete)));
+        assert_eq!(
+            memtable.get_at(b"b", 1),
+            Some((1, MemTableValue::Set(b"old".to_vec())))
+        );
+        assert_eq!(memtable.get(b"a"), Some(MemTableValue::Delete));
+        assert_eq!(
+            memtable.get(b"c"),
+            Some(MemTableValue::Set(b"new".to_vec()))
+        );
+        assert_eq!(
+            memtable.get(b"d"),
+            Some(MemTableValue::Set(b"end".to_vec()))
+        );
+    }
+}
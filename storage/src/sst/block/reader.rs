@@ -1,19 +1,22 @@
 use std::{
+    cmp::Ordering,
     io::{Cursor, Read, Result, Seek, SeekFrom},
     mem::size_of,
 };
 use tracing::{instrument, span, Level};
 
+use crate::key::comparator::Comparator;
 use crate::utils::{fixedint::read_u32, varint::read::read_varint};
 
 #[derive(Debug)]
 pub struct SstBlockReader {
     block: Vec<u8>,
     restarts: Vec<u32>,
+    comparator: Comparator,
 }
 
 impl SstBlockReader {
-    pub fn new(block: Vec<u8>) -> Result<Self> {
+    pub fn new(block: Vec<u8>, comparator: Comparator) -> Result<Self> {
         let block_len = block.len();
         let mut cursor = Cursor::new(block);
         cursor.seek(SeekFrom::End(-(size_of::<u32>() as i64)))?;
@@ -26,7 +29,11 @@ impl SstBlockReader {
             restarts.push(read_u32(&mut cursor)?);
         }
         let block = cursor.into_inner();
-        Ok(Self { block, restarts })
+        Ok(Self {
+            block,
+            restarts,
+            comparator,
+        })
     }
 
     #[instrument]
@@ -43,10 +50,21 @@ impl SstBlockReader {
     pub fn iter<'a>(&'a self) -> SstBlockIterator<'a> {
         SstBlockIterator::new(self)
     }
+
+    // Iterator right after the last entry at or before `target`, for going
+    // backwards with prev
+    pub fn seek_for_prev<'a>(&'a self, target: &'a [u8]) -> SstBlockIterator<'a> {
+        SstBlockIterator::new_for_prev(self, target)
+    }
+
+    pub fn iter_at_end<'a>(&'a self) -> SstBlockIterator<'a> {
+        SstBlockIterator::new_at_end(self)
+    }
 }
 
 pub struct SstBlockIterator<'a> {
     slice: &'a [u8],
+    restarts: &'a [u32],
     cursor: Cursor<&'a [u8]>,
     key: Vec<u8>,
     block_len: usize,
@@ -76,7 +94,7 @@ impl<'a> SstBlockIterator<'a> {
             debug_assert!(shared == 0);
             let mut restart_key = vec![9; non_shared];
             cursor.read_exact(&mut restart_key[0..non_shared]).unwrap();
-            if restart_key.as_slice() <= from {
+            if (reader.comparator)(&restart_key, from) != Ordering::Greater {
                 left = mid;
             } else {
                 right = mid;
@@ -106,7 +124,7 @@ impl<'a> SstBlockIterator<'a> {
                     .read_exact(&mut next_key[shared..(shared + non_shared)])
                     .unwrap();
 
-                if next_key.as_slice() >= from {
+                if (reader.comparator)(&next_key, from) != Ordering::Less {
                     // Backtrack
                     cursor.seek(SeekFrom::Start(pos)).unwrap();
                     break;
@@ -119,18 +137,36 @@ impl<'a> SstBlockIterator<'a> {
 
         Self {
             slice,
+            restarts: &reader.restarts,
             cursor,
             key,
             block_len,
         }
     }
 
+    pub fn new_for_prev(reader: &'a SstBlockReader, target: &'a [u8]) -> Self {
+        let mut iter = Self::new_from(reader, target);
+        let position = iter.cursor.position();
+        match iter.next() {
+            Some((key, _)) if (reader.comparator)(&key, target) == Ordering::Equal => {}
+            _ => iter.cursor.set_position(position),
+        }
+        iter
+    }
+
+    pub fn new_at_end(reader: &'a SstBlockReader) -> Self {
+        let mut iter = Self::new(reader);
+        iter.cursor.set_position(iter.block_len as u64);
+        iter
+    }
+
     pub fn new(reader: &'a SstBlockReader) -> Self {
         let block_len = reader.block.len() - size_of::<u32>() * reader.restarts.len();
         let slice = &reader.block[0..block_len];
         let cursor = Cursor::new(slice);
         Self {
             slice,
+            restarts: &reader.restarts,
             cursor,
             key: Vec::new(),
             block_len,
@@ -138,6 +174,30 @@ impl<'a> SstBlockIterator<'a> {
     }
 }
 
+impl<'a> SstBlockIterator<'a> {
+    // Entry before the position, which moves back onto it so that next
+    // returns it again. Entries are decoded forward from the last restart
+    // point before the position since keys only store what they do not share
+    // with the previous one.
+    pub fn prev(&mut self) -> Option<(Vec<u8>, &'a [u8])> {
+        let end = self.cursor.position();
+        if end == 0 {
+            return None;
+        }
+        let restart = self.restarts.partition_point(|r| (*r as u64) < end) - 1;
+        self.cursor.set_position(self.restarts[restart] as u64);
+        self.key.clear();
+        loop {
+            let start = self.cursor.position();
+            let entry = self.next()?;
+            if self.cursor.position() >= end {
+                self.cursor.set_position(start);
+                return Some(entry);
+            }
+        }
+    }
+}
+
 impl<'a> Iterator for SstBlockIterator<'a> {
     type Item = (Vec<u8>, &'a [u8]);
 
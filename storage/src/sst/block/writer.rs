@@ -1,7 +1,8 @@
-use std::{io::Result, mem::size_of};
+use std::{cmp::Ordering, io::Result, mem::size_of};
 
 use crate::{
     db::options::DbOptions,
+    key::comparator::Comparator,
     utils::{
         fixedint::write_u32,
         varint::{len::len_varint, write::write_varint},
@@ -16,10 +17,11 @@ pub struct SstBlockWriter {
     counter: usize,
     first_key: Option<Vec<u8>>,
     last_key: Vec<u8>,
+    comparator: Comparator,
 }
 
 impl SstBlockWriter {
-    pub fn new(restart_every: usize) -> Self {
+    pub fn new(restart_every: usize, comparator: Comparator) -> Self {
         let restarts = vec![0];
         let estimate = size_of::<u32>() + size_of::<u32>();
         Self {
@@ -30,6 +32,7 @@ impl SstBlockWriter {
             counter: 0,
             first_key: None,
             last_key: Vec::new(),
+            comparator,
         }
     }
 
@@ -54,7 +57,9 @@ impl SstBlockWriter {
 
     pub fn append(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
         debug_assert!(self.counter <= self.restart_every);
-        debug_assert!(key > self.last_key.as_slice());
+        debug_assert!(
+            self.first_key.is_none() || (self.comparator)(key, &self.last_key) == Ordering::Greater
+        );
 
         if self.first_key.is_none() {
             self.first_key = Some(key.to_vec());
@@ -75,7 +80,7 @@ impl SstBlockWriter {
 
         write_varint(shared, &mut self.buffer)?;
         write_varint(non_shared, &mut self.buffer)?;
-        write_varint(key.len(), &mut self.buffer)?;
+        write_varint(value.len(), &mut self.buffer)?;
 
         self.buffer.extend_from_slice(&key[shared..]);
         self.buffer.extend_from_slice(&value);
@@ -115,6 +120,7 @@ fn slice_shared_offset(left: &[u8], right: &[u8]) -> usize {
 #[cfg(test)]
 mod tests {
 
+    use crate::key::comparator::bytewise_compare;
     use crate::sst::block::reader;
 
     use super::*;
@@ -129,14 +135,14 @@ mod tests {
 
     #[test]
     fn read_none() {
-        let mut writer = SstBlockWriter::new(16);
+        let mut writer = SstBlockWriter::new(16, bytewise_compare);
         writer.append(b"hello0", b"world0").unwrap();
         writer.append(b"hello1", b"world1").unwrap();
         writer.append(b"hello2", b"world2").unwrap();
 
         let (_, block) = writer.finalize().unwrap();
 
-        let reader = reader::SstBlockReader::new(block).unwrap();
+        let reader = reader::SstBlockReader::new(block, bytewise_compare).unwrap();
 
         assert_eq!(reader.get(b"test"), None);
         assert_eq!(reader.get(b"abc"), None);
@@ -144,14 +150,14 @@ mod tests {
 
     #[test]
     fn read_write() {
-        let mut writer = SstBlockWriter::new(16);
+        let mut writer = SstBlockWriter::new(16, bytewise_compare);
         writer.append(b"hello0", b"world0").unwrap();
         writer.append(b"hello1", b"world1").unwrap();
         writer.append(b"hello2", b"world2").unwrap();
 
         let (_, block) = writer.finalize().unwrap();
 
-        let reader = reader::SstBlockReader::new(block).unwrap();
+        let reader = reader::SstBlockReader::new(block, bytewise_compare).unwrap();
         let mut iter = reader.iter();
 
         let (key0, value0) = iter.next().unwrap();
@@ -168,4 +174,54 @@ mod tests {
 
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn read_write_values() {
+        let mut writer = SstBlockWriter::new(2, bytewise_compare);
+        writer.append(b"a", b"").unwrap();
+        writer.append(b"b", b"long value").unwrap();
+        writer.append(b"c", b"v").unwrap();
+
+        let (_, block) = writer.finalize().unwrap();
+
+        let reader = reader::SstBlockReader::new(block, bytewise_compare).unwrap();
+        assert_eq!(reader.get(b"a"), Some(b"".to_vec()));
+        assert_eq!(reader.get(b"b"), Some(b"long value".to_vec()));
+        assert_eq!(reader.get(b"c"), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn read_backwards() {
+        // Several entries between restart points, sharing key prefixes
+        let mut writer = SstBlockWriter::new(4, bytewise_compare);
+        for i in 0..50 {
+            let key = format!("hello{:0>2}", i * 2);
+            writer.append(key.as_bytes(), &[i as u8]).unwrap();
+        }
+        let (_, block) = writer.finalize().unwrap();
+        let reader = reader::SstBlockReader::new(block, bytewise_compare).unwrap();
+
+        let mut iter = reader.iter_at_end();
+        for i in (0..50).rev() {
+            let (key, value) = iter.prev().unwrap();
+            assert_eq!(key, format!("hello{:0>2}", i * 2).as_bytes());
+            assert_eq!(value, &[i as u8]);
+        }
+        assert_eq!(iter.prev(), None);
+
+        // Moving back and forth returns the same entries
+        let mut iter = reader.seek_for_prev(b"hello42");
+        assert_eq!(iter.prev().unwrap().0, b"hello42");
+        assert_eq!(iter.prev().unwrap().0, b"hello40");
+        assert_eq!(iter.next().unwrap().0, b"hello40");
+        assert_eq!(iter.next().unwrap().0, b"hello42");
+        assert_eq!(iter.next().unwrap().0, b"hello44");
+
+        let mut iter = reader.seek_for_prev(b"hello43");
+        assert_eq!(iter.prev().unwrap().0, b"hello42");
+        let mut iter = reader.seek_for_prev(b"zzz");
+        assert_eq!(iter.prev().unwrap().0, b"hello98");
+        let mut iter = reader.seek_for_prev(b"a");
+        assert_eq!(iter.prev(), None);
+    }
 }
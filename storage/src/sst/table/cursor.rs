@@ -0,0 +1,208 @@
+use std::cmp::Ordering;
+use std::io::Result;
+use std::sync::Arc;
+
+use tokio::fs::File;
+
+use crate::key::internal_key::compare;
+use crate::sst::block::reader::SstBlockReader;
+
+use super::table::SstTable;
+
+// Walks the entries of a table in internal key order, holding one decoded
+// data block at a time
+pub struct SstTableCursor {
+    table: Arc<SstTable>,
+    file: Option<File>,
+    block: usize,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    position: usize,
+}
+
+impl SstTableCursor {
+    pub fn new(table: Arc<SstTable>) -> Self {
+        Self {
+            table,
+            file: None,
+            block: 0,
+            entries: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn current(&self) -> Option<(&[u8], &[u8])> {
+        self.entries
+            .get(self.position)
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+    }
+
+    async fn reader(&mut self, block: usize) -> Result<SstBlockReader> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(File::open(self.table.path()).await?),
+        };
+        self.table.read_block(file, block).await
+    }
+
+    async fn read_block(&mut self, block: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let reader = self.reader(block).await?;
+        Ok(reader.iter().map(|(k, v)| (k, v.to_vec())).collect())
+    }
+
+    async fn load(&mut self, block: usize) -> Result<()> {
+        self.entries = self.read_block(block).await?;
+        self.block = block;
+        self.position = 0;
+        Ok(())
+    }
+
+    // Moves on to the next block while past the end of the current one
+    async fn skip_exhausted(&mut self) -> Result<()> {
+        while self.position >= self.entries.len() && self.block + 1 < self.table.block_count() {
+            self.load(self.block + 1).await?;
+        }
+        Ok(())
+    }
+
+    // Positions on the first entry at or after the encoded key, or on the
+    // first entry without one
+    pub async fn seek(&mut self, target: Option<&[u8]>) -> Result<()> {
+        self.entries.clear();
+        self.position = 0;
+        if self.table.block_count() == 0 {
+            return Ok(());
+        }
+        let block = target.map_or(0, |target| {
+            self.table.blocks_until(target).saturating_sub(1)
+        });
+        self.load(block).await?;
+        if let Some(target) = target {
+            self.position = self
+                .entries
+                .partition_point(|(key, _)| compare(key, target) == Ordering::Less);
+        }
+        self.skip_exhausted().await
+    }
+
+    pub async fn next(&mut self) -> Result<()> {
+        if self.position < self.entries.len() {
+            self.position += 1;
+            self.skip_exhausted().await?;
+        }
+        Ok(())
+    }
+
+    // Steps back one entry, moving on to the previous blocks while at the
+    // start of the current one. Going back past the first entry leaves the
+    // cursor on nothing.
+    async fn step_back(&mut self) -> Result<()> {
+        while self.position == 0 {
+            if self.block == 0 {
+                self.entries.clear();
+                return Ok(());
+            }
+            self.load(self.block - 1).await?;
+            self.position = self.entries.len();
+        }
+        self.position -= 1;
+        Ok(())
+    }
+
+    // Positions on the last entry before the encoded key, or on the last
+    // entry without one
+    pub async fn seek_for_prev(&mut self, target: Option<&[u8]>) -> Result<()> {
+        self.entries.clear();
+        self.position = 0;
+        let blocks = match target {
+            Some(target) => self.table.blocks_before(target),
+            None => self.table.block_count(),
+        };
+        if blocks == 0 {
+            return Ok(());
+        }
+        // The block starts before the target so it holds the entry
+        self.load(blocks - 1).await?;
+        self.position = match target {
+            Some(target) => self
+                .entries
+                .partition_point(|(key, _)| compare(key, target) == Ordering::Less),
+            None => self.entries.len(),
+        };
+        self.step_back().await
+    }
+
+    pub async fn prev(&mut self) -> Result<()> {
+        if self.position < self.entries.len() {
+            self.step_back().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::options::DbOptions;
+    use crate::key::internal_key::{lookup_key, InternalKey, ValueType, MAX_SEQ_NUM};
+    use crate::sst::table::writer::SstTableWriter;
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn seek_across_blocks() {
+        let file_path = NamedTempFile::new().unwrap();
+        let key =
+            |i: usize| InternalKey::new(format!("foo{:0>3}", i).into_bytes(), 1, ValueType::Set);
+        let mut writer = SstTableWriter::new(file_path.path(), 500, DbOptions::default())
+            .await
+            .unwrap();
+        for i in 0..500 {
+            writer.add(&key(i * 2).encode(), b"bar").await.unwrap();
+        }
+        let table = Arc::new(writer.finish().await.unwrap());
+        assert!(table.block_count() > 1);
+
+        let mut cursor = SstTableCursor::new(table);
+        cursor.seek(None).await.unwrap();
+        let mut count = 0;
+        while let Some((found, value)) = cursor.current() {
+            assert_eq!(found, key(count * 2).encode());
+            assert_eq!(value, b"bar");
+            cursor.next().await.unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 500);
+
+        cursor
+            .seek(Some(&lookup_key(b"foo501", MAX_SEQ_NUM)))
+            .await
+            .unwrap();
+        assert_eq!(cursor.current().unwrap().0, key(502).encode());
+        cursor
+            .seek(Some(&lookup_key(b"foo999", MAX_SEQ_NUM)))
+            .await
+            .unwrap();
+        assert_eq!(cursor.current(), None);
+
+        let before = |target: &[u8]| lookup_key(target, MAX_SEQ_NUM);
+        cursor
+            .seek_for_prev(Some(&before(b"foo502")))
+            .await
+            .unwrap();
+        assert_eq!(cursor.current().unwrap().0, key(500).encode());
+        cursor
+            .seek_for_prev(Some(&before(b"foo000")))
+            .await
+            .unwrap();
+        assert_eq!(cursor.current(), None);
+
+        // Back across blocks down to the first entry
+        cursor.seek_for_prev(None).await.unwrap();
+        let mut count = 500;
+        while let Some((found, _)) = cursor.current() {
+            count -= 1;
+            assert_eq!(found, key(count * 2).encode());
+            cursor.prev().await.unwrap();
+        }
+        assert_eq!(count, 0);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod cursor;
 pub mod reader;
 pub mod stats;
 pub mod table;
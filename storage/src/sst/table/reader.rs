@@ -3,6 +3,9 @@ use tokio::{
     io::{AsyncSeekExt, BufReader},
 };
 
+use crate::key::comparator::bytewise_compare;
+use crate::key::internal_key::compare;
+use crate::key::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
 use crate::sst::{
     block::{
         handle::{block_from_handle, SstBlockHandle},
@@ -10,6 +13,9 @@ use crate::sst::{
     },
     filter::SstFilter,
 };
+use crate::utils::fixedint::read_u64;
+use crate::utils::string::read_bytes;
+use crate::utils::varint::read::read_varint;
 
 use super::table::SstTable;
 use std::{
@@ -30,7 +36,7 @@ pub async fn sst_table_writer_new<P: AsRef<Path>>(path: P) -> Result<SstTable> {
     let meta_handle = SstBlockHandle::async_read_from(&mut file_reader).await?;
 
     let meta_block = block_from_handle(&mut file_reader, &meta_handle).await?;
-    let meta_reader = SstBlockReader::new(meta_block)?;
+    let meta_reader = SstBlockReader::new(meta_block, bytewise_compare)?;
     let mut meta_index: BTreeMap<String, Cursor<&[u8]>> = meta_reader
         .iter()
         .map(|(key, value)| (String::from_utf8(key.to_vec()).unwrap(), Cursor::new(value)))
@@ -40,17 +46,40 @@ pub async fn sst_table_writer_new<P: AsRef<Path>>(path: P) -> Result<SstTable> {
     let filter_block = block_from_handle(&mut file_reader, &filter_handle).await?;
     let fiter = SstFilter::from_data(&filter_block, 7);
 
-    let index_handle = SstBlockHandle::read_from(meta_index.get_mut("index").unwrap())?;
-    let index_block = block_from_handle(&mut file_reader, &index_handle).await?;
-    let index: Vec<(Vec<u8>, SstBlockHandle)> = SstBlockReader::new(index_block)?
-        .iter()
-        .map(|(k, v)| {
-            let handle = SstBlockHandle::read_from(&mut Cursor::new(v)).unwrap();
-            (k.to_vec(), handle)
-        })
-        .collect();
+    let mut index: Vec<(Vec<u8>, SstBlockHandle)> = Vec::new();
+    if let Some(index_cursor) = meta_index.get_mut("index") {
+        let index_handle = SstBlockHandle::read_from(index_cursor)?;
+        let index_block = block_from_handle(&mut file_reader, &index_handle).await?;
+        index = SstBlockReader::new(index_block, compare)?
+            .iter()
+            .map(|(k, v)| {
+                let handle = SstBlockHandle::read_from(&mut Cursor::new(v)).unwrap();
+                (k.to_vec(), handle)
+            })
+            .collect();
+    }
+
+    let mut range_tombstones = Vec::new();
+    if let Some(range_del_cursor) = meta_index.get_mut("range_del") {
+        let range_del_handle = SstBlockHandle::read_from(range_del_cursor)?;
+        let range_del_block = block_from_handle(&mut file_reader, &range_del_handle).await?;
+        for (start, value) in SstBlockReader::new(range_del_block, bytewise_compare)?.iter() {
+            let mut cursor = Cursor::new(value);
+            let end = read_bytes(&mut cursor)?;
+            let count: usize = read_varint(&mut cursor)?;
+            for _ in 0..count {
+                let seq_num = read_u64(&mut cursor)?;
+                range_tombstones.push(RangeTombstone::new(start.to_vec(), end.clone(), seq_num));
+            }
+        }
+    }
 
-    let table = SstTable::new(path.as_ref(), fiter, index);
+    let table = SstTable::new(
+        path.as_ref(),
+        fiter,
+        index,
+        FragmentedRangeTombstones::new(&range_tombstones),
+    );
 
     Ok(table)
 }
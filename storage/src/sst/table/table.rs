@@ -1,10 +1,14 @@
-use std::io::{Result, SeekFrom};
+use std::cmp;
+use std::io::Result;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio_stream::Stream;
-use tracing::{debug, event, info, instrument, Level};
+use tracing::{debug, event, info, instrument, warn, Level};
+
+use crate::key::internal_key::{compare, lookup_key, InternalKey, ValueType};
+use crate::key::range_tombstone::FragmentedRangeTombstones;
 
 use crate::sst::block::handle::{block_from_handle, SstBlockHandle};
 use crate::sst::block::reader::SstBlockReader;
@@ -17,6 +21,10 @@ pub struct SstTable {
     path: PathBuf,
     filter: SstFilter,
     index: Vec<(Vec<u8>, SstBlockHandle)>,
+    range_tombstones: FragmentedRangeTombstones,
+    // Set once the table is no longer part of the database, the file is then
+    // deleted when the last reader lets go of it.
+    obsolete: AtomicBool,
 }
 
 impl SstTable {
@@ -24,55 +32,117 @@ impl SstTable {
         path: P,
         filter: SstFilter,
         index: Vec<(Vec<u8>, SstBlockHandle)>,
+        range_tombstones: FragmentedRangeTombstones,
     ) -> Self {
         Self {
             path: path.into(),
             filter,
             index,
+            range_tombstones,
+            obsolete: AtomicBool::new(false),
         }
     }
 
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::Relaxed);
+    }
+
+    pub fn range_tombstones(&self) -> &FragmentedRangeTombstones {
+        &self.range_tombstones
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.index.len()
+    }
+
+    // Number of data blocks starting at or before the encoded key, the first
+    // entry at or after it is in the last of them or starts the next one
+    pub fn blocks_until(&self, key: &[u8]) -> usize {
+        self.index
+            .partition_point(|(k, _)| compare(k, key) != cmp::Ordering::Greater)
+    }
+
+    // Number of data blocks starting before the encoded key
+    pub fn blocks_before(&self, key: &[u8]) -> usize {
+        self.index
+            .partition_point(|(k, _)| compare(k, key) == cmp::Ordering::Less)
+    }
+
+    pub async fn read_block(&self, file: &mut File, block: usize) -> Result<SstBlockReader> {
+        let data = block_from_handle(file, &self.index[block].1).await?;
+        SstBlockReader::new(data, compare)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    // Newest version of the user key visible at `seq_num`. A range tombstone
+    // of the file written after that version reads as a delete.
     #[instrument]
-    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        debug!(key = String::from_utf8(key.to_vec()).unwrap());
+    pub async fn get(&self, key: &[u8], seq_num: u64) -> Result<Option<(InternalKey, Vec<u8>)>> {
+        let found = self.get_point(key, seq_num).await?;
+        let covering = self.range_tombstones.covering(key, seq_num);
+        match (found, covering) {
+            (Some((found, _)), Some(covering)) if found.seq_num < covering => Ok(Some((
+                InternalKey::new(key.to_vec(), covering, ValueType::Delete),
+                Vec::new(),
+            ))),
+            (None, Some(covering)) => Ok(Some((
+                InternalKey::new(key.to_vec(), covering, ValueType::Delete),
+                Vec::new(),
+            ))),
+            (found, _) => Ok(found),
+        }
+    }
+
+    async fn get_point(&self, key: &[u8], seq_num: u64) -> Result<Option<(InternalKey, Vec<u8>)>> {
+        debug!(key = %String::from_utf8_lossy(key));
         if !self.filter.may_contain(key) {
             return Ok(None);
         }
-        for (k, _) in &self.index {
-            event!(
-                Level::DEBUG,
-                "index: {}",
-                String::from_utf8(k.to_vec()).unwrap()
-            );
-        }
-        let partition_point = self.index.partition_point(|(k, _)| k.as_slice() <= key);
+        let lookup = lookup_key(key, seq_num);
+        // The first entry at or after the lookup key is either in the last
+        // block starting before it, or starts the next one.
+        let partition_point = self
+            .index
+            .partition_point(|(k, _)| compare(k, &lookup) != cmp::Ordering::Greater);
         event!(Level::DEBUG, "partition_point: {}", partition_point);
-        match partition_point {
-            0 => Ok(None),
-            i => {
-                let (_, handle) = &self.index[i - 1];
-                let mut block = vec![0; handle.size as usize];
-                let mut file = File::open(&self.path).await?;
-                file.seek(SeekFrom::Start(handle.offset)).await?;
-                file.read_exact(&mut block).await?;
-                let reader = SstBlockReader::new(block)?;
-                Ok(reader.get(key))
+        let mut file = File::open(&self.path).await?;
+        for (_, handle) in self
+            .index
+            .iter()
+            .skip(partition_point.saturating_sub(1))
+            .take(2)
+        {
+            let block = block_from_handle(&mut file, handle).await?;
+            let reader = SstBlockReader::new(block, compare)?;
+            if let Some((found, value)) = reader.iter_from(&lookup).next() {
+                let found = InternalKey::decode(&found)?;
+                if found.user_key != key {
+                    return Ok(None);
+                }
+                return Ok(Some((found, value.to_vec())));
             }
         }
+        Ok(None)
     }
 
     #[instrument]
     pub async fn iter_from<'a>(
         &'a self,
         from: &'a [u8],
-    ) -> impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+    ) -> impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
         let mut file = File::open(&self.path).await.unwrap();
-        let partitioned = self.index.partition_point(|(k, _)| k.as_slice() < from) - 1;
+        let partitioned = self
+            .index
+            .partition_point(|(k, _)| compare(k, from) == cmp::Ordering::Less)
+            .saturating_sub(1);
         debug!("partitioned: {}", partitioned);
         try_stream! {
             for (_, handle) in &self.index[partitioned..] {
                 let block = block_from_handle(&mut file, handle).await?;
-                let reader = SstBlockReader::new(block)?;
+                let reader = SstBlockReader::new(block, compare)?;
                 for (key, value) in reader.iter_from(from) {
                     yield (key, value.to_vec())
                 }
@@ -86,18 +156,71 @@ impl SstTable {
         try_stream! {
             for (_, handle) in &self.index {
                 let block = block_from_handle(&mut file, handle).await?;
-                let reader = SstBlockReader::new(block)?;
+                let reader = SstBlockReader::new(block, compare)?;
                 for (key, value) in reader.iter() {
                     yield (key, value.to_vec())
                 }
             }
         }
     }
+
+    // Entries at or before the encoded key, last first
+    #[instrument]
+    pub async fn iter_rev_from<'a>(
+        &'a self,
+        to: &'a [u8],
+    ) -> impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        let mut file = File::open(&self.path).await.unwrap();
+        let blocks = self.blocks_until(to);
+        debug!("blocks: {}", blocks);
+        try_stream! {
+            for (i, (_, handle)) in self.index[..blocks].iter().enumerate().rev() {
+                let block = block_from_handle(&mut file, handle).await?;
+                let reader = SstBlockReader::new(block, compare)?;
+                // Only the last block holds entries after the key
+                let mut iter = if i + 1 == blocks {
+                    reader.seek_for_prev(to)
+                } else {
+                    reader.iter_at_end()
+                };
+                while let Some((key, value)) = iter.prev() {
+                    yield (key, value.to_vec())
+                }
+            }
+        }
+    }
+
+    #[instrument]
+    pub async fn iter_rev(&self) -> impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        let mut file = File::open(&self.path).await.unwrap();
+        try_stream! {
+            for (_, handle) in self.index.iter().rev() {
+                let block = block_from_handle(&mut file, handle).await?;
+                let reader = SstBlockReader::new(block, compare)?;
+                let mut iter = reader.iter_at_end();
+                while let Some((key, value)) = iter.prev() {
+                    yield (key, value.to_vec())
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SstTable {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::Relaxed) {
+            info!("Deleting {}", self.path.display());
+            if let Err(err) = std::fs::remove_file(&self.path) {
+                warn!("Failed to delete {}: {}", self.path.display(), err);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use crate::key::internal_key::{ValueType, MAX_SEQ_NUM};
     use crate::utils::tracing::init_tracer;
     use crate::{db::options::DbOptions, sst::table::writer::SstTableWriter};
 
@@ -120,7 +243,8 @@ mod tests {
         let mut writer = SstTableWriter::new(file_path, count, options).await?;
         for i in 0..count {
             let key = format!("foo{:0>count_digit$}", i);
-            writer.add(key.as_bytes(), key.as_bytes()).await?;
+            let internal_key = InternalKey::new(key.clone().into_bytes(), i as u64, ValueType::Set);
+            writer.add(&internal_key.encode(), key.as_bytes()).await?;
         }
 
         let res = writer.finish().await?;
@@ -139,24 +263,25 @@ mod tests {
                 .await
                 .unwrap();
 
-            let res = table.get(b"foo382").await.unwrap();
+            let get = |key: &'static [u8]| async {
+                let found = table.get(key, MAX_SEQ_NUM).await.unwrap();
+                found.map(|(key, value)| (key.value_type, value))
+            };
+            let res = get(b"foo382").await;
 
-            assert!(res.is_some());
-            assert_eq!(res.unwrap(), b"foo382");
+            assert_eq!(res, Some((ValueType::Set, b"foo382".to_vec())));
 
-            let res2 = table.get(b"foo383").await.unwrap();
+            let res2 = get(b"foo383").await;
 
-            assert!(res2.is_some());
-            assert_eq!(res2.unwrap(), b"foo383");
+            assert_eq!(res2, Some((ValueType::Set, b"foo383".to_vec())));
 
-            let res3 = table.get(b"foo384").await.unwrap();
-            assert!(res3.is_some());
-            assert_eq!(res3.unwrap(), b"foo384");
+            let res3 = get(b"foo384").await;
+            assert_eq!(res3, Some((ValueType::Set, b"foo384".to_vec())));
 
-            let res4 = table.get(b"abc").await.unwrap();
+            let res4 = table.get(b"abc", MAX_SEQ_NUM).await.unwrap();
             assert!(res4.is_none());
 
-            let res2 = table.get(b"bar").await.unwrap();
+            let res2 = table.get(b"bar", MAX_SEQ_NUM).await.unwrap();
             assert!(res2.is_none());
         }
         .instrument(span)
@@ -180,7 +305,7 @@ mod tests {
             pin_mut!(iter);
             while let Some(Ok((key, value))) = iter.next().await {
                 let test = format!("foo{:0>4}", i);
-                assert_eq!(key, test.as_bytes());
+                assert_eq!(InternalKey::decode(&key).unwrap().user_key, test.as_bytes());
                 assert_eq!(value, test.as_bytes());
                 i += 1;
             }
@@ -207,11 +332,13 @@ mod tests {
                 .await
                 .unwrap();
 
-            let iter = table.iter_from(b"foo567").await;
+            let from = lookup_key(b"foo567", MAX_SEQ_NUM);
+            let iter = table.iter_from(&from).await;
             let mut i = 567;
             pin_mut!(iter);
             while let Some(Ok((key, value))) = iter.next().await {
                 let test = format!("foo{:0>3}", i);
+                let key = InternalKey::decode(&key).unwrap().user_key;
                 assert_eq!(String::from_utf8(key).unwrap(), test);
                 assert_eq!(String::from_utf8(value).unwrap(), test);
                 i += 1;
@@ -221,4 +348,108 @@ mod tests {
         .instrument(span)
         .await;
     }
+
+    #[tokio::test]
+    async fn iter_rev_write() {
+        init_tracer();
+
+        let span = info_span!("iter_rev_write");
+        async move {
+            let file_path = NamedTempFile::new().unwrap();
+            let table_size = 1000;
+            let options = DbOptions {
+                sst_block_size: 4096,
+                ..Default::default()
+            };
+
+            let table = filled_table(file_path.path(), table_size, options)
+                .await
+                .unwrap();
+            assert!(table.index.len() > 2);
+
+            let iter = table.iter_rev().await;
+            let mut i = table_size;
+            pin_mut!(iter);
+            while let Some(Ok((key, value))) = iter.next().await {
+                i -= 1;
+                let test = format!("foo{:0>3}", i);
+                assert_eq!(InternalKey::decode(&key).unwrap().user_key, test.as_bytes());
+                assert_eq!(value, test.as_bytes());
+            }
+            assert_eq!(i, 0);
+
+            // Starts from the entry itself when there is one
+            for (to, last) in [(b"foo567", 567), (b"foo999", 999), (b"fop000", 999)] {
+                let to = InternalKey::new(to.to_vec(), 0, ValueType::Delete).encode();
+                let iter = table.iter_rev_from(&to).await;
+                let mut i = last + 1;
+                pin_mut!(iter);
+                while let Some(Ok((key, value))) = iter.next().await {
+                    i -= 1;
+                    let test = format!("foo{:0>3}", i);
+                    let key = InternalKey::decode(&key).unwrap().user_key;
+                    assert_eq!(String::from_utf8(key).unwrap(), test);
+                    assert_eq!(String::from_utf8(value).unwrap(), test);
+                }
+                assert_eq!(i, 0);
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+
+    #[tokio::test]
+    async fn read_versions() {
+        init_tracer();
+
+        let span = info_span!("read_versions");
+        async move {
+            let file_path = NamedTempFile::new().unwrap();
+            let options = DbOptions {
+                sst_block_size: 256,
+                ..Default::default()
+            };
+            // Versions of a key span several blocks
+            let mut writer = SstTableWriter::new(file_path.path(), 202, options)
+                .await
+                .unwrap();
+            let a = InternalKey::new(b"a".to_vec(), 500, ValueType::Set);
+            writer.add(&a.encode(), b"a").await.unwrap();
+            for seq_num in (1..=200).rev() {
+                let value_type = if seq_num % 10 == 0 {
+                    ValueType::Delete
+                } else {
+                    ValueType::Set
+                };
+                let key = InternalKey::new(b"foo".to_vec(), seq_num, value_type);
+                writer
+                    .add(&key.encode(), seq_num.to_string().as_bytes())
+                    .await
+                    .unwrap();
+            }
+            let z = InternalKey::new(b"z".to_vec(), 300, ValueType::Set);
+            writer.add(&z.encode(), b"z").await.unwrap();
+            let table = writer.finish().await.unwrap();
+            assert!(table.index.len() > 2);
+
+            for seq_num in 1..=200 {
+                let (key, value) = table.get(b"foo", seq_num).await.unwrap().unwrap();
+                assert_eq!(key.seq_num, seq_num);
+                assert_eq!(key.value_type == ValueType::Delete, seq_num % 10 == 0);
+                assert_eq!(value, seq_num.to_string().as_bytes());
+            }
+            assert_eq!(
+                table.get(b"foo", MAX_SEQ_NUM).await.unwrap().unwrap().1,
+                b"200"
+            );
+            assert_eq!(table.get(b"foo", 0).await.unwrap(), None);
+            assert_eq!(table.get(b"z", 299).await.unwrap(), None);
+            assert_eq!(
+                table.get(b"z", 300).await.unwrap(),
+                Some((z, b"z".to_vec()))
+            );
+        }
+        .instrument(span)
+        .await;
+    }
 }
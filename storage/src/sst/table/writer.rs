@@ -4,8 +4,13 @@ use std::path::PathBuf;
 use tokio::fs::{create_dir_all, File};
 use tokio::io::{AsyncWriteExt, BufWriter};
 
+use crate::key::comparator::bytewise_compare;
+use crate::key::internal_key::{compare, user_key};
+use crate::key::range_tombstone::{FragmentedRangeTombstones, RangeTombstone};
 use crate::sst::block::handle::SstBlockHandle;
 use crate::utils::fixedint::{read_u32, write_u64};
+use crate::utils::string::write_bytes;
+use crate::utils::varint::write::write_varint;
 use crate::{
     db::options::DbOptions,
     sst::{block::writer::SstBlockWriter, filter::SstFilter},
@@ -22,6 +27,7 @@ pub struct SstTableWriter {
     block_writer: SstBlockWriter,
     filter: SstFilter,
     index: Vec<(Vec<u8>, SstBlockHandle)>,
+    range_tombstones: Vec<RangeTombstone>,
     stats: SstStats,
 }
 
@@ -39,9 +45,11 @@ impl SstTableWriter {
             file_writer: file,
             written_size: 0,
             db_options: db_options.clone(),
-            block_writer: SstBlockWriter::new(db_options.sst_block_restart_interval),
-            filter: SstFilter::new(item_count, 0.01),
+            block_writer: SstBlockWriter::new(db_options.sst_block_restart_interval, compare),
+            // A file may only hold range tombstones
+            filter: SstFilter::new(item_count.max(1), 0.01),
             index: Vec::new(),
+            range_tombstones: Vec::new(),
             stats: SstStats::default(),
         })
     }
@@ -54,11 +62,15 @@ impl SstTableWriter {
             self._process_block().await?;
         }
         self.block_writer.append(key, value)?;
-        self.filter.add(key);
+        self.filter.add(user_key(key));
 
         Ok(())
     }
 
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.range_tombstones.push(tombstone);
+    }
+
     fn _add_handle(&mut self, block_size: usize) -> SstBlockHandle {
         let offset = self.written_size;
         self.written_size += block_size;
@@ -69,7 +81,10 @@ impl SstTableWriter {
     }
 
     async fn _process_block(&mut self) -> Result<()> {
-        let new_block = SstBlockWriter::new(self.db_options.sst_block_restart_interval);
+        if self.block_writer.is_empty() {
+            return Ok(());
+        }
+        let new_block = SstBlockWriter::new(self.db_options.sst_block_restart_interval, compare);
 
         let prev_block = replace(&mut self.block_writer, new_block);
 
@@ -84,7 +99,7 @@ impl SstTableWriter {
     }
 
     fn _index_to_block(&self) -> Result<Vec<u8>> {
-        let mut block = SstBlockWriter::new(self.db_options.sst_index_restart_interval);
+        let mut block = SstBlockWriter::new(self.db_options.sst_index_restart_interval, compare);
         for (key, handle) in self.index.iter() {
             let handle_value = handle.to_value();
             block.append(key, handle_value.as_slice())?;
@@ -93,6 +108,25 @@ impl SstTableWriter {
         Ok(block)
     }
 
+    // Fragments keyed by start key, each value holding the end key followed
+    // by the sequence numbers covering the fragment, newest first.
+    fn _range_del_to_block(&self, fragmented: &FragmentedRangeTombstones) -> Result<Vec<u8>> {
+        let mut block =
+            SstBlockWriter::new(self.db_options.sst_index_restart_interval, bytewise_compare);
+        let tombstones = fragmented.tombstones();
+        for chunk in tombstones.chunk_by(|a, b| a.start == b.start) {
+            let mut value = Vec::new();
+            write_bytes(&chunk[0].end, &mut value)?;
+            write_varint(chunk.len(), &mut value)?;
+            for tombstone in chunk {
+                write_u64(tombstone.seq_num, &mut value)?;
+            }
+            block.append(&chunk[0].start, &value)?;
+        }
+        let (_, block) = block.finalize()?;
+        Ok(block)
+    }
+
     pub async fn finish(mut self) -> Result<SstTable> {
         self._process_block().await?;
 
@@ -102,16 +136,33 @@ impl SstTableWriter {
         self.file_writer.write_all(filter_block).await?;
         let filter_handle = self._add_handle(filter_block.len());
 
-        // Finish index block
-        let index_block = self._index_to_block()?;
-        self.stats.set_index_size(index_block.len());
-        self.file_writer.write_all(&index_block).await?;
-        let index_handle = self._add_handle(index_block.len());
+        // Finish index block, missing when the file only holds range tombstones
+        let mut index_handle = None;
+        if !self.index.is_empty() {
+            let index_block = self._index_to_block()?;
+            self.stats.set_index_size(index_block.len());
+            self.file_writer.write_all(&index_block).await?;
+            index_handle = Some(self._add_handle(index_block.len()));
+        }
+
+        // Finish range tombstone block
+        let range_tombstones = FragmentedRangeTombstones::new(&self.range_tombstones);
+        let mut range_del_handle = None;
+        if !range_tombstones.is_empty() {
+            let range_del_block = self._range_del_to_block(&range_tombstones)?;
+            self.file_writer.write_all(&range_del_block).await?;
+            range_del_handle = Some(self._add_handle(range_del_block.len()));
+        }
 
         // Finish meta block
-        let mut meta_index = SstBlockWriter::new(usize::MAX);
+        let mut meta_index = SstBlockWriter::new(usize::MAX, bytewise_compare);
         meta_index.append(b"filter", &filter_handle.to_value())?;
-        meta_index.append(b"index", &index_handle.to_value())?;
+        if let Some(index_handle) = index_handle {
+            meta_index.append(b"index", &index_handle.to_value())?;
+        }
+        if let Some(range_del_handle) = range_del_handle {
+            meta_index.append(b"range_del", &range_del_handle.to_value())?;
+        }
         let (_, meta_block) = meta_index.finalize()?;
         self.file_writer.write_all(&meta_block).await?;
         let meta_handle = self._add_handle(meta_block.len());
@@ -124,8 +175,9 @@ impl SstTableWriter {
         self.file_writer.write_all(&footer).await?;
 
         self.file_writer.flush().await?;
+        self.file_writer.get_ref().sync_data().await?;
 
-        let table = SstTable::new(self.file_path, self.filter, self.index);
+        let table = SstTable::new(self.file_path, self.filter, self.index, range_tombstones);
 
         Ok(table)
     }
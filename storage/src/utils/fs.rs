@@ -0,0 +1,8 @@
+use std::io::Result;
+use std::path::Path;
+use tokio::fs::File;
+
+// Makes the creation of new files in a directory durable
+pub async fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path).await?.sync_all().await
+}
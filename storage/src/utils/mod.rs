@@ -1,7 +1,9 @@
 pub mod bitvec;
 pub mod crc32;
 pub mod fixedint;
+pub mod fs;
 pub mod murmur3;
 pub mod string;
+pub mod time;
 pub mod tracing;
 pub mod varint;
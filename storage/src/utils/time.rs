@@ -0,0 +1,22 @@
+use std::future::pending;
+use std::io::{Error, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Interval;
+
+// Seconds since the unix epoch, as recorded in the manifest
+pub fn unix_time() -> Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::other)?
+        .as_secs())
+}
+
+// Waits for the next tick, forever when there is no interval
+pub async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => pending().await,
+    }
+}
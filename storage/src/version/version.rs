@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::db::column_family::DEFAULT_COLUMN_FAMILY;
+use crate::manifest::entry::{ManifestLogEntry, NewFileTag, WalTag};
+
+// Smallest and largest are user keys, a file may hold several versions of each
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetaData {
+    pub file_number: u64,
+    pub file_size: u64,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    pub smallest_seqno: u64,
+    pub largest_seqno: u64,
+    pub creation_time: Option<u64>,
+}
+
+impl FileMetaData {
+    // Manifest entry adding this file to the given level of a column family
+    pub fn new_file_entry(&self, column_family: u32, level: u32) -> ManifestLogEntry {
+        let mut tags: Vec<NewFileTag> = self
+            .creation_time
+            .map(|time| NewFileTag::FileCreationTime { time })
+            .into_iter()
+            .collect();
+        if column_family != DEFAULT_COLUMN_FAMILY {
+            tags.push(NewFileTag::ColumnFamily { column_family });
+        }
+        ManifestLogEntry::NewFile {
+            level,
+            file_number: self.file_number,
+            file_size: self.file_size,
+            smallest: self.smallest.clone(),
+            largest: self.largest.clone(),
+            smallest_seqno: self.smallest_seqno,
+            largest_seqno: self.largest_seqno,
+            tags,
+        }
+    }
+}
+
+// The shape of the database as recorded in the manifest. A version is never
+// modified once built, edits produce a new one.
+#[derive(Debug, Clone, Default)]
+pub struct Version {
+    // Files of each level of each column family, by increasing file number
+    files: BTreeMap<u32, Vec<Vec<Arc<FileMetaData>>>>,
+    // Live column families but the default one, which is always there
+    column_families: BTreeMap<u32, String>,
+    // Ids are never reused, even once their family is dropped
+    max_column_family: u32,
+    // Live logs with the size they were synced up to when closed
+    wals: BTreeMap<u32, Option<u64>>,
+    // Logs before this one only hold writes persisted in sst files
+    log_number: u32,
+    next_file_number: u64,
+    last_sequence: Option<u64>,
+}
+
+impl Version {
+    pub fn num_levels(&self, column_family: u32) -> usize {
+        self.files
+            .get(&column_family)
+            .map_or(0, |files| files.len())
+    }
+
+    pub fn files(&self, column_family: u32, level: usize) -> &[Arc<FileMetaData>] {
+        self.files
+            .get(&column_family)
+            .and_then(|files| files.get(level))
+            .map_or(&[], |files| files.as_slice())
+    }
+
+    pub fn column_families(&self) -> &BTreeMap<u32, String> {
+        &self.column_families
+    }
+
+    pub fn max_column_family(&self) -> u32 {
+        self.max_column_family
+    }
+
+    fn is_live(&self, column_family: u32) -> bool {
+        column_family == DEFAULT_COLUMN_FAMILY || self.column_families.contains_key(&column_family)
+    }
+
+    pub fn wals(&self) -> &BTreeMap<u32, Option<u64>> {
+        &self.wals
+    }
+
+    pub fn log_number(&self) -> u32 {
+        self.log_number
+    }
+
+    pub fn next_file_number(&self) -> u64 {
+        self.next_file_number
+    }
+
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
+
+    pub(super) fn apply(&mut self, entry: &ManifestLogEntry) {
+        match entry {
+            ManifestLogEntry::LogNumber { log_number } => {
+                self.log_number = *log_number as u32;
+            }
+            ManifestLogEntry::NextFileNumber { next_file_number } => {
+                // Flushes and compactions allocate numbers concurrently and
+                // may record them out of order.
+                self.next_file_number = self.next_file_number.max(*next_file_number);
+            }
+            ManifestLogEntry::LastSequence { last_sequence } => {
+                self.last_sequence = Some(*last_sequence);
+            }
+            ManifestLogEntry::NewFile {
+                level,
+                file_number,
+                file_size,
+                smallest,
+                largest,
+                smallest_seqno,
+                largest_seqno,
+                tags,
+            } => {
+                let column_family = tags
+                    .iter()
+                    .find_map(|tag| match tag {
+                        NewFileTag::ColumnFamily { column_family } => Some(*column_family),
+                        _ => None,
+                    })
+                    .unwrap_or(DEFAULT_COLUMN_FAMILY);
+                // Flushed or compacted while its family was dropped
+                if !self.is_live(column_family) {
+                    return;
+                }
+                let level = *level as usize;
+                let files = self.files.entry(column_family).or_default();
+                if files.len() <= level {
+                    files.resize(level + 1, Vec::new());
+                }
+                let creation_time = tags.iter().find_map(|tag| match tag {
+                    NewFileTag::FileCreationTime { time } => Some(*time),
+                    _ => None,
+                });
+                let file = FileMetaData {
+                    file_number: *file_number,
+                    file_size: *file_size,
+                    smallest: smallest.clone(),
+                    largest: largest.clone(),
+                    smallest_seqno: *smallest_seqno,
+                    largest_seqno: *largest_seqno,
+                    creation_time,
+                };
+                let files = &mut files[level];
+                let index = files.partition_point(|f| f.file_number < file.file_number);
+                files.insert(index, Arc::new(file));
+            }
+            // File numbers are unique across column families
+            ManifestLogEntry::DeletedFile { level, file_number } => {
+                for files in self.files.values_mut() {
+                    if let Some(files) = files.get_mut(*level as usize) {
+                        files.retain(|file| file.file_number != *file_number);
+                    }
+                }
+            }
+            ManifestLogEntry::ColumnFamilyAdd {
+                column_family,
+                name,
+            } => {
+                self.column_families.insert(*column_family, name.clone());
+            }
+            ManifestLogEntry::ColumnFamilyDrop { column_family } => {
+                self.column_families.remove(column_family);
+                self.files.remove(column_family);
+            }
+            ManifestLogEntry::MaxColumnFamily { max_column_family } => {
+                self.max_column_family = self.max_column_family.max(*max_column_family);
+            }
+            ManifestLogEntry::WalAddition { log_number, tags } => {
+                let synced_size = tags.iter().find_map(|tag| match tag {
+                    WalTag::SyncedSize { size } => Some(*size),
+                    _ => None,
+                });
+                self.wals.insert(*log_number as u32, synced_size);
+            }
+            ManifestLogEntry::WalDeletion { log_number } => {
+                self.wals.remove(&(*log_number as u32));
+            }
+            ManifestLogEntry::PrevFileNumber { .. }
+            | ManifestLogEntry::InAtomicGroup { .. }
+            | ManifestLogEntry::DbId { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures_util::pin_mut;
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+use crate::manifest::entry::ManifestLogEntry;
+use crate::manifest::reader::iter_from;
+
+use super::version::Version;
+
+// Holds the current version, replacing it as edits are committed to the
+// manifest.
+#[derive(Debug, Default)]
+pub struct VersionSet {
+    current: Arc<Version>,
+}
+
+impl VersionSet {
+    // Replays the manifest into the version it describes, along with the
+    // offset the last committed edit ends at. An atomic group cut short by a
+    // crash was never committed, so it is left out.
+    pub async fn recover(path: PathBuf) -> Result<(Self, u64)> {
+        let mut version = Version::default();
+        let mut group: Option<(usize, Vec<ManifestLogEntry>)> = None;
+        let mut committed = 0;
+        let entries = iter_from(path).await;
+        pin_mut!(entries);
+        while let Some(entry) = entries.next().await {
+            let (entry, end) = entry?;
+            if let ManifestLogEntry::InAtomicGroup { version_edit_count } = entry {
+                // Only the last group can be incomplete, the manifest is
+                // truncated before anything is appended after it
+                if group.is_some() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Atomic group interrupted by another one",
+                    ));
+                }
+                group = Some((version_edit_count as usize, Vec::new()));
+                continue;
+            }
+            match group.as_mut() {
+                Some((count, edits)) => {
+                    edits.push(entry);
+                    if edits.len() == *count {
+                        for edit in edits.iter() {
+                            version.apply(edit);
+                        }
+                        group = None;
+                        committed = end;
+                    }
+                }
+                None => {
+                    version.apply(&entry);
+                    committed = end;
+                }
+            }
+        }
+        if let Some((count, edits)) = group {
+            warn!(
+                "Discarding atomic group with {} of {} edits",
+                edits.len(),
+                count
+            );
+        }
+        let versions = Self {
+            current: Arc::new(version),
+        };
+        Ok((versions, committed))
+    }
+
+    pub fn current(&self) -> Arc<Version> {
+        self.current.clone()
+    }
+
+    // Builds the version following the given committed edits
+    pub fn apply(&mut self, entries: &[ManifestLogEntry]) {
+        let mut version = (*self.current).clone();
+        for entry in entries {
+            version.apply(entry);
+        }
+        self.current = Arc::new(version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::column_family::DEFAULT_COLUMN_FAMILY;
+    use crate::manifest::entry::{NewFileTag, WalTag};
+    use crate::manifest::writer::ManifestWriter;
+    use std::path::Path;
+    use tempfile::tempdir;
+    use tokio::fs::write;
+
+    fn new_file(level: u32, file_number: u64) -> ManifestLogEntry {
+        ManifestLogEntry::NewFile {
+            level,
+            file_number,
+            file_size: 100,
+            smallest: b"a".to_vec(),
+            largest: b"z".to_vec(),
+            smallest_seqno: file_number,
+            largest_seqno: file_number,
+            tags: vec![],
+        }
+    }
+
+    async fn written_manifest(path: &Path, entries: &[ManifestLogEntry]) {
+        write(path.join("CURRENT"), "MANIFEST-0").await.unwrap();
+        let mut writer = ManifestWriter::new(0, path.join("MANIFEST-0"))
+            .await
+            .unwrap();
+        writer.append(entries).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn recover_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        written_manifest(
+            path,
+            &[
+                ManifestLogEntry::WalAddition {
+                    log_number: 0,
+                    tags: vec![],
+                },
+                ManifestLogEntry::InAtomicGroup {
+                    version_edit_count: 2,
+                },
+                ManifestLogEntry::WalAddition {
+                    log_number: 0,
+                    tags: vec![WalTag::SyncedSize { size: 42 }],
+                },
+                ManifestLogEntry::WalAddition {
+                    log_number: 1,
+                    tags: vec![],
+                },
+                new_file(0, 2),
+                new_file(0, 1),
+                new_file(1, 3),
+                ManifestLogEntry::DeletedFile {
+                    level: 0,
+                    file_number: 2,
+                },
+                ManifestLogEntry::NextFileNumber {
+                    next_file_number: 4,
+                },
+                ManifestLogEntry::LastSequence { last_sequence: 10 },
+                ManifestLogEntry::LogNumber { log_number: 1 },
+                ManifestLogEntry::WalDeletion { log_number: 0 },
+            ],
+        )
+        .await;
+
+        let (versions, _) = VersionSet::recover(path.to_path_buf()).await.unwrap();
+        let version = versions.current();
+        assert_eq!(version.num_levels(DEFAULT_COLUMN_FAMILY), 2);
+        let files: Vec<u64> = version
+            .files(DEFAULT_COLUMN_FAMILY, 0)
+            .iter()
+            .map(|f| f.file_number)
+            .collect();
+        assert_eq!(files, vec![1]);
+        assert_eq!(version.files(DEFAULT_COLUMN_FAMILY, 1)[0].file_number, 3);
+        assert!(version.files(DEFAULT_COLUMN_FAMILY, 2).is_empty());
+        assert_eq!(version.wals().iter().collect::<Vec<_>>(), vec![(&1, &None)]);
+        assert_eq!(version.log_number(), 1);
+        assert_eq!(version.next_file_number(), 4);
+        assert_eq!(version.last_sequence(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn discard_partial_atomic_group() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        written_manifest(
+            path,
+            &[
+                new_file(0, 0),
+                ManifestLogEntry::InAtomicGroup {
+                    version_edit_count: 3,
+                },
+                new_file(0, 1),
+                ManifestLogEntry::NextFileNumber {
+                    next_file_number: 2,
+                },
+            ],
+        )
+        .await;
+
+        let (mut versions, committed) = VersionSet::recover(path.to_path_buf()).await.unwrap();
+        // Right after the only committed edit
+        let mut first = Vec::new();
+        new_file(0, 0).write(&mut first).await.unwrap();
+        assert_eq!(committed, first.len() as u64);
+        let version = versions.current();
+        assert_eq!(version.files(DEFAULT_COLUMN_FAMILY, 0).len(), 1);
+        assert_eq!(version.next_file_number(), 0);
+
+        // Edits build a new version, leaving the previous one untouched
+        versions.apply(&[new_file(0, 1)]);
+        assert_eq!(versions.current().files(DEFAULT_COLUMN_FAMILY, 0).len(), 2);
+        assert_eq!(version.files(DEFAULT_COLUMN_FAMILY, 0).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn interrupted_atomic_group() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        written_manifest(
+            path,
+            &[
+                ManifestLogEntry::InAtomicGroup {
+                    version_edit_count: 2,
+                },
+                new_file(0, 0),
+                ManifestLogEntry::InAtomicGroup {
+                    version_edit_count: 2,
+                },
+                new_file(0, 1),
+                new_file(0, 2),
+            ],
+        )
+        .await;
+
+        let err = VersionSet::recover(path.to_path_buf()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn recover_column_families() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let family_file = |column_family: u32, file_number: u64| {
+            let mut entry = new_file(0, file_number);
+            if let ManifestLogEntry::NewFile { tags, .. } = &mut entry {
+                tags.push(NewFileTag::ColumnFamily { column_family });
+            }
+            entry
+        };
+        written_manifest(
+            path,
+            &[
+                ManifestLogEntry::ColumnFamilyAdd {
+                    column_family: 1,
+                    name: "first".to_string(),
+                },
+                ManifestLogEntry::MaxColumnFamily {
+                    max_column_family: 1,
+                },
+                ManifestLogEntry::ColumnFamilyAdd {
+                    column_family: 2,
+                    name: "second".to_string(),
+                },
+                ManifestLogEntry::MaxColumnFamily {
+                    max_column_family: 2,
+                },
+                new_file(0, 0),
+                family_file(1, 1),
+                family_file(2, 2),
+                ManifestLogEntry::ColumnFamilyDrop { column_family: 2 },
+                // Flushed while the column family was being dropped
+                family_file(2, 3),
+                ManifestLogEntry::DeletedFile {
+                    level: 0,
+                    file_number: 1,
+                },
+                family_file(1, 4),
+            ],
+        )
+        .await;
+
+        let (versions, _) = VersionSet::recover(path.to_path_buf()).await.unwrap();
+        let version = versions.current();
+        let names: Vec<(&u32, &str)> = version
+            .column_families()
+            .iter()
+            .map(|(id, name)| (id, name.as_str()))
+            .collect();
+        assert_eq!(names, vec![(&1, "first")]);
+        assert_eq!(version.max_column_family(), 2);
+        let files = |column_family: u32| -> Vec<u64> {
+            version
+                .files(column_family, 0)
+                .iter()
+                .map(|f| f.file_number)
+                .collect()
+        };
+        assert_eq!(files(DEFAULT_COLUMN_FAMILY), vec![0]);
+        assert_eq!(files(1), vec![4]);
+        assert!(files(2).is_empty());
+    }
+}
@@ -1,18 +1,33 @@
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::io::Read;
 use std::io::Result;
 use std::io::Write;
+use std::io::{Error, ErrorKind};
 
-use crate::utils::fixedint::write_u8;
-use crate::utils::string::write_bytes;
+use crate::db::column_family::DEFAULT_COLUMN_FAMILY;
+use crate::utils::fixedint::{read_u32, read_u8, write_u32, write_u8};
+use crate::utils::string::{read_bytes, write_bytes};
 
 #[repr(u8)]
+#[derive(FromPrimitive)]
 pub enum WalEntryType {
     Set = 1,
     Delete = 2,
+    Merge = 3,
+    DeleteRange = 4,
+    SingleDelete = 5,
+    // Prefixes the entries of the column families but the default one
+    ColumnFamily = 6,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum WalEntry {
     Set { key: Vec<u8>, value: Vec<u8> },
     Delete { key: Vec<u8> },
+    Merge { key: Vec<u8>, operand: Vec<u8> },
+    DeleteRange { start: Vec<u8>, end: Vec<u8> },
+    SingleDelete { key: Vec<u8> },
 }
 
 impl WalEntry {
@@ -20,6 +35,9 @@ impl WalEntry {
         match self {
             WalEntry::Set { .. } => WalEntryType::Set,
             WalEntry::Delete { .. } => WalEntryType::Delete,
+            WalEntry::Merge { .. } => WalEntryType::Merge,
+            WalEntry::DeleteRange { .. } => WalEntryType::DeleteRange,
+            WalEntry::SingleDelete { .. } => WalEntryType::SingleDelete,
         }
     }
 
@@ -30,10 +48,75 @@ impl WalEntry {
                 write_bytes(key, writer)?;
                 write_bytes(value, writer)?;
             }
-            WalEntry::Delete { key } => {
+            WalEntry::Delete { key } | WalEntry::SingleDelete { key } => {
                 write_bytes(key, writer)?;
             }
+            WalEntry::Merge { key, operand } => {
+                write_bytes(key, writer)?;
+                write_bytes(operand, writer)?;
+            }
+            WalEntry::DeleteRange { start, end } => {
+                write_bytes(start, writer)?;
+                write_bytes(end, writer)?;
+            }
         }
         Ok(())
     }
+
+    // Writes the entry along with the column family it belongs to
+    pub fn write_in<W: Write>(&self, column_family: u32, writer: &mut W) -> Result<()> {
+        if column_family != DEFAULT_COLUMN_FAMILY {
+            write_u8(WalEntryType::ColumnFamily as u8, writer)?;
+            write_u32(column_family, writer)?;
+        }
+        self.write(writer)
+    }
+
+    // Reads an entry written by write_in, and the column family it belongs to
+    pub fn read_in<R: Read>(reader: &mut R) -> Result<(u32, Self)> {
+        let entry_type_value = read_u8(reader)?;
+        match FromPrimitive::from_u8(entry_type_value) {
+            Some(WalEntryType::ColumnFamily) => {
+                let column_family = read_u32(reader)?;
+                let entry_type_value = read_u8(reader)?;
+                Ok((column_family, Self::read_typed(entry_type_value, reader)?))
+            }
+            _ => Ok((
+                DEFAULT_COLUMN_FAMILY,
+                Self::read_typed(entry_type_value, reader)?,
+            )),
+        }
+    }
+
+    fn read_typed<R: Read>(entry_type_value: u8, reader: &mut R) -> Result<Self> {
+        match FromPrimitive::from_u8(entry_type_value) {
+            Some(WalEntryType::Set) => {
+                let key = read_bytes(reader)?;
+                let value = read_bytes(reader)?;
+                Ok(WalEntry::Set { key, value })
+            }
+            Some(WalEntryType::Delete) => {
+                let key = read_bytes(reader)?;
+                Ok(WalEntry::Delete { key })
+            }
+            Some(WalEntryType::Merge) => {
+                let key = read_bytes(reader)?;
+                let operand = read_bytes(reader)?;
+                Ok(WalEntry::Merge { key, operand })
+            }
+            Some(WalEntryType::DeleteRange) => {
+                let start = read_bytes(reader)?;
+                let end = read_bytes(reader)?;
+                Ok(WalEntry::DeleteRange { start, end })
+            }
+            Some(WalEntryType::SingleDelete) => {
+                let key = read_bytes(reader)?;
+                Ok(WalEntry::SingleDelete { key })
+            }
+            Some(WalEntryType::ColumnFamily) | None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown wal entry type: {}", entry_type_value),
+            )),
+        }
+    }
 }
@@ -1,55 +1,84 @@
+use num_derive::FromPrimitive;
 use std::io::Result;
 use std::mem::size_of;
 use std::path::PathBuf;
-use tokio::fs::File;
+use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
 
 use crate::db::options::DbOptions;
 use crate::utils::crc32::Crc32;
+use crate::utils::fs::sync_dir;
 
 #[repr(u8)]
-enum RecordType {
+#[derive(FromPrimitive)]
+pub enum RecordType {
     Full = 1,
     First = 2,
     Middle = 3,
     Last = 4,
 }
 
-const HEADER_SIZE: usize = size_of::<u32>() + size_of::<u16>() + size_of::<u8>();
+pub const HEADER_SIZE: usize = size_of::<u32>() + size_of::<u16>() + size_of::<u8>();
 
 pub struct Wal {
     seq_num: u32,
     file: File,
     options: DbOptions,
     remaining_block_size: usize,
+    size: u64,
+    // Writes made to the file, to check that records are grouped
+    #[cfg(test)]
+    write_count: usize,
 }
 
 impl Wal {
     pub async fn create(seq_num: u32, path: PathBuf, options: DbOptions) -> Result<Self> {
         let path = path.join(format!("WAL-{}", seq_num));
         println!("wal path {}", path.to_str().unwrap());
-        let file = File::create(path).await?;
+        let file = File::create(&path).await?;
+        sync_dir(path.parent().unwrap()).await?;
         let remaining_block_size = options.wal_block_size;
         Ok(Self {
             seq_num,
             file,
             remaining_block_size,
             options,
+            size: 0,
+            #[cfg(test)]
+            write_count: 0,
         })
     }
 
     pub async fn load(seq_num: u32, path: PathBuf, options: DbOptions) -> Result<Self> {
         let path = path.join(format!("WAL-{}", seq_num));
-        let file = File::open(path).await?;
-        let remaining_block_size = options.wal_block_size;
+        let mut file = OpenOptions::new().append(true).open(path).await?;
+        // Resume writing in the middle of the last block
+        let mut size = file.metadata().await?.len();
+        let mut remaining_block_size =
+            options.wal_block_size - size as usize % options.wal_block_size;
+        // Recovery may have truncated the log right before the block padding
+        if remaining_block_size < HEADER_SIZE {
+            let padding = vec![0u8; remaining_block_size];
+            file.write_all(&padding).await?;
+            file.flush().await?;
+            size += remaining_block_size as u64;
+            remaining_block_size = options.wal_block_size;
+        }
         Ok(Self {
             seq_num,
             file,
             remaining_block_size,
             options,
+            size,
+            #[cfg(test)]
+            write_count: 0,
         })
     }
 
+    pub async fn sync(&mut self) -> Result<()> {
+        self.file.sync_data().await
+    }
+
     fn _record_type(
         data_size: usize,
         written_size: usize,
@@ -65,10 +94,40 @@ impl Wal {
         }
     }
 
+    #[cfg(test)]
     pub async fn append(&mut self, data: &[u8]) -> Result<()> {
+        self.append_all(&[data]).await
+    }
+
+    #[cfg(test)]
+    pub fn write_count(&self) -> usize {
+        self.write_count
+    }
+
+    // Encodes every record into a single buffer so that they reach the file
+    // with one write.
+    pub async fn append_all<D: AsRef<[u8]>>(&mut self, records: &[D]) -> Result<()> {
+        let mut buffer = Vec::new();
+        for data in records {
+            self.encode(data.as_ref(), &mut buffer);
+        }
+        self.file.write_all(&buffer).await?;
+        self.file.flush().await?;
+        self.size += buffer.len() as u64;
+        #[cfg(test)]
+        {
+            self.write_count += 1;
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn encode(&mut self, data: &[u8], buffer: &mut Vec<u8>) {
         let data_size = data.len();
         let mut written_size = 0;
-        // let mut remaining_data_size = data_size;
 
         while written_size < data_size {
             let available_payload_size = self.remaining_block_size - HEADER_SIZE;
@@ -81,23 +140,19 @@ impl Wal {
             let record_type = Self::_record_type(data_size, written_size, available_payload_size);
 
             let crc = Crc32::hash(writable_slice);
-            self.file.write_u32(crc).await?;
-            self.file.write_u16(written_payload_size as u16).await?;
-            self.file.write_u8(record_type as u8).await?;
-            self.file.write_all(writable_slice).await?;
+            buffer.extend_from_slice(&crc.to_be_bytes());
+            buffer.extend_from_slice(&(written_payload_size as u16).to_be_bytes());
+            buffer.push(record_type as u8);
+            buffer.extend_from_slice(writable_slice);
 
             written_size += written_payload_size;
             self.remaining_block_size -= written_payload_size + HEADER_SIZE;
 
             if self.remaining_block_size < HEADER_SIZE {
-                if self.remaining_block_size > 0 {
-                    let padding = vec![0u8; self.remaining_block_size];
-                    self.file.write_all(&padding).await?;
-                }
+                buffer.resize(buffer.len() + self.remaining_block_size, 0);
                 self.remaining_block_size = self.options.wal_block_size;
             }
         }
-        Ok(())
     }
 }
 
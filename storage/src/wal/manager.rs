@@ -1,44 +1,125 @@
-use std::io::{Cursor, Read, Result};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::io::{Cursor, Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
-use crate::db::options::DbOptions;
-use crate::utils::fixedint::write_u64;
+use crate::db::flush::FlushRequest;
+use crate::db::options::{DbOptions, WalRecoveryMode, WalSyncPolicy};
+use crate::db::state::DbState;
+use crate::memtable::memtable::MemTable;
+use crate::utils::fixedint::{read_u32, read_u64, write_u64};
+use crate::utils::time::tick;
+use crate::version::version::Version;
 use crate::{
-    manifest::{self, manifest::ManifestRequest},
+    manifest::{
+        entry::{ManifestLogEntry, WalTag},
+        manifest::{append_entries, ManifestRequest},
+    },
     utils::fixedint::write_u32,
     wal::entry::WalEntry,
 };
+use tokio::fs::{metadata, remove_file, OpenOptions};
+use tokio::select;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::time::interval;
+use tracing::{info, warn};
 
 use super::log::Wal;
+use super::reader::WalReader;
+
+// Stop gathering requests into a write group past this many bytes
+const MAX_GROUP_SIZE: usize = 1024 * 1024;
 
 pub struct WalRequest {
     seq_num: u64,
-    entries: Vec<WalEntry>,
+    // Along with the column family each of them belongs to
+    entries: Vec<(u32, WalEntry)>,
+    sync: bool,
+    completion: Option<oneshot::Sender<Result<()>>>,
 }
 
 impl WalRequest {
-    pub fn new(seq_num: u64, entries: Vec<WalEntry>) -> Self {
-        Self { seq_num, entries }
+    // The returned receiver resolves once the request is persisted and
+    // applied to the memtable, or with the error that prevented it.
+    pub fn new(
+        seq_num: u64,
+        entries: Vec<(u32, WalEntry)>,
+        sync: bool,
+    ) -> (Self, oneshot::Receiver<Result<()>>) {
+        let (sender, receiver) = oneshot::channel();
+        let request = Self {
+            seq_num,
+            entries,
+            sync,
+            completion: Some(sender),
+        };
+        (request, receiver)
     }
 
     pub fn to_vec(&self) -> Vec<u8> {
         let mut writer = Cursor::new(Vec::new());
         write_u64(self.seq_num, &mut writer).unwrap();
         write_u32(self.entries.len() as u32, &mut writer).unwrap();
-        for entry in &self.entries {
-            entry.write(&mut writer).unwrap();
+        for (column_family, entry) in &self.entries {
+            entry.write_in(*column_family, &mut writer).unwrap();
         }
         writer.into_inner()
     }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(data);
+        let seq_num = read_u64(&mut reader)?;
+        let count = read_u32(&mut reader)?;
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            entries.push(WalEntry::read_in(&mut reader)?);
+        }
+        Ok(Self {
+            seq_num,
+            entries,
+            sync: false,
+            completion: None,
+        })
+    }
+
+    fn complete(&mut self, result: Result<()>) {
+        if let Some(completion) = self.completion.take() {
+            // The writer may have stopped waiting
+            let _ = completion.send(result);
+        }
+    }
+}
+
+pub enum WalCommand {
+    Write(WalRequest),
+    // Switches the memtable even if it is not full, the completion resolves
+    // once every memtable switched so far is flushed.
+    Flush {
+        completion: oneshot::Sender<Result<()>>,
+    },
+    // Resolves once every write queued before it is applied to the memtables
+    Barrier {
+        completion: oneshot::Sender<()>,
+    },
+}
+
+impl From<WalRequest> for WalCommand {
+    fn from(request: WalRequest) -> Self {
+        WalCommand::Write(request)
+    }
 }
 
 pub struct WalManager {
     path: PathBuf,
     seq_num: u32,
     manifest_sender: Sender<ManifestRequest>,
-    wal_receiver: Receiver<WalRequest>,
+    flush_sender: Sender<FlushRequest>,
+    wal_receiver: Receiver<WalCommand>,
     current_wal: Wal,
+    state: Arc<RwLock<DbState>>,
+    last_seq_num: Option<u64>,
+    unsynced: bool,
     options: DbOptions,
 }
 
@@ -46,52 +127,856 @@ impl WalManager {
     pub async fn create(
         path: PathBuf,
         manifest_sender: Sender<ManifestRequest>,
-        wal_receiver: Receiver<WalRequest>,
+        flush_sender: Sender<FlushRequest>,
+        wal_receiver: Receiver<WalCommand>,
+        state: Arc<RwLock<DbState>>,
         options: DbOptions,
     ) -> Result<Self> {
-        manifest_sender
-            .send(ManifestRequest::Append {
-                entries: vec![manifest::entry::ManifestLogEntry::WalAddition {
-                    log_number: 0,
-                    tags: vec![],
-                }],
-            })
-            .await
-            .unwrap();
-
         let current_wal = Wal::create(0, path.clone(), options.clone()).await?;
+        append_entries(
+            &manifest_sender,
+            vec![ManifestLogEntry::WalAddition {
+                log_number: 0,
+                tags: vec![],
+            }],
+        )
+        .await?;
+
         Ok(Self {
             seq_num: 0,
             path,
             manifest_sender,
+            flush_sender,
             wal_receiver,
             current_wal,
+            state,
+            last_seq_num: None,
+            unsynced: false,
             options,
         })
     }
 
+    // Replays the live logs of the version that hold writes not flushed yet
     pub async fn load(
         path: PathBuf,
         manifest_sender: Sender<ManifestRequest>,
-        wal_receiver: Receiver<WalRequest>,
+        flush_sender: Sender<FlushRequest>,
+        wal_receiver: Receiver<WalCommand>,
+        state: Arc<RwLock<DbState>>,
+        version: &Version,
         options: DbOptions,
     ) -> Result<Self> {
-        let current_wal = Wal::load(0, path.clone(), options.clone()).await?;
+        let log_number = version.log_number();
+        let mut wals = version.wals().clone();
+        // Left behind when stopped between a flush and the deletion of its logs
+        let obsolete: Vec<u32> = wals.range(..log_number).map(|(&n, _)| n).collect();
+        if !obsolete.is_empty() {
+            delete_obsolete_wals(&path, &manifest_sender, &obsolete).await?;
+            wals.retain(|&n, _| n >= log_number);
+        }
+
+        let memtables = memtables(&state);
+        let mut last_seq_num = None;
+        let mut stopped = false;
+        for (&log_number, &synced_size) in &wals {
+            let log_path = path.join(format!("WAL-{}", log_number));
+            if stopped {
+                // Keeping records written after the point of recovery would
+                // bring them back on the next open.
+                warn!("Discarding WAL-{} after point of recovery", log_number);
+                OpenOptions::new()
+                    .write(true)
+                    .open(log_path)
+                    .await?
+                    .set_len(0)
+                    .await?;
+                continue;
+            }
+
+            // Logs are only cut short by the modes that tolerate damaged
+            // records, so their synced size is checked by the strict ones.
+            let strict = matches!(
+                options.wal_recovery_mode,
+                WalRecoveryMode::AbsoluteConsistency
+                    | WalRecoveryMode::TolerateCorruptedTailRecords
+            );
+            let file_size = metadata(&log_path).await?.len();
+            if strict && synced_size.is_some_and(|synced_size| file_size < synced_size) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("WAL-{} is smaller than its synced size", log_number),
+                ));
+            }
+
+            let (log_last_seq_num, log_stopped) =
+                Self::replay(log_number, &path, &memtables, &options).await?;
+            last_seq_num = log_last_seq_num.or(last_seq_num);
+            stopped = log_stopped;
+        }
+
+        let seq_num = match wals.keys().last() {
+            Some(&seq_num) => seq_num,
+            None => return Err(Error::new(ErrorKind::InvalidData, "No live wal")),
+        };
+        let current_wal = Wal::load(seq_num, path.clone(), options.clone()).await?;
         Ok(Self {
-            seq_num: 0,
+            seq_num,
             path,
             manifest_sender,
+            flush_sender,
             wal_receiver,
             current_wal,
+            state,
+            last_seq_num,
+            unsynced: false,
             options,
         })
     }
 
+    // Applies every record of a log to the memtables and returns the last
+    // sequence number it contained, and whether replay must stop there.
+    // Damaged records are handled according to the configured recovery mode,
+    // and whatever follows the last record recovered is cut off so that new
+    // records are not appended after garbage.
+    async fn replay(
+        seq_num: u32,
+        path: &Path,
+        memtables: &BTreeMap<u32, Arc<MemTable>>,
+        options: &DbOptions,
+    ) -> Result<(Option<u64>, bool)> {
+        let mut reader = WalReader::open(seq_num, path.to_path_buf(), options.clone()).await?;
+        let mut last_seq_num = None;
+        let mut damaged = false;
+        let mut stopped = false;
+        loop {
+            let request = reader.read_record().await.and_then(|record| {
+                record
+                    .map(|record| {
+                        WalRequest::from_slice(&record)
+                            .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+                    })
+                    .transpose()
+            });
+            match request {
+                Ok(Some(request)) => {
+                    let count = request.entries.len() as u64;
+                    if count > 0 {
+                        last_seq_num = Some(request.seq_num + count - 1);
+                    }
+                    apply(memtables, request.seq_num, request.entries);
+                }
+                Ok(None) => break,
+                Err(err) => match (options.wal_recovery_mode, err.kind()) {
+                    (WalRecoveryMode::TolerateCorruptedTailRecords, ErrorKind::UnexpectedEof)
+                    | (
+                        WalRecoveryMode::PointInTimeRecovery,
+                        ErrorKind::UnexpectedEof | ErrorKind::InvalidData,
+                    ) => {
+                        warn!("Stopping replay of WAL-{}: {}", seq_num, err);
+                        damaged = true;
+                        stopped = options.wal_recovery_mode == WalRecoveryMode::PointInTimeRecovery;
+                        break;
+                    }
+                    (
+                        WalRecoveryMode::SkipAnyCorruptedRecords,
+                        ErrorKind::UnexpectedEof | ErrorKind::InvalidData,
+                    ) => {
+                        warn!("Skipping record of WAL-{}: {}", seq_num, err);
+                        damaged = true;
+                    }
+                    _ => return Err(err),
+                },
+            }
+        }
+
+        if damaged {
+            let log_path = path.join(format!("WAL-{}", seq_num));
+            let file = OpenOptions::new().write(true).open(log_path).await?;
+            warn!(
+                "Truncating WAL-{} to {} bytes",
+                seq_num,
+                reader.last_record_end()
+            );
+            file.set_len(reader.last_record_end()).await?;
+        }
+
+        Ok((last_seq_num, stopped))
+    }
+
+    pub fn last_seq_num(&self) -> Option<u64> {
+        self.last_seq_num
+    }
+
+    async fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.current_wal.sync().await?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    // Writes the given request along with every other one already waiting in
+    // the channel, using a single write and at most one sync for the group.
+    async fn write_group(&mut self, request: WalRequest) -> Result<()> {
+        let mut records = vec![request.to_vec()];
+        let mut group_size = records[0].len();
+        let mut group = vec![request];
+        // A flush or a barrier ends the group so that it covers every write
+        // queued before it
+        let mut flush = None;
+        let mut barrier = None;
+        while group_size < MAX_GROUP_SIZE {
+            match self.wal_receiver.try_recv() {
+                Ok(WalCommand::Write(request)) => {
+                    let record = request.to_vec();
+                    group_size += record.len();
+                    records.push(record);
+                    group.push(request);
+                }
+                Ok(WalCommand::Flush { completion }) => {
+                    flush = Some(completion);
+                    break;
+                }
+                Ok(WalCommand::Barrier { completion }) => {
+                    barrier = Some(completion);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let sync = self.options.wal_sync_policy == WalSyncPolicy::Always
+            || group.iter().any(|request| request.sync);
+        if let Err(err) = self.append_group(&records, sync).await {
+            for request in group.iter_mut() {
+                request.complete(Err(Error::new(err.kind(), err.to_string())));
+            }
+            return Err(err);
+        }
+
+        let memtables = memtables(&self.state);
+        let mut last_sequence = None;
+        for request in group.iter_mut() {
+            let entries = std::mem::take(&mut request.entries);
+            if !entries.is_empty() {
+                last_sequence = Some(request.seq_num + entries.len() as u64 - 1);
+            }
+            apply(&memtables, request.seq_num, entries);
+        }
+        // Snapshots taken once a write is acknowledged see it
+        if last_sequence.is_some() {
+            self.state.write().unwrap().last_sequence = last_sequence;
+        }
+        for mut request in group {
+            request.complete(Ok(()));
+        }
+        if let Some(completion) = barrier {
+            let _ = completion.send(());
+        }
+
+        if let Some(completion) = flush {
+            self.switch_memtable(Some(completion)).await?;
+        } else if self.is_memtable_full() {
+            self.switch_memtable(None).await?;
+        } else if self.current_wal.size() >= self.options.max_wal_size {
+            self.rotate().await?;
+        }
+        Ok(())
+    }
+
+    // Whether the memtable of a column family reached its write buffer size
+    fn is_memtable_full(&self) -> bool {
+        let state = self.state.read().unwrap();
+        state
+            .column_families
+            .values()
+            .any(|family| family.memtable.approximate_size() >= family.options.write_buffer_size)
+    }
+
+    // Starts a new log along with new memtables for every column family, and
+    // hands the previous ones over to the flusher. Writes of the new
+    // memtables all go to the new log, so the logs before it can be deleted
+    // once the flush is done.
+    async fn switch_memtable(
+        &mut self,
+        completion: Option<oneshot::Sender<Result<()>>>,
+    ) -> Result<()> {
+        let memtables = if memtables(&self.state).values().all(|m| m.is_empty()) {
+            Vec::new()
+        } else {
+            self.rotate().await?;
+            let mut state = self.state.write().unwrap();
+            let mut memtables = Vec::new();
+            for (&column_family, family) in state.column_families.iter_mut() {
+                if family.memtable.is_empty() {
+                    continue;
+                }
+                let memtable = std::mem::replace(&mut family.memtable, Arc::new(MemTable::new()));
+                family.immutables.push(memtable.clone());
+                memtables.push((column_family, memtable));
+            }
+            memtables
+        };
+        let request = FlushRequest::new(memtables, self.seq_num, completion);
+        self.flush_sender
+            .send(request)
+            .await
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Flusher stopped"))
+    }
+
+    // Closes the current log and starts writing to a new one
+    pub async fn rotate(&mut self) -> Result<()> {
+        self.current_wal.sync().await?;
+        self.unsynced = false;
+
+        let seq_num = self.seq_num + 1;
+        let wal = Wal::create(seq_num, self.path.clone(), self.options.clone()).await?;
+        append_entries(
+            &self.manifest_sender,
+            vec![
+                ManifestLogEntry::WalAddition {
+                    log_number: self.seq_num as u64,
+                    tags: vec![WalTag::SyncedSize {
+                        size: self.current_wal.size(),
+                    }],
+                },
+                ManifestLogEntry::WalAddition {
+                    log_number: seq_num as u64,
+                    tags: vec![],
+                },
+            ],
+        )
+        .await?;
+        info!("Rotated WAL-{} to WAL-{}", self.seq_num, seq_num);
+
+        self.current_wal = wal;
+        self.seq_num = seq_num;
+        Ok(())
+    }
+
+    async fn append_group(&mut self, records: &[Vec<u8>], sync: bool) -> Result<()> {
+        self.current_wal.append_all(records).await?;
+        self.unsynced = true;
+        if sync {
+            self.sync().await?;
+        }
+        Ok(())
+    }
+
     pub async fn run(&mut self) -> Result<()> {
-        while let Some(msg) = self.wal_receiver.recv().await {
-            let vec = msg.to_vec();
-            self.current_wal.append(&vec).await?;
+        let mut sync_interval = match self.options.wal_sync_policy {
+            WalSyncPolicy::Interval(period) => Some(interval(period)),
+            _ => None,
+        };
+        loop {
+            select! {
+                command = self.wal_receiver.recv() => match command {
+                    Some(WalCommand::Write(request)) => self.write_group(request).await?,
+                    Some(WalCommand::Flush { completion }) => {
+                        self.switch_memtable(Some(completion)).await?
+                    }
+                    Some(WalCommand::Barrier { completion }) => {
+                        let _ = completion.send(());
+                    }
+                    None => break,
+                },
+                _ = tick(&mut sync_interval) => self.sync().await?,
+            }
+        }
+        if self.options.wal_sync_policy != WalSyncPolicy::Never {
+            self.sync().await?;
         }
         Ok(())
     }
 }
+
+// Memtables receiving the writes of each column family
+fn memtables(state: &RwLock<DbState>) -> BTreeMap<u32, Arc<MemTable>> {
+    let state = state.read().unwrap();
+    state
+        .column_families
+        .iter()
+        .map(|(&column_family, family)| (column_family, family.memtable.clone()))
+        .collect()
+}
+
+// Applies the entries of a request to the memtables of their column families,
+// leaving out those of dropped families
+fn apply(memtables: &BTreeMap<u32, Arc<MemTable>>, seq_num: u64, entries: Vec<(u32, WalEntry)>) {
+    for (i, (column_family, entry)) in entries.into_iter().enumerate() {
+        if let Some(memtable) = memtables.get(&column_family) {
+            memtable.apply(seq_num + i as u64, entry);
+        }
+    }
+}
+
+// Records the deletion of logs whose data is persisted elsewhere, then removes
+// their files.
+pub async fn delete_obsolete_wals(
+    path: &Path,
+    manifest_sender: &Sender<ManifestRequest>,
+    seq_nums: &[u32],
+) -> Result<()> {
+    let entries = seq_nums
+        .iter()
+        .map(|&seq_num| ManifestLogEntry::WalDeletion {
+            log_number: seq_num as u64,
+        })
+        .collect();
+    append_entries(manifest_sender, entries).await?;
+    for seq_num in seq_nums {
+        info!("Deleting WAL-{}", seq_num);
+        remove_file(path.join(format!("WAL-{}", seq_num))).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::column_family::{
+        ColumnFamily, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME,
+    };
+    use crate::levels::levels::Levels;
+    use crate::manifest::manifest::Manifest;
+    use crate::memtable::memtable::MemTableValue;
+    use crate::wal::log::HEADER_SIZE;
+    use std::io::SeekFrom;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+    async fn start_manifest(path: &Path) -> Sender<ManifestRequest> {
+        let (manifest_sender, manifest_receiver) = channel(1024);
+        let mut manifest = Manifest::create(path.to_path_buf(), manifest_receiver)
+            .await
+            .unwrap();
+        tokio::spawn(async move { manifest.run().await });
+        manifest_sender
+    }
+
+    fn new_state(memtable: &Arc<MemTable>) -> Arc<RwLock<DbState>> {
+        let mut family = ColumnFamily::new(
+            DEFAULT_COLUMN_FAMILY_NAME.to_string(),
+            DbOptions::default(),
+            Levels::new(1),
+        );
+        family.memtable = memtable.clone();
+        let state = DbState::new(BTreeMap::from([(DEFAULT_COLUMN_FAMILY, family)]), 0);
+        Arc::new(RwLock::new(state))
+    }
+
+    fn set_request(seq_num: u64, key: &[u8]) -> WalRequest {
+        let (request, _) = WalRequest::new(
+            seq_num,
+            vec![(
+                DEFAULT_COLUMN_FAMILY,
+                WalEntry::Set {
+                    key: key.to_vec(),
+                    value: b"value".to_vec(),
+                },
+            )],
+            false,
+        );
+        request
+    }
+
+    // Writes three records and returns the offset of each one
+    async fn written_wal(path: &Path) -> Vec<u64> {
+        let manifest_sender = start_manifest(path).await;
+        append_entries(
+            &manifest_sender,
+            vec![ManifestLogEntry::WalAddition {
+                log_number: 0,
+                tags: vec![],
+            }],
+        )
+        .await
+        .unwrap();
+
+        let mut wal = Wal::create(0, path.to_path_buf(), DbOptions::default())
+            .await
+            .unwrap();
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        for (seq_num, key) in [(0, b"foo"), (1, b"bar"), (2, b"baz")] {
+            let record = set_request(seq_num, key).to_vec();
+            wal.append(&record).await.unwrap();
+            offsets.push(offset);
+            offset += (HEADER_SIZE + record.len()) as u64;
+        }
+        offsets
+    }
+
+    async fn corrupt(path: &Path, offset: u64) {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path.join("WAL-0"))
+            .await
+            .unwrap();
+        file.seek(SeekFrom::Start(offset + HEADER_SIZE as u64))
+            .await
+            .unwrap();
+        file.write_all(b"\xff").await.unwrap();
+        file.flush().await.unwrap();
+    }
+
+    async fn truncate(path: &Path, len: u64) {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path.join("WAL-0"))
+            .await
+            .unwrap();
+        file.set_len(len).await.unwrap();
+    }
+
+    async fn recover(
+        path: &Path,
+        wal_recovery_mode: WalRecoveryMode,
+    ) -> Result<(WalManager, Arc<MemTable>)> {
+        let options = DbOptions {
+            wal_recovery_mode,
+            ..Default::default()
+        };
+        recover_with(path, options).await
+    }
+
+    async fn recover_with(path: &Path, options: DbOptions) -> Result<(WalManager, Arc<MemTable>)> {
+        let (manifest_sender, manifest_receiver) = channel(1024);
+        let (flush_sender, _) = channel(1024);
+        let (_, wal_receiver) = channel(1024);
+        let manifest = Manifest::load(path.to_path_buf(), manifest_receiver).await?;
+        let memtable = Arc::new(MemTable::new());
+        let manager = WalManager::load(
+            path.to_path_buf(),
+            manifest_sender,
+            flush_sender,
+            wal_receiver,
+            new_state(&memtable),
+            &manifest.current(),
+            options,
+        )
+        .await?;
+        Ok((manager, memtable))
+    }
+
+    #[tokio::test]
+    async fn group_commit() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let manifest_sender = start_manifest(path).await;
+        let (wal_sender, wal_receiver) = channel(1024);
+        let (flush_sender, _flush_receiver) = channel(1024);
+        let memtable = Arc::new(MemTable::new());
+        let mut manager = WalManager::create(
+            path.to_path_buf(),
+            manifest_sender,
+            flush_sender,
+            wal_receiver,
+            new_state(&memtable),
+            DbOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // Requests already queued are written as groups
+        for seq_num in 0..1000 {
+            let key = format!("foo{}", seq_num);
+            let request = set_request(seq_num, key.as_bytes());
+            wal_sender.send(request.into()).await.unwrap();
+        }
+        drop(wal_sender);
+        manager.run().await.unwrap();
+        let write_count = manager.current_wal.write_count();
+        assert!(
+            write_count > 0 && write_count < 100,
+            "{} writes",
+            write_count
+        );
+        drop(manager);
+
+        for seq_num in 0..1000 {
+            let key = format!("foo{}", seq_num);
+            assert!(memtable.get(key.as_bytes()).is_some());
+        }
+
+        let (manager, memtable) = recover(path, WalRecoveryMode::AbsoluteConsistency)
+            .await
+            .unwrap();
+        assert_eq!(manager.last_seq_num(), Some(999));
+        assert!(memtable.get(b"foo0").is_some());
+        assert!(memtable.get(b"foo999").is_some());
+    }
+
+    #[tokio::test]
+    async fn sync_policies() {
+        for (wal_sync_policy, synced) in [
+            (WalSyncPolicy::Always, true),
+            (WalSyncPolicy::Interval(Duration::from_secs(60)), false),
+            (WalSyncPolicy::Never, false),
+        ] {
+            let dir = tempdir().unwrap();
+            let path = dir.path();
+            let manifest_sender = start_manifest(path).await;
+            let (_wal_sender, wal_receiver) = channel(1024);
+            let (flush_sender, _flush_receiver) = channel(1024);
+            let options = DbOptions {
+                wal_sync_policy,
+                ..Default::default()
+            };
+            let mut manager = WalManager::create(
+                path.to_path_buf(),
+                manifest_sender,
+                flush_sender,
+                wal_receiver,
+                new_state(&Arc::new(MemTable::new())),
+                options,
+            )
+            .await
+            .unwrap();
+
+            manager.write_group(set_request(0, b"foo")).await.unwrap();
+            assert_eq!(!manager.unsynced, synced);
+
+            // Whatever the policy
+            let (request, _) = WalRequest::new(
+                1,
+                vec![(
+                    DEFAULT_COLUMN_FAMILY,
+                    WalEntry::Set {
+                        key: b"bar".to_vec(),
+                        value: b"value".to_vec(),
+                    },
+                )],
+                true,
+            );
+            manager.write_group(request).await.unwrap();
+            assert!(!manager.unsynced);
+        }
+    }
+
+    #[tokio::test]
+    async fn rotate_and_delete() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let manifest_sender = start_manifest(path).await;
+        let (wal_sender, wal_receiver) = channel(1024);
+        let (flush_sender, _flush_receiver) = channel(1024);
+        let options = DbOptions {
+            max_wal_size: 1024,
+            ..Default::default()
+        };
+        let mut manager = WalManager::create(
+            path.to_path_buf(),
+            manifest_sender.clone(),
+            flush_sender,
+            wal_receiver,
+            new_state(&Arc::new(MemTable::new())),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+
+        let writer = tokio::spawn(async move {
+            for seq_num in 0..100 {
+                let key = format!("foo{:0>2}", seq_num);
+                let (request, completion) = WalRequest::new(
+                    seq_num,
+                    vec![(
+                        DEFAULT_COLUMN_FAMILY,
+                        WalEntry::Set {
+                            key: key.into_bytes(),
+                            value: vec![b'a'; 100],
+                        },
+                    )],
+                    false,
+                );
+                wal_sender.send(request.into()).await.unwrap();
+                completion.await.unwrap().unwrap();
+            }
+        });
+        manager.run().await.unwrap();
+        writer.await.unwrap();
+        let last_seq_num = manager.seq_num;
+        assert!(last_seq_num >= 9);
+        drop(manager);
+
+        let (manager, memtable) = recover_with(path, options.clone()).await.unwrap();
+        assert_eq!(manager.seq_num, last_seq_num);
+        assert_eq!(manager.last_seq_num(), Some(99));
+        assert!(memtable.get(b"foo00").is_some());
+        assert!(memtable.get(b"foo99").is_some());
+        drop(manager);
+
+        // Once deleted, the data of a log is not recovered anymore
+        let obsolete: Vec<u32> = (0..last_seq_num).collect();
+        delete_obsolete_wals(path, &manifest_sender, &obsolete)
+            .await
+            .unwrap();
+        assert!(!path.join("WAL-0").exists());
+        assert!(path.join(format!("WAL-{}", last_seq_num)).exists());
+
+        let (manager, memtable) = recover_with(path, options).await.unwrap();
+        assert_eq!(manager.seq_num, last_seq_num);
+        assert!(memtable.get(b"foo00").is_none());
+        assert!(memtable.get(b"foo99").is_some());
+    }
+
+    #[tokio::test]
+    async fn recover_corrupted_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let offsets = written_wal(path).await;
+        corrupt(path, offsets[1]).await;
+
+        for mode in [
+            WalRecoveryMode::AbsoluteConsistency,
+            WalRecoveryMode::TolerateCorruptedTailRecords,
+        ] {
+            let err = recover(path, mode).await.err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        let (manager, memtable) = recover(path, WalRecoveryMode::SkipAnyCorruptedRecords)
+            .await
+            .unwrap();
+        assert_eq!(manager.last_seq_num(), Some(2));
+        assert!(memtable.get(b"foo").is_some());
+        assert!(memtable.get(b"bar").is_none());
+        assert!(memtable.get(b"baz").is_some());
+        drop(manager);
+
+        let (manager, memtable) = recover(path, WalRecoveryMode::PointInTimeRecovery)
+            .await
+            .unwrap();
+        assert_eq!(manager.last_seq_num(), Some(0));
+        assert!(memtable.get(b"foo").is_some());
+        assert!(memtable.get(b"bar").is_none());
+        assert!(memtable.get(b"baz").is_none());
+    }
+
+    #[tokio::test]
+    async fn recover_torn_tail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let offsets = written_wal(path).await;
+        truncate(path, offsets[2] + HEADER_SIZE as u64 + 2).await;
+
+        let err = recover(path, WalRecoveryMode::AbsoluteConsistency)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let (mut manager, memtable) = recover(path, WalRecoveryMode::TolerateCorruptedTailRecords)
+            .await
+            .unwrap();
+        assert_eq!(manager.last_seq_num(), Some(1));
+        assert!(memtable.get(b"bar").is_some());
+        assert!(memtable.get(b"baz").is_none());
+
+        // New records must be readable after the torn one was cut off
+        manager
+            .current_wal
+            .append(&set_request(2, b"qux").to_vec())
+            .await
+            .unwrap();
+        drop(manager);
+
+        let (manager, memtable) = recover(path, WalRecoveryMode::AbsoluteConsistency)
+            .await
+            .unwrap();
+        assert_eq!(manager.last_seq_num(), Some(2));
+        assert!(memtable.get(b"qux").is_some());
+    }
+
+    #[tokio::test]
+    async fn recover_corrupted_tail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let offsets = written_wal(path).await;
+        corrupt(path, offsets[2]).await;
+
+        let err = recover(path, WalRecoveryMode::AbsoluteConsistency)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let (mut manager, memtable) = recover(path, WalRecoveryMode::TolerateCorruptedTailRecords)
+            .await
+            .unwrap();
+        assert_eq!(manager.last_seq_num(), Some(1));
+        assert!(memtable.get(b"bar").is_some());
+        assert!(memtable.get(b"baz").is_none());
+
+        // Cut off like a torn record
+        manager
+            .current_wal
+            .append(&set_request(2, b"qux").to_vec())
+            .await
+            .unwrap();
+        drop(manager);
+
+        let (manager, memtable) = recover(path, WalRecoveryMode::AbsoluteConsistency)
+            .await
+            .unwrap();
+        assert_eq!(manager.last_seq_num(), Some(2));
+        assert!(memtable.get(b"qux").is_some());
+    }
+
+    #[tokio::test]
+    async fn apply_after_append() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let manifest_sender = start_manifest(&path).await;
+        let (wal_sender, wal_receiver) = channel(1024);
+        let (flush_sender, _flush_receiver) = channel(1024);
+        let memtable = Arc::new(MemTable::new());
+        let mut manager = WalManager::create(
+            path,
+            manifest_sender,
+            flush_sender,
+            wal_receiver,
+            new_state(&memtable),
+            DbOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let (set, set_completion) = WalRequest::new(
+            0,
+            vec![(
+                DEFAULT_COLUMN_FAMILY,
+                WalEntry::Set {
+                    key: b"foo".to_vec(),
+                    value: b"bar".to_vec(),
+                },
+            )],
+            true,
+        );
+        let (delete, delete_completion) = WalRequest::new(
+            1,
+            vec![(
+                DEFAULT_COLUMN_FAMILY,
+                WalEntry::Delete {
+                    key: b"baz".to_vec(),
+                },
+            )],
+            false,
+        );
+        wal_sender.send(set.into()).await.unwrap();
+        wal_sender.send(delete.into()).await.unwrap();
+        drop(wal_sender);
+
+        manager.run().await.unwrap();
+
+        assert_eq!(
+            memtable.get(b"foo"),
+            Some(MemTableValue::Set(b"bar".to_vec()))
+        );
+        assert_eq!(memtable.get(b"baz"), Some(MemTableValue::Delete));
+        set_completion.await.unwrap().unwrap();
+        delete_completion.await.unwrap().unwrap();
+    }
+}
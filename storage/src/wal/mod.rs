@@ -1,3 +1,4 @@
 pub mod entry;
 pub mod log;
 pub mod manager;
+pub mod reader;
@@ -0,0 +1,276 @@
+use num_traits::FromPrimitive;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::db::options::DbOptions;
+use crate::utils::crc32::Crc32;
+
+use super::log::{RecordType, HEADER_SIZE};
+
+pub struct WalReader {
+    file: File,
+    block: Vec<u8>,
+    block_offset: usize,
+    block_start: u64,
+    last_record_end: u64,
+    options: DbOptions,
+}
+
+impl WalReader {
+    pub async fn open(seq_num: u32, path: PathBuf, options: DbOptions) -> Result<Self> {
+        let path = path.join(format!("WAL-{}", seq_num));
+        let file = File::open(path).await?;
+        Ok(Self {
+            file,
+            block: Vec::new(),
+            block_offset: 0,
+            block_start: 0,
+            last_record_end: 0,
+            options,
+        })
+    }
+
+    // Returns false once the end of the file is reached
+    async fn read_block(&mut self) -> Result<bool> {
+        self.block_start += self.block.len() as u64;
+        self.block.resize(self.options.wal_block_size, 0);
+        let mut read_size = 0;
+        while read_size < self.block.len() {
+            let n = self.file.read(&mut self.block[read_size..]).await?;
+            if n == 0 {
+                break;
+            }
+            read_size += n;
+        }
+        self.block.truncate(read_size);
+        self.block_offset = 0;
+        Ok(read_size > 0)
+    }
+
+    /// File offset right after the last record successfully returned
+    pub fn last_record_end(&self) -> u64 {
+        self.last_record_end
+    }
+
+    fn record_read(&mut self, record: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.last_record_end = self.block_start + self.block_offset as u64;
+        Ok(Some(record))
+    }
+
+    fn is_last_block(&self) -> bool {
+        self.block.len() < self.options.wal_block_size
+    }
+
+    // A damaged fragment followed by nothing but zeros in the last block is
+    // the last one written before a crash, reported like a truncated one
+    fn damaged(&self, message: String) -> Error {
+        let at_tail =
+            self.is_last_block() && self.block[self.block_offset..].iter().all(|b| *b == 0);
+        let kind = if at_tail {
+            ErrorKind::UnexpectedEof
+        } else {
+            ErrorKind::InvalidData
+        };
+        Error::new(kind, message)
+    }
+
+    async fn read_fragment(&mut self) -> Result<Option<(RecordType, Vec<u8>)>> {
+        loop {
+            let remaining = self.block.len() - self.block_offset;
+            if remaining < HEADER_SIZE {
+                // The writer pads full blocks only, so leftover bytes at the end
+                // of the last block are a header that was not completely written.
+                if remaining > 0 && self.is_last_block() {
+                    self.block_offset = self.block.len();
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Truncated wal record header",
+                    ));
+                }
+                if !self.read_block().await? {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let header = &self.block[self.block_offset..self.block_offset + HEADER_SIZE];
+            let crc = u32::from_be_bytes(header[0..4].try_into().unwrap());
+            let size = u16::from_be_bytes(header[4..6].try_into().unwrap()) as usize;
+            let record_type_value = header[6];
+
+            let payload_offset = self.block_offset + HEADER_SIZE;
+            if payload_offset + size > self.block.len() {
+                // Nothing after this fragment can be trusted in this block
+                self.block_offset = self.block.len();
+                if self.is_last_block() {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Truncated wal record payload",
+                    ));
+                }
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Wal record length exceeds block",
+                ));
+            }
+
+            let payload = self.block[payload_offset..payload_offset + size].to_vec();
+            self.block_offset = payload_offset + size;
+
+            if Crc32::hash(&payload) != crc {
+                return Err(self.damaged("Wal record checksum mismatch".to_string()));
+            }
+
+            return match FromPrimitive::from_u8(record_type_value) {
+                Some(record_type) => Ok(Some((record_type, payload))),
+                None => {
+                    Err(self.damaged(format!("Unknown wal record type: {}", record_type_value)))
+                }
+            };
+        }
+    }
+
+    pub async fn read_record(&mut self) -> Result<Option<Vec<u8>>> {
+        // Holds the fragments read so far when inside a First..Last sequence
+        let mut record: Option<Vec<u8>> = None;
+        loop {
+            let fragment = self.read_fragment().await?;
+            match (fragment, record.take()) {
+                (None, None) => return Ok(None),
+                (None, Some(_)) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Wal ended inside a fragmented record",
+                    ))
+                }
+                (Some((RecordType::Full, data)), None) => return self.record_read(data),
+                (Some((RecordType::First, data)), None) => record = Some(data),
+                (Some((RecordType::Middle, data)), Some(mut partial)) => {
+                    partial.extend(data);
+                    record = Some(partial);
+                }
+                (Some((RecordType::Last, data)), Some(mut partial)) => {
+                    partial.extend(data);
+                    return self.record_read(partial);
+                }
+                _ => return Err(self.damaged("Unexpected wal record fragment".to_string())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::log::Wal;
+    use std::io::SeekFrom;
+    use std::path::Path;
+    use tempfile::tempdir;
+    use tokio::fs::OpenOptions;
+    use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+    async fn written_wal(path: &Path, records: &[Vec<u8>]) {
+        let mut wal = Wal::create(0, path.to_path_buf(), DbOptions::default())
+            .await
+            .unwrap();
+        for record in records {
+            wal.append(record).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn read_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let records = vec![
+            b"Hello world".to_vec(),
+            vec![b'a'; 1000],
+            vec![b'b'; 97270],
+            vec![b'c'; 8000],
+        ];
+        written_wal(&path, &records).await;
+
+        let mut reader = WalReader::open(0, path, DbOptions::default())
+            .await
+            .unwrap();
+        for record in &records {
+            assert_eq!(&reader.read_record().await.unwrap().unwrap(), record);
+        }
+        assert_eq!(reader.read_record().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        written_wal(&path, &[b"Hello world".to_vec(), vec![b'a'; 40000]]).await;
+
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path.join("WAL-0"))
+            .await
+            .unwrap();
+        file.set_len(33000).await.unwrap();
+
+        let mut reader = WalReader::open(0, path, DbOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(reader.read_record().await.unwrap().unwrap(), b"Hello world");
+        let err = reader.read_record().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn corrupted_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        written_wal(&path, &[b"Hello world".to_vec(), b"Hello again".to_vec()]).await;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path.join("WAL-0"))
+            .await
+            .unwrap();
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64))
+            .await
+            .unwrap();
+        file.write_all(b"J").await.unwrap();
+        file.flush().await.unwrap();
+
+        let mut reader = WalReader::open(0, path, DbOptions::default())
+            .await
+            .unwrap();
+        let err = reader.read_record().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn corrupted_last_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        written_wal(&path, &[b"Hello world".to_vec(), b"Hello again".to_vec()]).await;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path.join("WAL-0"))
+            .await
+            .unwrap();
+        file.seek(SeekFrom::Start((HEADER_SIZE * 2 + 11) as u64))
+            .await
+            .unwrap();
+        file.write_all(b"J").await.unwrap();
+        // Blocks of zeros written ahead of the data that never made it
+        file.seek(SeekFrom::End(0)).await.unwrap();
+        file.write_all(&[0; 100]).await.unwrap();
+        file.flush().await.unwrap();
+
+        let mut reader = WalReader::open(0, path, DbOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(reader.read_record().await.unwrap().unwrap(), b"Hello world");
+        let err = reader.read_record().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}